@@ -0,0 +1,86 @@
+//! Helpers shared by the authorization examples.
+
+use std::env;
+use std::fmt;
+
+use mtproto::rpc::{RpcFunction, Session};
+use mtproto::rpc::message::{Message, MessageType};
+use mtproto::tl::dynamic::TLObject;
+use serde::de::DeserializeOwned;
+
+use error::{self, ErrorKind, ResultExt};
+
+
+pub const WIRE_LOG_TARGET: &str = "mtproto::wire";
+
+
+/// Hex-dumps bytes crossing the socket under the `mtproto::wire` log
+/// target.
+///
+/// Off unless that target is enabled at trace level (for example, with
+/// `RUST_LOG=mtproto::wire=trace`) since it exposes raw traffic.
+pub fn log_wire(direction: &str, bytes: &[u8]) {
+    if log_enabled!(target: WIRE_LOG_TARGET, ::log::LogLevel::Trace) {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        trace!(target: WIRE_LOG_TARGET, "{} {} bytes: {}", direction, bytes.len(), hex.join(" "));
+    }
+}
+
+/// Whether `MTPROTO_USE_TEST_DC` is set to connect to a test DC instead
+/// of a production one.
+///
+/// Test DCs require test credentials, but don't risk banning a
+/// production account.
+pub fn use_test_dc() -> bool {
+    env::var_os("MTPROTO_USE_TEST_DC").is_some()
+}
+
+/// Reads a reply to `request`, adding the name of the function to
+/// errors.
+///
+/// `check_transport_error` fails for errors the transport sends in
+/// place of a message.
+pub fn parse_response<F>(session: &Session,
+                         request: &Message<F>,
+                         response_bytes: &[u8],
+                         message_type: MessageType,
+                         check_transport_error: fn(&[u8]) -> error::Result<()>)
+                        -> error::Result<Message<F::Reply>>
+    where F: RpcFunction + TLObject,
+          F::Reply: fmt::Debug + DeserializeOwned
+{
+    let request_name = request.body().constructor_name().unwrap_or("an unknown function");
+
+    read_response(session, request, response_bytes, message_type, check_transport_error)
+        .chain_err(|| format!("failed while invoking {}", request_name))
+}
+
+fn read_response<F>(session: &Session,
+                    request: &Message<F>,
+                    response_bytes: &[u8],
+                    message_type: MessageType,
+                    check_transport_error: fn(&[u8]) -> error::Result<()>)
+                   -> error::Result<Message<F::Reply>>
+    where F: RpcFunction + TLObject,
+          F::Reply: fmt::Debug + DeserializeOwned
+{
+    info!("Response bytes: {:?}", &response_bytes);
+
+    check_transport_error(response_bytes)?;
+
+    let len = response_bytes.len();
+
+    if len < 24 {
+        bail!(ErrorKind::BadMessage(len));
+    }
+
+    let encrypted_data_len = match message_type {
+        MessageType::PlainText => None,
+        MessageType::Encrypted => Some((len - 24) as u32),
+    };
+
+    let response = session.process_reply(request, &response_bytes, encrypted_data_len)?;
+    info!("Message received: {:#?}", &response);
+
+    Ok(response)
+}
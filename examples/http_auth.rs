@@ -6,6 +6,7 @@ extern crate error_chain;
 extern crate extprim;
 extern crate futures;
 extern crate hyper;
+extern crate hyper_tls;
 #[macro_use]
 extern crate log;
 extern crate mtproto;
@@ -16,25 +17,29 @@ extern crate serde_mtproto;
 extern crate tokio_core;
 
 
+use std::env;
 use std::fmt;
 use std::str;
 
 use byteorder::{ByteOrder, BigEndian};
 use futures::{Future, Stream};
+use hyper::header::ContentLength;
+use hyper_tls::HttpsConnector;
 use mtproto::tl::dynamic::TLObject;
 use mtproto::rpc::{AppInfo, Session};
-use mtproto::rpc::encryption::asymm;
+use mtproto::rpc::encryption::{asymm, dh, Nonce128};
 use mtproto::rpc::message::{Message, MessageType};
 use mtproto::schema;
 use rand::Rng;
 use select::document::Document;
 use select::predicate::Name;
 use serde::Serialize;
-use serde::de::DeserializeOwned;
 use serde_mtproto::MtProtoSized;
 use tokio_core::reactor::{Core, Handle};
 
 
+mod common;
+
 mod error {
     error_chain! {
         links {
@@ -50,11 +55,6 @@ mod error {
         }
 
         errors {
-            NonceMismatch(expected: ::extprim::i128::i128, found: ::extprim::i128::i128) {
-                description("nonce mismatch")
-                display("nonce mismatch (expected {}, found {})", expected, found)
-            }
-
             HtmlErrorText(error_text: String) {
                 description("RPC returned an HTML error")
                 display("RPC returned an HTML error with text: {}", error_text)
@@ -69,12 +69,28 @@ mod error {
                 description("Unknown HTML error structure")
                 display("Unknown HTML error structure:\n{}", html)
             }
+
+            IncompleteBody(expected_len: u64, found_len: usize) {
+                description("Response body is shorter than its Content-Length")
+                display("Response body is {} bytes long, but Content-Length is {}", found_len, expected_len)
+            }
         }
     }
 }
 
+use common::log_wire;
 use error::{ErrorKind, ResultExt};
 
+
+const PRODUCTION_SERVER_URL: &str = "http://149.154.167.51:443/api";
+const TEST_SERVER_URL: &str = "http://149.154.167.40:443/api";
+// TLS certificates are issued for host names, not for the IP addresses above
+const PRODUCTION_SERVER_HTTPS_URL: &str = "https://venus.web.telegram.org/apiw1";
+const TEST_SERVER_HTTPS_URL: &str = "https://venus.web.telegram.org/apiw1_test";
+const DNS_WORKER_THREADS: usize = 4;
+
+type HttpClient = hyper::Client<HttpsConnector<hyper::client::HttpConnector>>;
+
 macro_rules! bailf {
     ($e:expr) => {
         return Box::new(futures::future::err($e.into()))
@@ -94,30 +110,33 @@ macro_rules! tryf {
 fn auth(handle: Handle) -> Box<Future<Item = (), Error = error::Error>> {
     let app_info = tryf!(fetch_app_info());
 
-    let http_client = hyper::Client::new(&handle);
+    // The connector handles both `http` and `https` URLs
+    let https_connector = tryf!(HttpsConnector::new(DNS_WORKER_THREADS, &handle)
+        .chain_err(|| "failed to initialize TLS"));
+    let http_client = hyper::Client::configure().connector(https_connector).build(&handle);
 
     let mut rng = rand::thread_rng();
-    let mut session = Session::new(rng.gen(), app_info);
+    let mut session = Session::new_with_rng(&mut rng, app_info);
 
-    let nonce = rng.gen();
+    let nonce: Nonce128 = rng.gen();
     let req_pq = schema::rpc::req_pq {
-        nonce: nonce,
+        nonce: nonce.to_i128(),
     };
 
-    let http_request = tryf!(create_http_request(&mut session, req_pq, MessageType::PlainText));
+    let (req_pq_message, http_request) =
+        tryf!(create_http_request(&mut session, req_pq, MessageType::PlainText));
     let auth_future = future_request(&http_client, http_request).and_then(move |response_bytes|
-        -> Box<Future<Item = (Vec<u8>, Session), Error = error::Error>>
+        -> Box<Future<Item = (Vec<u8>, Session, Message<schema::rpc::req_DH_params>), Error = error::Error>>
     {
-        let response: Message<schema::ResPQ> =
-            tryf!(parse_response(&mut session, &response_bytes, MessageType::PlainText));
+        let response =
+            tryf!(common::parse_response(
+                &session, &req_pq_message, &response_bytes, MessageType::PlainText, check_transport_error));
 
         let res_pq = response.unwrap_plain_text_body();
 
-        if nonce != res_pq.nonce {
-            bailf!(ErrorKind::NonceMismatch(nonce, res_pq.nonce));
-        }
+        tryf!(nonce.expect_match(&Nonce128::from(res_pq.nonce)));
 
-        let pq_u64 = BigEndian::read_u64(&res_pq.pq);
+        let pq_u64 = tryf!(asymm::pq_from_bytes(&res_pq.pq));
         info!("Decomposing pq = {}...", pq_u64);
         let (p_u32, q_u32) = tryf!(asymm::decompose_pq(pq_u64));
         info!("Decomposed p = {}, q = {}", p_u32, q_u32);
@@ -148,14 +167,13 @@ fn auth(handle: Handle) -> Box<Future<Item = (), Error = error::Error>> {
             })
             .collect::<error::Result<Vec<_>>>());
         info!("Known public key SHA1 fingerprints: {:?}", known_sha1_fingerprints);
-        let known_fingerprints = tryf!(asymm::KNOWN_RAW_KEYS.iter()
-            .map(|raw_key| Ok(raw_key.read()?.fingerprint()?))
-            .collect::<error::Result<Vec<_>>>());
+        let known_fingerprints = asymm::all_fingerprints();
         info!("Known public key fingerprints: {:?}", known_fingerprints);
         let server_pk_fingerprints = res_pq.server_public_key_fingerprints.inner().as_slice();
         info!("Server public key fingerprints: {:?}", &server_pk_fingerprints);
+        let key_set = asymm::PublicKeySet::default();
         let (rsa_public_key, fingerprint) =
-            tryf!(asymm::find_first_key_fail_safe(server_pk_fingerprints));
+            tryf!(asymm::find_first_key_fail_safe(&key_set, server_pk_fingerprints));
         info!("RSA public key used: {:#?}", &rsa_public_key);
         let encrypted_data = tryf!(rsa_public_key.encrypt(&p_q_inner_data_serialized));
         info!("Encrypted data: {:?}", encrypted_data.as_ref());
@@ -172,12 +190,18 @@ fn auth(handle: Handle) -> Box<Future<Item = (), Error = error::Error>> {
             //encrypted_data: encrypted_data2.into(),
         };
 
-        let http_request = tryf!(create_http_request(&mut session, req_dh_params, MessageType::PlainText));
+        let (message, http_request) =
+            tryf!(create_http_request(&mut session, req_dh_params, MessageType::PlainText));
 
-        Box::new(future_request(&http_client, http_request).map(|bytes| (bytes, session)))
-    }).and_then(|(response_bytes, mut session)| {
-        let _: Message<schema::Server_DH_Params> =
-            tryf!(parse_response(&mut session, &response_bytes, MessageType::PlainText));
+        Box::new(future_request(&http_client, http_request).map(|bytes| (bytes, session, message)))
+    }).and_then(|(response_bytes, session, req_dh_params_message)| {
+        let response =
+            tryf!(common::parse_response(
+                &session, &req_dh_params_message, &response_bytes, MessageType::PlainText, check_transport_error));
+
+        let req_dh_params = req_dh_params_message.into_body();
+        let _server_dh_params_ok = tryf!(dh::check_server_dh_params(
+            response.unwrap_plain_text_body(), req_dh_params.nonce, req_dh_params.server_nonce));
 
         Box::new(futures::future::ok(()))
     });
@@ -185,6 +209,21 @@ fn auth(handle: Handle) -> Box<Future<Item = (), Error = error::Error>> {
     Box::new(auth_future)
 }
 
+/// Selects the test DC if `MTPROTO_USE_TEST_DC` is set and a production DC otherwise.
+///
+/// Requests are sent over TLS if `MTPROTO_USE_HTTPS` is set.
+fn server_url() -> &'static str {
+    let use_test_dc = common::use_test_dc();
+    let use_https = env::var_os("MTPROTO_USE_HTTPS").is_some();
+
+    match (use_test_dc, use_https) {
+        (false, false) => PRODUCTION_SERVER_URL,
+        (false, true) => PRODUCTION_SERVER_HTTPS_URL,
+        (true, false) => TEST_SERVER_URL,
+        (true, true) => TEST_SERVER_HTTPS_URL,
+    }
+}
+
 /// Obtain `AppInfo` from all possible known sources in the following
 /// priority:
 ///
@@ -204,7 +243,7 @@ fn fetch_app_info() -> error::Result<AppInfo> {
 fn create_http_request<T>(session: &mut Session,
                           data: T,
                           message_type: MessageType)
-                         -> error::Result<hyper::Request>
+                         -> error::Result<(Message<T>, hyper::Request)>
     where T: fmt::Debug + Serialize + TLObject
 {
     let message = match message_type {
@@ -220,7 +259,7 @@ fn create_http_request<T>(session: &mut Session,
 
     let mut request = hyper::Request::new(
         hyper::Method::Post,
-        "http://149.154.167.51:443/api".parse().unwrap(),
+        server_url().parse().unwrap(),
     );
 
     request
@@ -230,31 +269,42 @@ fn create_http_request<T>(session: &mut Session,
         .headers_mut()
         .set(hyper::header::ContentLength(serialized_message.len() as u64));
 
+    log_wire("Sent", &serialized_message);
     request.set_body(serialized_message);
 
-    Ok(request)
+    Ok((message, request))
 }
 
-fn future_request(http_client: &hyper::Client<hyper::client::HttpConnector>,
+fn future_request(http_client: &HttpClient,
                   http_request: hyper::Request)
                  -> Box<Future<Item = Vec<u8>, Error = error::Error>> {
+    // `concat2()` reads the body until the end of the stream for both
+    // `Content-Length` and chunked responses, but a connection closed
+    // early must not pass for a complete message
     let future = http_client
         .request(http_request)
-        .and_then(|res| res.body().concat2())
-        .map(|data| data.to_vec())
-        .map_err(|err| err.into());
+        .map_err(error::Error::from)
+        .and_then(|res| {
+            let expected_len = res.headers().get::<ContentLength>().map(|len| len.0);
+
+            res.body().concat2().map_err(error::Error::from).and_then(move |data| {
+                log_wire("Received", &data);
+
+                if let Some(expected_len) = expected_len {
+                    if data.len() as u64 != expected_len {
+                        bail!(ErrorKind::IncompleteBody(expected_len, data.len()));
+                    }
+                }
+
+                Ok(data.to_vec())
+            })
+        });
 
     Box::new(future)
 }
 
-fn parse_response<T>(session: &mut Session,
-                     response_bytes: &[u8],
-                     message_type: MessageType)
-                    -> error::Result<Message<T>>
-    where T: fmt::Debug + DeserializeOwned
-{
-    info!("Response bytes: {:?}", &response_bytes);
-
+/// Fails for an HTML error page sent in place of a message.
+fn check_transport_error(response_bytes: &[u8]) -> error::Result<()> {
     if let Ok(response_str) = str::from_utf8(response_bytes) {
         let response_str = response_str.trim();
         let str_len = response_str.len();
@@ -273,21 +323,7 @@ fn parse_response<T>(session: &mut Session,
         }
     }
 
-    let len = response_bytes.len();
-
-    if len < 24 {
-        bail!(ErrorKind::BadMessage(len));
-    }
-
-    let encrypted_data_len = match message_type {
-        MessageType::PlainText => None,
-        MessageType::Encrypted => Some((len - 24) as u32),
-    };
-
-    let response = session.process_message(&response_bytes, encrypted_data_len)?;
-    info!("Message received: {:#?}", &response);
-
-    Ok(response)
+    Ok(())
 }
 
 
@@ -1,40 +1,55 @@
 extern crate byteorder;
-extern crate crc;
 extern crate dotenv;
 extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
 extern crate extprim;
 extern crate futures;
+extern crate futures_cpupool;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate mtproto;
+extern crate net2;
 extern crate rand;
 extern crate serde;
 extern crate serde_mtproto;
 extern crate tokio_core;
+#[macro_use]
 extern crate tokio_io;
 
 
+use std::cell::RefCell;
+use std::env;
 use std::fmt;
-use std::io;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
 
-use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use crc::crc32;
+use byteorder::{BigEndian, ByteOrder};
 use extprim::i128;
 use futures::Future;
+use futures::future::Loop;
+use futures_cpupool::CpuPool;
+use net2::TcpBuilder;
 use mtproto::tl::dynamic::TLObject;
-use mtproto::rpc::{AppInfo, Session};
-use mtproto::rpc::message::{Message, MessageType};
-use mtproto::rpc::encryption::asymm;
+use mtproto::rpc::{config, tcp_common, AppInfo, Session};
+use mtproto::rpc::message::{self, Message, MessageType};
+use mtproto::rpc::encryption::{asymm, dh, AesParams, AuthKey, DhGenOutcome, Nonce128};
+use mtproto::rpc::encryption::obfuscation::{self, ObfuscatedCipher, INTERMEDIATE_TAG};
+use mtproto::rpc::tcp_common::{FrameDecoder, FrameEncoder, FrameMode};
 use mtproto::schema;
 use rand::{Rng, ThreadRng};
 use serde::Serialize;
-use serde::de::DeserializeOwned;
 use serde_mtproto::MtProtoSized;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+
 
+mod common;
 
 mod error {
     error_chain! {
@@ -49,11 +64,6 @@ mod error {
         }
 
         errors {
-            NonceMismatch(expected: ::extprim::i128::i128, found: ::extprim::i128::i128) {
-                description("nonce mismatch")
-                display("nonce mismatch (expected {}, found {})", expected, found)
-            }
-
             ErrorCode(code: i32) {
                 description("RPC returned an error code")
                 display("RPC returned a {} error code", code)
@@ -64,16 +74,28 @@ mod error {
                 display("Message length is neither 4, nor >= 24 bytes: {}", found_len)
             }
 
-            MessageTooLong(len: usize) {
-                description("Message too long to send")
-                display("Message of length {} too long to send", len)
+            ConnectionRefused(addr: ::std::net::SocketAddr) {
+                description("Connection refused")
+                display("Connection to {} refused, the DC may be down or the port wrong", addr)
+            }
+
+            NetworkUnreachable(addr: ::std::net::SocketAddr) {
+                description("Network unreachable")
+                display("Network of {} is unreachable, try another DC address", addr)
             }
         }
     }
 }
 
+use common::log_wire;
 use error::{ErrorKind, ResultExt};
 
+
+const PRODUCTION_SERVER_ADDR: &str = "149.154.167.51:443";
+const TEST_SERVER_ADDR: &str = "149.154.167.40:443";
+// Port to connect to when `MTPROTO_SERVER_HOST` is set
+const SERVER_PORT: u16 = 443;
+
 macro_rules! bailf {
     ($e:expr) => {
         return Box::new(futures::future::err($e.into()))
@@ -95,38 +117,47 @@ fn auth<M>(handle: Handle, mut tcp_mode: M) -> Box<Future<Item = (), Error = err
 {
     let app_info = tryf!(fetch_app_info());
 
-    let remote_addr = "149.154.167.51:443".parse().unwrap();
-    info!("Address: {:?}", &remote_addr);
-    let socket = TcpStream::connect(&remote_addr, &handle).map_err(error::Error::from);
+    let keepalive = tryf!(tcp_keepalive());
+    let local_addr = tryf!(local_addr());
+    let socket = remote_addr().and_then(move |remote_addr| -> Box<Future<Item = TcpStream, Error = error::Error>> {
+        info!("Address: {:?}", &remote_addr);
+        let connecting = tryf!(connect(&remote_addr, local_addr, &handle))
+            .map_err(move |err| connect_error(remote_addr, err));
+
+        Box::new(connecting)
+    }).and_then(move |socket| {
+        configure_socket(&socket, keepalive)?;
+        Ok(socket)
+    });
 
     let auth_future = socket.and_then(|socket|
-        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, ThreadRng, M, i128::i128), Error = error::Error>>
+        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, ThreadRng, M, Message<schema::rpc::req_pq>, Nonce128), Error = error::Error>>
     {
         let mut rng = rand::thread_rng();
-        let mut session = Session::new(rng.gen(), app_info);
+        let mut session = Session::new_with_rng(&mut rng, app_info);
 
-        let nonce = rng.gen();
+        let nonce: Nonce128 = rng.gen();
         let req_pq = schema::rpc::req_pq {
-            nonce: nonce,
+            nonce: nonce.to_i128(),
         };
 
-        let serialized_message = tryf!(create_serialized_message(&mut session, req_pq, MessageType::PlainText));
+        let (message, serialized_message) =
+            tryf!(create_serialized_message(&mut session, req_pq, MessageType::PlainText));
         let request = tcp_mode.request(socket, serialized_message);
 
-        Box::new(request.map(move |(s, b)| (s, b, session, rng, tcp_mode, nonce)))
-    }).and_then(|(socket, response_bytes, mut session, mut rng, mut tcp_mode, nonce)|
-        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, ThreadRng, M), Error = error::Error>>
+        Box::new(request.map(move |(s, b)| (s, b, session, rng, tcp_mode, message, nonce)))
+    }).and_then(|(socket, response_bytes, mut session, mut rng, mut tcp_mode, req_pq_message, nonce)|
+        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, M, Message<schema::rpc::req_DH_params>, (i128::i128, i128::i128)), Error = error::Error>>
     {
-        let response: Message<schema::ResPQ> =
-            tryf!(parse_response(&session, &response_bytes, MessageType::PlainText));
+        let response =
+            tryf!(common::parse_response(
+                &session, &req_pq_message, &response_bytes, MessageType::PlainText, check_transport_error));
 
         let res_pq = response.unwrap_plain_text_body();
 
-        if nonce != res_pq.nonce {
-            bailf!(ErrorKind::NonceMismatch(nonce, res_pq.nonce));
-        }
+        tryf!(nonce.expect_match(&Nonce128::from(res_pq.nonce)));
 
-        let pq_u64 = BigEndian::read_u64(&res_pq.pq);
+        let pq_u64 = tryf!(asymm::pq_from_bytes(&res_pq.pq));
         info!("Decomposing pq = {}...", pq_u64);
         let (p_u32, q_u32) = tryf!(asymm::decompose_pq(pq_u64));
         info!("Decomposed p = {}, q = {}", p_u32, q_u32);
@@ -138,13 +169,14 @@ fn auth<M>(handle: Handle, mut tcp_mode: M) -> Box<Future<Item = (), Error = err
         let p = u32_to_vec(p_u32);
         let q = u32_to_vec(q_u32);
 
+        let new_nonce = rng.gen();
         let p_q_inner_data = schema::P_Q_inner_data::p_q_inner_data(schema::p_q_inner_data {
             pq:  res_pq.pq,
             p: p.clone().into(),
             q: q.clone().into(),
             nonce: res_pq.nonce,
             server_nonce: res_pq.server_nonce,
-            new_nonce: rng.gen(),
+            new_nonce: new_nonce,
         });
 
         info!("Data to send: {:#?}", &p_q_inner_data);
@@ -157,14 +189,13 @@ fn auth<M>(handle: Handle, mut tcp_mode: M) -> Box<Future<Item = (), Error = err
             })
             .collect::<error::Result<Vec<_>>>());
         info!("Known public key SHA1 fingerprints: {:?}", known_sha1_fingerprints);
-        let known_fingerprints = tryf!(asymm::KNOWN_RAW_KEYS.iter()
-            .map(|raw_key| Ok(raw_key.read()?.fingerprint()?))
-            .collect::<error::Result<Vec<_>>>());
+        let known_fingerprints = asymm::all_fingerprints();
         info!("Known public key fingerprints: {:?}", known_fingerprints);
         let server_pk_fingerprints = res_pq.server_public_key_fingerprints.inner().as_slice();
         info!("Server public key fingerprints: {:?}", &server_pk_fingerprints);
+        let key_set = asymm::PublicKeySet::default();
         let (rsa_public_key, fingerprint) =
-            tryf!(asymm::find_first_key_fail_safe(server_pk_fingerprints));
+            tryf!(asymm::find_first_key_fail_safe(&key_set, server_pk_fingerprints));
         info!("RSA public key used: {:#?}", &rsa_public_key);
         let encrypted_data = tryf!(rsa_public_key.encrypt(&p_q_inner_data_serialized));
         info!("Encrypted data: {:?}", encrypted_data.as_ref());
@@ -180,20 +211,228 @@ fn auth<M>(handle: Handle, mut tcp_mode: M) -> Box<Future<Item = (), Error = err
             encrypted_data: encrypted_data.to_vec().into(),
         };
 
-        let serialized_message = tryf!(create_serialized_message(&mut session, req_dh_params, MessageType::PlainText));
+        let (message, serialized_message) =
+            tryf!(create_serialized_message(&mut session, req_dh_params, MessageType::PlainText));
+        let request = tcp_mode.request(socket, serialized_message);
+
+        Box::new(request.map(move |(s, b)| (s, b, session, tcp_mode, message, new_nonce)))
+    }).and_then(|(socket, response_bytes, mut session, mut tcp_mode, req_dh_params_message, new_nonce)|
+        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, M, Message<schema::rpc::set_client_DH_params>, (i128::i128, i128::i128), AuthKey), Error = error::Error>>
+    {
+        let response =
+            tryf!(common::parse_response(
+                &session, &req_dh_params_message, &response_bytes, MessageType::PlainText, check_transport_error));
+
+        let req_dh_params = req_dh_params_message.into_body();
+        let server_dh_params_ok = tryf!(dh::check_server_dh_params(
+            response.unwrap_plain_text_body(), req_dh_params.nonce, req_dh_params.server_nonce));
+
+        let aes_params = tryf!(AesParams::from_nonces(req_dh_params.server_nonce, new_nonce));
+        let server_dh_inner_data =
+            tryf!(dh::decrypt_server_dh_inner_data(&server_dh_params_ok.encrypted_answer, aes_params));
+        info!("Server DH inner data: {:#?}", &server_dh_inner_data);
+        tryf!(dh::check_nonces(req_dh_params.nonce, req_dh_params.server_nonce,
+                               server_dh_inner_data.nonce, server_dh_inner_data.server_nonce));
+
+        let (auth_key, g_b) = tryf!(asymm::calculate_auth_key(
+            server_dh_inner_data.g as u32, &server_dh_inner_data.dh_prime, &server_dh_inner_data.g_a));
+        let client_dh_inner_data = schema::Client_DH_Inner_Data {
+            nonce: req_dh_params.nonce,
+            server_nonce: req_dh_params.server_nonce,
+            retry_id: 0,
+            g_b: g_b.into(),
+        };
+        let encrypted_data = tryf!(dh::encrypt_client_dh_inner_data(client_dh_inner_data, aes_params));
+
+        let set_client_dh_params = schema::rpc::set_client_DH_params {
+            nonce: req_dh_params.nonce,
+            server_nonce: req_dh_params.server_nonce,
+            encrypted_data: encrypted_data.into(),
+        };
+
+        let (message, serialized_message) =
+            tryf!(create_serialized_message(&mut session, set_client_dh_params, MessageType::PlainText));
+        let request = tcp_mode.request(socket, serialized_message);
+
+        Box::new(request.map(move |(s, b)| (s, b, session, tcp_mode, message, new_nonce, auth_key)))
+    }).and_then(|(socket, response_bytes, mut session, mut tcp_mode, set_client_dh_params_message, new_nonce, auth_key)|
+        -> Box<Future<Item = (TcpStream, Vec<u8>, Session, M, Message<schema::rpc::help::getNearestDc>), Error = error::Error>>
+    {
+        let response =
+            tryf!(common::parse_response(
+                &session, &set_client_dh_params_message, &response_bytes, MessageType::PlainText, check_transport_error));
+
+        match tryf!(dh::check_dh_gen_answer(&response.unwrap_plain_text_body(), new_nonce, &auth_key)) {
+            DhGenOutcome::Ok => info!("Authorization key generated: {:#x}", auth_key.key_id()),
+            DhGenOutcome::Retry { .. } => bailf!("the server asked to retry with a new g_b, which this example doesn't do"),
+        }
+
+        let server_nonce = set_client_dh_params_message.into_body().server_nonce;
+        session.adopt_key(auth_key);
+        session.add_server_salts(vec![schema::FutureSalt {
+            valid_since: 0,
+            valid_until: i32::max_value(),
+            salt: dh::initial_server_salt(new_nonce, server_nonce),
+        }]);
+
+        // Now that the key is set, any request can be sent encrypted
+        let (message, serialized_message) =
+            tryf!(create_serialized_message(&mut session, schema::rpc::help::getNearestDc {}, MessageType::Encrypted));
         let request = tcp_mode.request(socket, serialized_message);
 
-        Box::new(request.map(move |(s, b)| (s, b, session, rng, tcp_mode)))
-    }).and_then(|(_socket, response_bytes, session, _rng, _tcp_mode)| {
-        let _: Message<schema::Server_DH_Params> =
-            tryf!(parse_response(&session, &response_bytes, MessageType::PlainText));
+        Box::new(request.map(move |(s, b)| (s, b, session, tcp_mode, message)))
+    }).and_then(|(socket, response_bytes, session, tcp_mode, get_nearest_dc_message)| {
+        // The server may push updates before the reply, which the session
+        // queues, so keep reading frames until the reply arrives
+        let receiver = tcp_mode.receiver();
+
+        let reply = futures::future::loop_fn((socket, response_bytes, session), move |(socket, response_bytes, mut session)|
+            -> Box<Future<Item = Loop<Session, (TcpStream, Vec<u8>, Session)>, Error = error::Error>>
+        {
+            info!("Response bytes: {:?}", &response_bytes);
+            tryf!(check_transport_error(&response_bytes));
+
+            let nearest_dc = tryf!(session.process_encrypted_reply(&get_nearest_dc_message, &response_bytes));
+            match nearest_dc {
+                Some(nearest_dc) => {
+                    println!("Nearest DC: {:#?}", nearest_dc);
+                    Box::new(futures::future::ok(Loop::Break(session)))
+                },
+                None => Box::new(receiver.receive(socket).map(move |(socket, response_bytes)| {
+                    Loop::Continue((socket, response_bytes, session))
+                })),
+            }
+        });
 
-        Box::new(futures::future::ok(()))
+        reply.map(|mut session| {
+            println!("Updates pushed by the server: {:#?}", session.take_updates());
+        })
     });
 
     Box::new(auth_future)
 }
 
+/// Selects the test DC if `MTPROTO_USE_TEST_DC` is set and a production DC otherwise.
+fn server_addr() -> &'static str {
+    if common::use_test_dc() {
+        TEST_SERVER_ADDR
+    } else {
+        PRODUCTION_SERVER_ADDR
+    }
+}
+
+/// Resolves `MTPROTO_SERVER_HOST` if it is set and falls back to
+/// `server_addr()` otherwise.
+///
+/// IPv6 addresses of the host are preferred if `MTPROTO_PREFER_IPV6` is
+/// set. The system resolver blocks, so it runs on a separate thread to
+/// keep the event loop going.
+fn remote_addr() -> Box<Future<Item = SocketAddr, Error = error::Error>> {
+    match env::var("MTPROTO_SERVER_HOST") {
+        Ok(host) => {
+            let prefer_ipv6 = env::var_os("MTPROTO_PREFER_IPV6").is_some();
+            let resolved = CpuPool::new(1)
+                .spawn_fn(move || config::resolve_host(&host, SERVER_PORT, prefer_ipv6));
+
+            Box::new(resolved.map_err(error::Error::from))
+        },
+        Err(_) => Box::new(futures::future::ok(server_addr().parse().unwrap())),
+    }
+}
+
+/// Reads TCP keepalive interval in seconds from `MTPROTO_TCP_KEEPALIVE`,
+/// if it is set.
+fn tcp_keepalive() -> error::Result<Option<Duration>> {
+    match env::var("MTPROTO_TCP_KEEPALIVE") {
+        Ok(secs) => {
+            let secs = secs.parse().chain_err(|| "MTPROTO_TCP_KEEPALIVE must be a number of seconds")?;
+            Ok(Some(Duration::from_secs(secs)))
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads the maximum response length in bytes from
+/// `MTPROTO_MAX_RESPONSE_SIZE`, defaulting to `DEFAULT_MAX_RESPONSE_SIZE`.
+fn max_response_size() -> error::Result<usize> {
+    match env::var("MTPROTO_MAX_RESPONSE_SIZE") {
+        Ok(size) => Ok(size.parse().chain_err(|| "MTPROTO_MAX_RESPONSE_SIZE must be a number of bytes")?),
+        Err(_) => Ok(DEFAULT_MAX_RESPONSE_SIZE),
+    }
+}
+
+/// Reads the local address to bind the socket to from
+/// `MTPROTO_LOCAL_ADDR`, if it is set.
+fn local_addr() -> error::Result<Option<SocketAddr>> {
+    match env::var("MTPROTO_LOCAL_ADDR") {
+        Ok(addr) => Ok(Some(addr.parse().chain_err(|| "MTPROTO_LOCAL_ADDR must be a socket address")?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Connects to `remote_addr`, binding the socket to `local_addr` first if
+/// it is specified, e.g. to use a specific interface on multi-homed
+/// hosts.
+fn connect(remote_addr: &SocketAddr, local_addr: Option<SocketAddr>, handle: &Handle)
+    -> io::Result<Box<Future<Item = TcpStream, Error = io::Error>>>
+{
+    let local_addr = match local_addr {
+        Some(local_addr) => local_addr,
+        None => return Ok(Box::new(TcpStream::connect(remote_addr, handle))),
+    };
+
+    let builder = match *remote_addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    let stream = builder.bind(local_addr)?.to_tcp_stream()?;
+    info!("Bound to local address: {:?}", local_addr);
+
+    Ok(TcpStream::connect_stream(stream, remote_addr, handle))
+}
+
+/// Tells failures which another DC address may fix apart from other IO
+/// errors.
+fn connect_error(remote_addr: SocketAddr, err: io::Error) -> error::Error {
+    let unreachable = match err.kind() {
+        io::ErrorKind::ConnectionRefused => {
+            return error::Error::with_chain(err, ErrorKind::ConnectionRefused(remote_addr));
+        },
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::TimedOut => true,
+        _ => is_unreachable_errno(&err),
+    };
+
+    if unreachable {
+        error::Error::with_chain(err, ErrorKind::NetworkUnreachable(remote_addr))
+    } else {
+        err.into()
+    }
+}
+
+/// `io::ErrorKind` has no variant for unreachable networks and hosts, so
+/// check errno directly where it is known.
+#[cfg(unix)]
+fn is_unreachable_errno(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) => true,
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_unreachable_errno(_err: &io::Error) -> bool {
+    false
+}
+
+/// Disables Nagle's algorithm, since MTProto messages are small and
+/// latency-sensitive, and sets keepalive if requested.
+fn configure_socket(socket: &TcpStream, keepalive: Option<Duration>) -> io::Result<()> {
+    socket.set_nodelay(true)?;
+    socket.set_keepalive(keepalive)?;
+
+    Ok(())
+}
+
 /// Obtain `AppInfo` from all possible known sources in the following
 /// priority:
 ///
@@ -213,7 +452,7 @@ fn fetch_app_info() -> error::Result<AppInfo> {
 fn create_serialized_message<T>(session: &mut Session,
                                 data: T,
                                 message_type: MessageType)
-                               -> error::Result<Vec<u8>>
+                               -> error::Result<(Message<T>, Vec<u8>)>
     where T: fmt::Debug + Serialize + TLObject
 {
     let message = match message_type {
@@ -227,208 +466,224 @@ fn create_serialized_message<T>(session: &mut Session,
     // Here we do mean to unwrap since it should fail if something goes wrong anyway
     assert_eq!(message.size_hint().unwrap(), serialized_message.len());
 
-    Ok(serialized_message)
+    Ok((message, serialized_message))
 }
 
-fn parse_response<T>(session: &Session,
-                     response_bytes: &[u8],
-                     message_type: MessageType)
-                    -> error::Result<Message<T>>
-    where T: fmt::Debug + DeserializeOwned
-{
-    info!("Response bytes: {:?}", &response_bytes);
-
-    let len = response_bytes.len();
-
-    if len == 4 { // Must be an error code
-        // Error codes are represented as negative i32
-        let code = LittleEndian::read_i32(response_bytes);
-        bail!(ErrorKind::ErrorCode(-code));
-    } else if len < 24 {
-        bail!(ErrorKind::BadMessage(len));
+/// Fails for a transport error code sent in place of a message.
+fn check_transport_error(response_bytes: &[u8]) -> error::Result<()> {
+    match message::transport_error_code(response_bytes) {
+        Some(code) => bail!(ErrorKind::ErrorCode(code)),
+        None => Ok(()),
     }
+}
 
-    let encrypted_data_len = match message_type {
-        MessageType::PlainText => None,
-        MessageType::Encrypted => Some((len - 24) as u32),
-    };
 
-    let response = session.process_message(&response_bytes, encrypted_data_len)?;
-    info!("Message received: {:#?}", &response);
+/// Length prefixes above this value are rejected before allocating a buffer for the response.
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
 
-    Ok(response)
-}
+trait MtProtoTcpMode {
+    /// Lays out a frame of this mode, prepended with whatever has to
+    /// precede the first one.
+    fn frame(&mut self, serialized_message: &[u8]) -> error::Result<Vec<u8>>;
 
+    /// Returns a handle to read frames received over the connection.
+    fn receiver(&self) -> FrameReceiver;
 
-trait MtProtoTcpMode {
     fn request(&mut self, socket: TcpStream, serialized_message: Vec<u8>)
-        -> Box<Future<Item = (TcpStream, Vec<u8>), Error = error::Error>>;
+        -> Box<Future<Item = (TcpStream, Vec<u8>), Error = error::Error>>
+    {
+        let data = tryf!(self.frame(&serialized_message));
+        log_wire("Sent", &data);
+
+        // Taken after framing, which sets up the cipher of the obfuscated mode
+        let receiver = self.receiver();
+        let request = tokio_io::io::write_all(socket, data);
+
+        let response = request.map_err(error::Error::from).and_then(move |(socket, _request_bytes)| {
+            receiver.receive(socket)
+        });
+
+        Box::new(response)
+    }
 }
 
-struct FullMode {
-    send_counter: u32,
+/// Reads frames received over a connection, decrypting the bytes first
+/// if the connection is obfuscated.
+///
+/// Clones share the decoder, so bytes read past the end of a frame are
+/// kept for the next one.
+#[derive(Clone)]
+struct FrameReceiver {
+    decoder: Rc<RefCell<FrameDecoder>>,
+    cipher: Option<Rc<RefCell<ObfuscatedCipher>>>,
 }
 
-impl FullMode {
-    fn new() -> FullMode {
-        FullMode { send_counter: 0 }
+impl FrameReceiver {
+    fn new(mode: FrameMode, max_response_size: usize) -> FrameReceiver {
+        FrameReceiver {
+            decoder: Rc::new(RefCell::new(FrameDecoder::new(mode, max_response_size))),
+            cipher: None,
+        }
+    }
+
+    fn receive<R: AsyncRead>(&self, socket: R) -> ReadFrame<R> {
+        ReadFrame {
+            socket: Some(socket),
+            receiver: self.clone(),
+        }
     }
 }
 
-impl MtProtoTcpMode for FullMode {
-    fn request(&mut self, socket: TcpStream, serialized_message: Vec<u8>)
-        -> Box<Future<Item = (TcpStream, Vec<u8>), Error = error::Error>>
-    {
-        let len = serialized_message.len() + 12;
-        let data = if len <= 0xff_ff_ff_ff {
-            let mut data = vec![0; len];
+/// Future of the body of the next frame received over `socket`.
+struct ReadFrame<R> {
+    socket: Option<R>,
+    receiver: FrameReceiver,
+}
 
-            LittleEndian::write_u32(&mut data[0..4], len as u32);
-            LittleEndian::write_u32(&mut data[4..8], self.send_counter);
-            data[8..len-4].copy_from_slice(&serialized_message);
+impl<R: AsyncRead> Future for ReadFrame<R> {
+    type Item = (R, Vec<u8>);
+    type Error = error::Error;
 
-            let crc = crc32::checksum_ieee(&data[0..len-4]);
-            self.send_counter += 1;
+    fn poll(&mut self) -> futures::Poll<(R, Vec<u8>), error::Error> {
+        let mut chunk = [0; 4096];
 
-            LittleEndian::write_u32(&mut data[len-4..], crc);
+        loop {
+            let frame = self.receiver.decoder.borrow_mut().next_frame()?;
+            if let Some(frame) = frame {
+                log_wire("Received", &frame);
+                let socket = self.socket.take().expect("poll a ReadFrame after it's done");
 
-            data
-        } else {
-            bailf!(ErrorKind::MessageTooLong(len));
-        };
+                return Ok(futures::Async::Ready((socket, frame)));
+            }
 
-        let request = tokio_io::io::write_all(socket, data);
+            let read = try_nb!(self.socket.as_mut().expect("poll a ReadFrame after it's done").read(&mut chunk));
+            if read == 0 {
+                return Err(self.receiver.decoder.borrow().closed_mid_message().into());
+            }
 
-        let response = request.and_then(|(socket, _request_bytes)| {
-            tokio_io::io::read_exact(socket, [0; 8])
-        }).and_then(|(socket, first_bytes)| {
-            let len = LittleEndian::read_u32(&first_bytes[0..4]);
-            let ulen = len as usize;
-            // TODO: Check seq_no
-            let _seq_no = LittleEndian::read_u32(&first_bytes[4..8]);
-
-            tokio_io::io::read_exact(socket, vec![0; ulen - 8]).and_then(move |(socket, last_bytes)| {
-                let checksum = LittleEndian::read_u32(&last_bytes[ulen - 12..ulen - 8]);
-                let mut body = last_bytes;
-                body.truncate(ulen - 12);
-
-                let mut value = 0;
-                value = crc32::update(value, &crc32::IEEE_TABLE, &first_bytes[0..4]);
-                value = crc32::update(value, &crc32::IEEE_TABLE, &first_bytes[4..8]);
-                value = crc32::update(value, &crc32::IEEE_TABLE, &body);
-
-                if value == checksum {
-                    futures::future::ok((socket, body))
-                } else {
-                    futures::future::err(io::Error::new(io::ErrorKind::Other, "invalid checksum"))
-                }
-            })
-        });
+            let bytes = match self.receiver.cipher {
+                Some(ref cipher) => cipher.borrow_mut().decrypt(&chunk[..read])?,
+                None => chunk[..read].to_vec(),
+            };
+            self.receiver.decoder.borrow_mut().feed(&bytes);
+        }
+    }
+}
+
+struct FullMode {
+    encoder: FrameEncoder,
+    receiver: FrameReceiver,
+}
+
+impl FullMode {
+    fn new(max_response_size: usize) -> FullMode {
+        FullMode {
+            encoder: FrameEncoder::new(FrameMode::Full),
+            receiver: FrameReceiver::new(FrameMode::Full, max_response_size),
+        }
+    }
+}
+
+impl MtProtoTcpMode for FullMode {
+    /// Lays out a frame as `length + seq_no + payload + crc32`, where
+    /// `seq_no` counts frames sent over this connection starting at 0.
+    fn frame(&mut self, serialized_message: &[u8]) -> error::Result<Vec<u8>> {
+        self.encoder.frame(serialized_message)
+    }
 
-        Box::new(response.map_err(Into::into))
+    fn receiver(&self) -> FrameReceiver {
+        self.receiver.clone()
     }
 }
 
 struct IntermediateMode {
-    is_first_request: bool,
+    encoder: FrameEncoder,
+    receiver: FrameReceiver,
 }
 
 impl IntermediateMode {
-    fn new() -> IntermediateMode {
-        IntermediateMode { is_first_request: true }
+    fn new(max_response_size: usize) -> IntermediateMode {
+        IntermediateMode {
+            encoder: FrameEncoder::new(FrameMode::Intermediate),
+            receiver: FrameReceiver::new(FrameMode::Intermediate, max_response_size),
+        }
     }
 }
 
 impl MtProtoTcpMode for IntermediateMode {
-    fn request(&mut self, socket: TcpStream, serialized_message: Vec<u8>)
-        -> Box<Future<Item = (TcpStream, Vec<u8>), Error = error::Error>>
-    {
-        let len = serialized_message.len();
-        let data = if len <= 0xff_ff_ff_ff {
-            let mut data = vec![0; 4 + len];
+    /// Lays out a frame as `length + payload`, prepended with the
+    /// `0xeeeeeeee` tag for the first request so that both are sent in a
+    /// single write.
+    fn frame(&mut self, serialized_message: &[u8]) -> error::Result<Vec<u8>> {
+        self.encoder.frame(serialized_message)
+    }
 
-            LittleEndian::write_u32(&mut data[0..4], len as u32);
-            data[4..].copy_from_slice(&serialized_message);
+    fn receiver(&self) -> FrameReceiver {
+        self.receiver.clone()
+    }
+}
 
-            data
-        } else {
-            bailf!(ErrorKind::MessageTooLong(len));
-        };
+/// Intermediate mode behind the obfuscated transport: a random 64-byte
+/// init frame is sent first and all traffic after it is encrypted with
+/// AES-256-CTR keys derived from that frame, to get past DPI.
+struct ObfuscatedMode {
+    receiver: FrameReceiver,
+}
 
-        let init: Box<Future<Item = (TcpStream, &'static [u8]), Error = io::Error>> = if self.is_first_request {
-            self.is_first_request = false;
-            Box::new(tokio_io::io::write_all(socket, b"\xee\xee\xee\xee".as_ref()))
-        } else {
-            Box::new(futures::future::ok((socket, [].as_ref())))
-        };
+impl ObfuscatedMode {
+    fn new(max_response_size: usize) -> ObfuscatedMode {
+        ObfuscatedMode {
+            receiver: FrameReceiver::new(FrameMode::Intermediate, max_response_size),
+        }
+    }
+}
 
-        let request = init.and_then(|(socket, _init_bytes)| {
-            tokio_io::io::write_all(socket, data)
-        });
+impl MtProtoTcpMode for ObfuscatedMode {
+    /// Lays out an encrypted intermediate frame, prepended with the init
+    /// frame carrying the `0xeeeeeeee` tag for the first request.
+    fn frame(&mut self, serialized_message: &[u8]) -> error::Result<Vec<u8>> {
+        let frame = tcp_common::frame_intermediate(serialized_message)?;
 
-        let response = request.and_then(|(socket, _request_bytes)| {
-            tokio_io::io::read_exact(socket, [0; 4])
-        }).and_then(|(socket, bytes_len)| {
-            let len = LittleEndian::read_u32(&bytes_len);
-            tokio_io::io::read_exact(socket, vec![0; len as usize]) // Use safe cast
-        });
+        if let Some(ref cipher) = self.receiver.cipher {
+            return Ok(cipher.borrow_mut().encrypt(&frame)?);
+        }
 
-        Box::new(response.map_err(Into::into))
+        let (init, mut cipher) = obfuscation::generate_init_frame(INTERMEDIATE_TAG, None)?;
+        let mut data = init.to_vec();
+        data.extend(cipher.encrypt(&frame)?);
+        self.receiver.cipher = Some(Rc::new(RefCell::new(cipher)));
+
+        Ok(data)
+    }
+
+    fn receiver(&self) -> FrameReceiver {
+        self.receiver.clone()
     }
 }
 
 struct AbridgedMode {
-    is_first_request: bool,
+    encoder: FrameEncoder,
+    receiver: FrameReceiver,
 }
 
 impl AbridgedMode {
-    fn new() -> AbridgedMode {
-        AbridgedMode { is_first_request: true }
+    fn new(max_response_size: usize) -> AbridgedMode {
+        AbridgedMode {
+            encoder: FrameEncoder::new(FrameMode::Abridged),
+            receiver: FrameReceiver::new(FrameMode::Abridged, max_response_size),
+        }
     }
 }
 
 impl MtProtoTcpMode for AbridgedMode {
-    fn request(&mut self, socket: TcpStream, serialized_message: Vec<u8>)
-        -> Box<Future<Item = (TcpStream, Vec<u8>), Error = error::Error>>
-    {
-        let mut data = if self.is_first_request {
-            self.is_first_request = false;
-            vec![0xef]
-        } else {
-            vec![]
-        };
-
-        let len = serialized_message.len() / 4;
-        if len < 0x7f {
-            data.push(len as u8);
-        } else if len < 0xff_ff_ff {
-            data.push(0x7f);
-            LittleEndian::write_uint(&mut data, len as u64, 3); // Use safe cast here
-        } else {
-            bailf!(ErrorKind::MessageTooLong(len));
-        }
-
-        data.extend(serialized_message);
-        let request = tokio_io::io::write_all(socket, data);
-
-        let response = request.and_then(|(socket, _request_bytes)| {
-            tokio_io::io::read_exact(socket, [0; 1])
-        }).and_then(|(socket, byte_id)| {
-            let boxed: Box<Future<Item = (TcpStream, usize), Error = io::Error>> = if byte_id == [0x7f] {
-                Box::new(tokio_io::io::read_exact(socket, [0; 3]).map(|(socket, bytes_len)| {
-                    let len = LittleEndian::read_uint(&bytes_len, 3) as usize * 4;
-                    (socket, len)
-                }))
-            } else {
-                Box::new(futures::future::ok((socket, byte_id[0] as usize * 4)))
-            };
-
-            boxed
-        }).and_then(|(socket, len)| {
-            tokio_io::io::read_exact(socket, vec![0; len])
-        });
+    /// Lays out a frame as `length + payload`, prepended with the `0xef`
+    /// tag for the first request.
+    fn frame(&mut self, serialized_message: &[u8]) -> error::Result<Vec<u8>> {
+        self.encoder.frame(serialized_message)
+    }
 
-        Box::new(response.map_err(Into::into))
+    fn receiver(&self) -> FrameReceiver {
+        self.receiver.clone()
     }
 }
 
@@ -438,13 +693,18 @@ fn run() -> error::Result<()> {
     dotenv::dotenv().ok();  // Fail silently if no .env is present
     let mut core = Core::new()?;
 
-    let auth_future = auth(core.handle(), AbridgedMode::new());
+    let max_response_size = max_response_size()?;
+
+    let auth_future = auth(core.handle(), AbridgedMode::new(max_response_size));
+    core.run(auth_future)?;
+
+    let auth_future = auth(core.handle(), IntermediateMode::new(max_response_size));
     core.run(auth_future)?;
 
-    let auth_future = auth(core.handle(), IntermediateMode::new());
+    let auth_future = auth(core.handle(), FullMode::new(max_response_size));
     core.run(auth_future)?;
 
-    let auth_future = auth(core.handle(), FullMode::new());
+    let auth_future = auth(core.handle(), ObfuscatedMode::new(max_response_size));
     core.run(auth_future)?;
 
     Ok(())
@@ -9,6 +9,7 @@ error_chain! {
         Envy(::envy::Error);
         FromUtf8(::std::string::FromUtf8Error);
         Io(::std::io::Error);
+        Json(::serde_json::Error);
         OpenSsl(::openssl::error::ErrorStack);
         TomlDeserialize(::toml::de::Error);
     }
@@ -25,6 +26,17 @@ error_chain! {
             display("Wrong fingerprint of an encrypted message (expected {}, found {})", expected, found)
         }
 
+        MessageKeyMismatch {
+            description("Message key doesn't match decrypted data")
+            display("Message key doesn't match decrypted data")
+        }
+
+        SessionIdMismatch(expected: i64, found: i64) {
+            description("session_id of a decrypted message doesn't match the current session")
+            display("session_id of a decrypted message doesn't match the current session (expected {}, found {})",
+                expected, found)
+        }
+
         NoServerSalts {
             description("No server salts found in the session")
             display("No server salts found in the session")
@@ -57,6 +69,16 @@ error_chain! {
             display("No exponent found from a RSA key")
         }
 
+        InvalidRsaBlockLength(len: usize) {
+            description("RSA-encrypted data is not 256 bytes long")
+            display("RSA-encrypted data is {} bytes long instead of 256", len)
+        }
+
+        InvalidPqLength(len: usize) {
+            description("pq of resPQ is not a 1 to 8 byte long number")
+            display("pq of resPQ is {} bytes long instead of 1 to 8", len)
+        }
+
         FactorizationFailureSquarePq(pq: u64) {
             description("Factorization failed: pq is a square number")
             display("Factorization failed: pq = {} is a square number", pq)
@@ -67,6 +89,63 @@ error_chain! {
             display("Factorization failed: other reason (pq = {})", pq)
         }
 
+        FactorizationFailureWrongFactors(pq: u64, p: u32, q: u32) {
+            description("Factorization failed: factors don't multiply to pq")
+            display("Factorization failed: {} * {} != pq = {}", p, q, pq)
+        }
+
+        InvalidDhPrime {
+            description("dh_prime is not a 2048-bit safe prime")
+            display("dh_prime is not a 2048-bit safe prime")
+        }
+
+        InvalidDhGenerator(g: u32) {
+            description("Invalid Diffie-Hellman generator for this dh_prime")
+            display("Invalid Diffie-Hellman generator for this dh_prime: {}", g)
+        }
+
+        DhValueOutOfRange {
+            description("Diffie-Hellman value is out of the safe range")
+            display("Diffie-Hellman value is out of the safe range [2^1984, dh_prime - 2^1984]")
+        }
+
+        NonceMismatch(expected: ::extprim::i128::i128, found: ::extprim::i128::i128) {
+            description("nonce of a key exchange reply doesn't match the sent one")
+            display("nonce of a key exchange reply doesn't match the sent one (expected {}, found {})",
+                expected, found)
+        }
+
+        ServerNonceMismatch(expected: ::extprim::i128::i128, found: ::extprim::i128::i128) {
+            description("server_nonce of a key exchange reply doesn't match the one from resPQ")
+            display("server_nonce of a key exchange reply doesn't match the one from resPQ (expected {}, found {})",
+                expected, found)
+        }
+
+        DhParamsInvalid {
+            description("Server rejected req_DH_params")
+            display("Server rejected req_DH_params (server_DH_params_fail)")
+        }
+
+        InnerDataSha1Mismatch {
+            description("SHA1 hash of decrypted inner data doesn't match the embedded one")
+            display("SHA1 hash of decrypted inner data doesn't match the embedded one")
+        }
+
+        InnerDataTooShort(len: usize) {
+            description("Decrypted inner data is too short")
+            display("Decrypted inner data is too short: {} bytes", len)
+        }
+
+        NewNonceHashMismatch(number: u8) {
+            description("new_nonce_hash of Set_client_DH_params_answer doesn't match")
+            display("new_nonce_hash{} of Set_client_DH_params_answer doesn't match", number)
+        }
+
+        DhGenFail {
+            description("Server failed to generate an authorization key")
+            display("Server failed to generate an authorization key (dh_gen_fail)")
+        }
+
         IntegerCast(num: u64) {
             description("Error while casting an integer")
             display("Error while casting an integer: {}", num)
@@ -86,5 +165,127 @@ error_chain! {
             description("Unknown constructor id found while deserializing")
             display("Unknown constructor id found while deserializing {}: {:#x}", type_or_variant, ctor_id)
         }
+
+        InvalidRawObjectLength(len: usize) {
+            description("Raw TL object has an invalid length")
+            display("Raw TL object is {} bytes long, which is not a non-zero multiple of 4", len)
+        }
+
+        GzipInflateFailed(packed_len: usize) {
+            description("Failed to inflate gzip_packed data")
+            display("Failed to inflate {} bytes of gzip_packed data", packed_len)
+        }
+
+        DeserializationLimitExceeded(limit_name: &'static str, limit: usize) {
+            description("Deserialization limit exceeded")
+            display("Deserialization limit exceeded: {} is over {}", limit_name, limit)
+        }
+
+        InvalidMsgContainer(len: usize) {
+            description("Malformed msg_container")
+            display("Malformed msg_container of {} bytes", len)
+        }
+
+        InvalidMsgCopy(len: usize) {
+            description("Malformed msg_copy")
+            display("Malformed msg_copy of {} bytes", len)
+        }
+
+        FrameTooLong(max_len: usize, found_len: usize) {
+            description("Message is too long to fit into a transport frame")
+            display("Message of {} bytes is too long to fit into a transport frame (max {} bytes)", found_len, max_len)
+        }
+
+        UnalignedFrameBody(len: usize) {
+            description("Abridged frame body length is not a multiple of 4")
+            display("Abridged frame body length {} is not a multiple of 4", len)
+        }
+
+        ResponseTooLong(len: u64) {
+            description("Response too long to receive")
+            display("Response of length {} too long to receive", len)
+        }
+
+        FrameTooShort(len: usize) {
+            description("Frame is too short to hold its length, seq_no and checksum")
+            display("Frame of length {} is too short to hold its length, seq_no and checksum", len)
+        }
+
+        SeqNoMismatch(expected: u32, found: u32) {
+            description("Unexpected seq_no of a received frame")
+            display("Unexpected seq_no of a received frame (expected {}, found {})", expected, found)
+        }
+
+        FrameChecksumMismatch(expected: u32, found: u32) {
+            description("Checksum of a received frame doesn't match its contents")
+            display("Checksum of a received frame doesn't match its contents (expected {:#010x}, found {:#010x})",
+                expected, found)
+        }
+
+        ConnectionClosedMidMessage(expected: usize, received: usize) {
+            description("Connection closed before a whole message was received")
+            display("Connection closed after receiving {} bytes of {} expected", received, expected)
+        }
+
+        InvalidPadding(message_data_len: u32, decrypted_len: usize) {
+            description("Decrypted message data length doesn't match its padding")
+            display("Decrypted message data length {} doesn't match its padding (decrypted {} bytes)",
+                message_data_len, decrypted_len)
+        }
+
+        InvalidServerMsgId(msg_id: i64) {
+            description("Message ID received from the server is invalid")
+            display("Message ID {:#x} received from the server has wrong parity or is out of the time window", msg_id)
+        }
+
+        UnexpectedReplyMsgId(expected: i64, found: i64) {
+            description("Reply refers to an unexpected message id")
+            display("Reply refers to an unexpected message id (expected {}, found {})", expected, found)
+        }
+
+        RpcError(code: i32, message: String) {
+            description("RPC function returned an error")
+            display("RPC function returned error {}: {}", code, message)
+        }
+
+        TransportError(code: i32) {
+            description("Server replied with a transport error code")
+            display("Server replied with transport error code {}", code)
+        }
+
+        MsgsStateInfoLengthMismatch(expected: usize, found: usize) {
+            description("msgs_state_info has a different number of states than messages requested")
+            display("msgs_state_info has {} states for {} messages requested", found, expected)
+        }
+
+        InvalidDcAddress(ip_address: String, port: i32) {
+            description("Invalid datacenter address")
+            display("Invalid datacenter address: {}:{}", ip_address, port)
+        }
+
+        DnsResolution(host: String) {
+            description("Failed to resolve a host name")
+            display("Failed to resolve host name {}", host)
+        }
+
+        InvalidFilePartSize(part_size: usize) {
+            description("Invalid file part size")
+            display("Invalid file part size: {} (must be divisible by 1024 and divide 524288)", part_size)
+        }
+
+        InvalidFileChunkSize(limit: usize) {
+            description("Invalid file chunk size")
+            display("Invalid file chunk size: {} (must be divisible by 1024 and divide 1048576)", limit)
+        }
+
+        FileChunkTooLong(limit: usize, found: usize) {
+            description("Received file chunk is longer than requested")
+            display("Received file chunk of {} bytes is longer than requested {} bytes", found, limit)
+        }
+
+        FileCdnRedirect(dc_id: i32) {
+            description("File must be downloaded from a CDN datacenter")
+            display("File must be downloaded from CDN datacenter {}", dc_id)
+        }
     }
 }
@@ -3,11 +3,15 @@
 
 extern crate byteorder;
 extern crate chrono;
+extern crate crc;
 extern crate envy;
 extern crate erased_serde;
 #[macro_use]
 extern crate error_chain;
 extern crate extprim;
+extern crate flate2;
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate num_traits;
@@ -17,16 +21,17 @@ extern crate serde;
 extern crate serde_bytes;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_mtproto;
 #[macro_use]
 extern crate serde_mtproto_derive;
 extern crate toml;
 
 
-mod manual_types;
 mod utils;
 
 pub mod error;
+pub mod manual_types;
 pub mod rpc;
 pub mod schema;
 pub mod tl;
@@ -1,4 +1,22 @@
+//! Types which the generated schema refers to, but can't generate
+//! itself.
+//!
+//! The constructors of `tl/001-manual.tl` are generated into
+//! `schema::manual` like any other TL constructors, but their `Object`
+//! fields map to the dynamically typed `Object` defined here:
+//!
+//! * `rpc_result#f35c6d01` — `schema::manual::RpcResult::result`;
+//! * `message` — `schema::manual::Message::body`, the bare item of
+//!   `msg_container#73f1f8dc` (`schema::manual::MessageContainer`);
+//! * `encrypted` and `plain` — `payload` of `schema::manual::Encrypted` and
+//!   `schema::manual::Plain`.
+//!
+//! An `Object` is serialized with its constructor id when wrapped in
+//! `Boxed`, as the schema does. Reading one back requires knowing all
+//! possible constructors, see `tl::TLConstructorsMap`.
+
 use tl::dynamic::TLObject;
 
+/// Any boxed TL object, the `Object` type of TL schemas.
 pub type Object = Box<TLObject>;
 //pub use tl::dynamic::LengthAndObject;
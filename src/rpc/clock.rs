@@ -0,0 +1,36 @@
+//! Sources of the current time used to generate message IDs.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+
+/// A source of the current local time.
+///
+/// `Session` combines it with the offset to server time, so a clock
+/// which is already synchronized, e.g. via NTP, keeps the offset small.
+pub trait Clock: fmt::Debug + Send {
+    /// Return the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock, used by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock frozen at a single point in time, e.g. to make message IDs
+/// predictable in tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
@@ -0,0 +1,143 @@
+//! Datacenter addresses as advertised by the `help.getConfig` RPC
+//! function.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use error::{self, ErrorKind};
+use schema::{Config, DcOption};
+
+
+impl Config {
+    /// Time after which this config must be fetched again.
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.expires as i64, 0)
+    }
+
+    /// Whether this config is stale at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at()
+    }
+
+    /// How long to wait from `now` before fetching the config again.
+    ///
+    /// Zero if the config has already expired.
+    pub fn refresh_in(&self, now: DateTime<Utc>) -> Duration {
+        refresh_in(self.expires_at(), now)
+    }
+}
+
+
+/// Addresses of datacenters keyed by `(dc_id, is_media, is_ipv6)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DcTable {
+    addrs: BTreeMap<(i32, bool, bool), Vec<SocketAddr>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl DcTable {
+    /// Create an empty table.
+    pub fn new() -> DcTable {
+        DcTable { addrs: BTreeMap::new(), expires_at: None }
+    }
+
+    /// Build a table from all `dc_option` entries of a `Config`.
+    ///
+    /// The table expires along with the config.
+    pub fn from_config(config: &Config) -> error::Result<DcTable> {
+        let mut table = DcTable::from_dc_options(config.dc_options.inner())?;
+        table.expires_at = Some(config.expires_at());
+
+        Ok(table)
+    }
+
+    /// Time after which the addresses may be stale, if the table was
+    /// built from a `Config`.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// Whether the addresses may be stale at `now`.
+    ///
+    /// Tables not built from a `Config` never expire.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map_or(false, |expires_at| now >= expires_at)
+    }
+
+    /// How long to wait from `now` before fetching the config again, if
+    /// the table was built from a `Config`.
+    pub fn refresh_in(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.expires_at.map(|expires_at| refresh_in(expires_at, now))
+    }
+
+    /// Build a table from a sequence of `dc_option` entries.
+    pub fn from_dc_options(dc_options: &[DcOption]) -> error::Result<DcTable> {
+        let mut table = DcTable::new();
+        for dc_option in dc_options {
+            table.add_dc_option(dc_option)?;
+        }
+
+        Ok(table)
+    }
+
+    /// Add an address from a single `dc_option` entry.
+    pub fn add_dc_option(&mut self, dc_option: &DcOption) -> error::Result<()> {
+        let invalid_addr = || ErrorKind::InvalidDcAddress(dc_option.ip_address.clone(), dc_option.port);
+
+        let ip: IpAddr = dc_option.ip_address.parse().map_err(|_| invalid_addr())?;
+        // Port is an `int` in TL and may be anything
+        if dc_option.port < 0 || dc_option.port > 0xffff {
+            bail!(invalid_addr());
+        }
+
+        self.addrs.entry((dc_option.id, dc_option.is_media_only(), dc_option.is_ipv6()))
+            .or_insert_with(Vec::new)
+            .push(SocketAddr::new(ip, dc_option.port as u16));
+
+        Ok(())
+    }
+
+    /// Addresses of a datacenter of the specified kind.
+    ///
+    /// Returns an empty slice if there are none.
+    pub fn get(&self, dc_id: i32, is_media: bool, is_ipv6: bool) -> &[SocketAddr] {
+        self.addrs.get(&(dc_id, is_media, is_ipv6))
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// First address of a datacenter, trying IPv6 addresses first if
+    /// `prefer_ipv6` is set and IPv4 addresses first otherwise.
+    pub fn preferred_addr(&self, dc_id: i32, is_media: bool, prefer_ipv6: bool) -> Option<SocketAddr> {
+        self.get(dc_id, is_media, prefer_ipv6).first()
+            .or_else(|| self.get(dc_id, is_media, !prefer_ipv6).first())
+            .cloned()
+    }
+}
+
+
+fn refresh_in(expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    if now >= expires_at {
+        Duration::zero()
+    } else {
+        expires_at.signed_duration_since(now)
+    }
+}
+
+/// Resolve a host name to a single address, trying IPv6 addresses first
+/// if `prefer_ipv6` is set and IPv4 addresses first otherwise.
+///
+/// This uses the system resolver and so blocks the current thread, run
+/// it on a thread pool when called from an event loop.
+pub fn resolve_host(host: &str, port: u16, prefer_ipv6: bool) -> error::Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()
+        .map_err(|_| ErrorKind::DnsResolution(host.to_owned()))?
+        .collect();
+
+    addrs.iter().find(|addr| addr.is_ipv6() == prefer_ipv6)
+        .or_else(|| addrs.first())
+        .cloned()
+        .ok_or(ErrorKind::DnsResolution(host.to_owned()).into())
+}
@@ -0,0 +1,116 @@
+//! Downloading files in chunks with `upload.getFile`.
+
+use std::io::Write;
+
+use error::{self, ErrorKind};
+use schema::InputFileLocation;
+use schema::rpc::upload::getFile;
+use schema::upload::File;
+
+
+/// Largest chunk size accepted by the server.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+const FILE_MIGRATE_PREFIX: &str = "FILE_MIGRATE_";
+
+
+/// Assembles a file from `upload.getFile` replies into a `Write`
+/// instance.
+///
+/// Requests are created with `next_request()` and their replies passed
+/// to `process_chunk()` until a chunk shorter than the limit signals the
+/// end of the file.
+#[derive(Debug)]
+pub struct FileDownload<W> {
+    writer: W,
+    location: InputFileLocation,
+    limit: usize,
+    offset: usize,
+    finished: bool,
+}
+
+impl<W: Write> FileDownload<W> {
+    /// Construct a `FileDownload` which requests chunks of `limit` bytes.
+    ///
+    /// `limit` must be divisible by 1 KB and 1 MB must be divisible by
+    /// `limit`, which also keeps all offsets 1 KB-aligned.
+    pub fn new(writer: W, location: InputFileLocation, limit: usize) -> error::Result<FileDownload<W>> {
+        if limit == 0 || limit % 1024 != 0 || MAX_CHUNK_SIZE % limit != 0 {
+            bail!(ErrorKind::InvalidFileChunkSize(limit));
+        }
+
+        Ok(FileDownload {
+            writer: writer,
+            location: location,
+            limit: limit,
+            offset: 0,
+            finished: false,
+        })
+    }
+
+    /// Return the number of bytes downloaded so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Whether the whole file was downloaded.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Return the request for the next chunk or `None` if the whole file
+    /// was downloaded.
+    ///
+    /// The request is the same until its reply is processed, so it may
+    /// be resent as is, e.g. to another datacenter after
+    /// `FILE_MIGRATE_X`.
+    pub fn next_request(&self) -> Option<getFile> {
+        if self.finished {
+            return None;
+        }
+
+        // Offsets are `int` in TL, so files are limited to 2 GB
+        Some(getFile {
+            location: self.location.clone(),
+            offset: self.offset as i32,
+            limit: self.limit as i32,
+        })
+    }
+
+    /// Write a chunk received in reply to `next_request()`.
+    pub fn process_chunk(&mut self, file: File) -> error::Result<()> {
+        let chunk = match file {
+            File::file(file) => file.bytes,
+            File::fileCdnRedirect(redirect) => bail!(ErrorKind::FileCdnRedirect(redirect.dc_id)),
+        };
+
+        if chunk.len() > self.limit {
+            bail!(ErrorKind::FileChunkTooLong(self.limit, chunk.len()));
+        }
+
+        self.writer.write_all(&chunk)?;
+        self.offset += chunk.len();
+
+        // A short chunk can only be the last one
+        if chunk.len() < self.limit {
+            self.finished = true;
+        }
+
+        Ok(())
+    }
+
+    /// Return the underlying writer.
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+}
+
+/// Return the datacenter ID the file must be downloaded from if
+/// `error_message` of `rpc_error` is `FILE_MIGRATE_X`.
+pub fn file_migrate_dc(error_message: &str) -> Option<i32> {
+    if error_message.starts_with(FILE_MIGRATE_PREFIX) {
+        error_message[FILE_MIGRATE_PREFIX.len()..].parse().ok()
+    } else {
+        None
+    }
+}
@@ -1,17 +1,25 @@
 //! Asymmetric-key operations and facilities around them.
 
 use std::fmt;
+use std::sync::Arc;
 
 use byteorder::{LittleEndian, ByteOrder};
-use openssl::{bn, hash, rsa};
+use openssl::{bn, rsa};
 use serde_bytes::ByteBuf;
 use serde_mtproto;
 
 use error::{self, ErrorKind};
+use rpc::utils::sha1_bytes;
+use tl::BeU64;
 use utils::safe_int_cast;
 
 use super::symm::AuthKey;
-use super::utils::{Padding, sha1_and_or_pad};
+use super::utils::{Padding, sha1_and_or_pad, zero_bytes};
+
+
+/// Length of `encrypted_data` of `req_DH_params`, i.e. of a 2048-bit RSA
+/// block.
+pub const RSA_BLOCK_LEN: usize = 256;
 
 
 /// RSA public key stored as **X.509 SubjectPublicKeyInfo/OpenSSL PEM
@@ -105,10 +113,7 @@ impl RsaPublicKey {
         serde_mtproto::to_writer(&mut buf, &ByteBuf::from(n_bytes))?;
         serde_mtproto::to_writer(&mut buf, &ByteBuf::from(e_bytes))?;
 
-        let mut hasher = hash::Hasher::new(hash::MessageDigest::sha1())?;
-        hasher.update(&buf)?;
-
-        Ok(hasher.finish2().map(|b| b.to_vec())?)
+        sha1_bytes(&[&buf])
     }
 
     pub fn fingerprint(&self) -> error::Result<i64> {
@@ -118,13 +123,16 @@ impl RsaPublicKey {
     }
 
     /// Encrypts using the internal RSA key.
-    pub fn encrypt(&self, input: &[u8]) -> error::Result<[u8; 256]> {
+    pub fn encrypt(&self, input: &[u8]) -> error::Result<[u8; RSA_BLOCK_LEN]> {
         let mut padded_input = sha1_and_or_pad(input, true, Padding::Total255Random)?;
         padded_input.insert(0, 0);    // OpenSSL requires exactly 256 bytes
         debug!("Padded input: {:?}", &padded_input);
 
-        let mut output = [0; 256];
-        self.0.public_encrypt(&padded_input, &mut output, rsa::NO_PADDING)?;
+        let mut output = [0; RSA_BLOCK_LEN];
+        let len = self.0.public_encrypt(&padded_input, &mut output, rsa::NO_PADDING)?;
+        if len != RSA_BLOCK_LEN {
+            bail!(ErrorKind::InvalidRsaBlockLength(len));
+        }
 
         Ok(output)
     }
@@ -132,6 +140,9 @@ impl RsaPublicKey {
     /// Other implementation of RSA encryption, just to verify that we are on the right track with
     /// `encrypt()`. Also can be served as a drop-in replacement in case if we abandon OpenSSL
     /// dependency after rewriting this method to use `num_bigint::BigUInt`.
+    ///
+    /// The result is left-padded with zeros to exactly 256 bytes, like
+    /// the output of `encrypt()`.
     pub fn encrypt2(&self, input: &[u8]) -> error::Result<Vec<u8>> {
         let padded_input = sha1_and_or_pad(input, true, Padding::Total255Random)?;
         debug!("Padded input: {:?}", &padded_input);
@@ -144,35 +155,195 @@ impl RsaPublicKey {
         let mut context = bn::BigNumContext::new()?;
         output.mod_exp(&bn_padded_input, e, n, &mut context)?;
 
-        Ok(output.to_vec())
+        // `to_vec()` drops leading zero bytes
+        let output = output.to_vec();
+        if output.len() > RSA_BLOCK_LEN {
+            bail!(ErrorKind::InvalidRsaBlockLength(output.len()));
+        }
+
+        let mut block = vec![0; RSA_BLOCK_LEN - output.len()];
+        block.extend(output);
+
+        Ok(block)
+    }
+}
+
+/// Set of RSA public keys to authorize with.
+///
+/// Defaults to keys known at compile-time, but can be extended or
+/// replaced at runtime, e.g. when server keys are rotated or test DCs
+/// are used. Keys are parsed once when they are added.
+#[derive(Clone, Debug)]
+pub struct PublicKeySet {
+    keys: Vec<(Arc<RsaPublicKey>, i64)>,
+}
+
+lazy_static! {
+    static ref KNOWN_KEYS: PublicKeySet = {
+        let mut key_set = PublicKeySet::new();
+        for raw_key in KNOWN_RAW_KEYS {
+            key_set.add_raw_key(raw_key).expect("keys known at compile-time must be valid");
+        }
+
+        key_set
+    };
+}
+
+impl Default for PublicKeySet {
+    fn default() -> PublicKeySet {
+        KNOWN_KEYS.clone()
+    }
+}
+
+impl PartialEq for PublicKeySet {
+    fn eq(&self, other: &PublicKeySet) -> bool {
+        self.fingerprints() == other.fingerprints()
+    }
+}
+
+impl PublicKeySet {
+    /// Construct an empty key set.
+    pub fn new() -> PublicKeySet {
+        PublicKeySet {
+            keys: Vec::new(),
+        }
+    }
+
+    /// Construct a key set from PEM-encoded public keys, without the
+    /// built-in ones.
+    pub fn from_pems<S: AsRef<str>>(pems: &[S]) -> error::Result<PublicKeySet> {
+        let mut key_set = PublicKeySet::new();
+        for pem in pems {
+            key_set.add_pem(pem.as_ref())?;
+        }
+
+        Ok(key_set)
+    }
+
+    /// Add a PEM-encoded public key and return its fingerprint.
+    pub fn add_pem(&mut self, pem: &str) -> error::Result<i64> {
+        self.add_raw_key(&RsaRawPublicKeyRef(pem.as_bytes()))
+    }
+
+    fn add_raw_key(&mut self, raw_key: &RsaRawPublicKeyRef) -> error::Result<i64> {
+        let key = raw_key.read()?;
+        let fingerprint = key.fingerprint()?;
+        self.keys.push((Arc::new(key), fingerprint));
+
+        Ok(fingerprint)
+    }
+
+    /// Fingerprints of all keys in the set.
+    pub fn fingerprints(&self) -> Vec<i64> {
+        self.keys.iter().map(|&(_, fingerprint)| fingerprint).collect()
+    }
+
+    /// Find the key with the specified fingerprint in the set.
+    pub fn key_for_fingerprint(&self, fingerprint: i64) -> Option<&RsaPublicKey> {
+        find_first_key(self, &[fingerprint]).map(|(key, _)| key)
     }
 }
 
+/// Find the key known at compile-time with the specified fingerprint.
+pub fn key_for_fingerprint(fingerprint: i64) -> Option<&'static RsaPublicKey> {
+    KNOWN_KEYS.key_for_fingerprint(fingerprint)
+}
+
+/// Fingerprints of all keys known at compile-time.
+pub fn all_fingerprints() -> Vec<i64> {
+    KNOWN_KEYS.fingerprints()
+}
+
 /// Flat version of `find_first_key` function.
 ///
 /// Upon failure to find a suitable key, it returns an error rather than
 /// `None`.
-pub fn find_first_key_fail_safe(of_fingerprints: &[i64]) -> error::Result<(RsaPublicKey, i64)> {
-    find_first_key(of_fingerprints)?
+pub fn find_first_key_fail_safe<'a>(key_set: &'a PublicKeySet, of_fingerprints: &[i64])
+    -> error::Result<(&'a RsaPublicKey, i64)>
+{
+    find_first_key(key_set, of_fingerprints)
         .ok_or(ErrorKind::NoRsaPublicKeyForFingerprints(of_fingerprints.to_vec()).into())
 }
 
-/// Find a key fingerprint of which can be found in the supplied
-/// sequence of fingerprints.
-pub fn find_first_key(of_fingerprints: &[i64]) -> error::Result<Option<(RsaPublicKey, i64)>> {
-    for raw_key in KNOWN_RAW_KEYS {
-        let key = raw_key.read()?;
-        let fingerprint = key.fingerprint()?;
+/// Find a key from `key_set` fingerprint of which can be found in the
+/// supplied sequence of fingerprints.
+pub fn find_first_key<'a>(key_set: &'a PublicKeySet, of_fingerprints: &[i64])
+    -> Option<(&'a RsaPublicKey, i64)>
+{
+    key_set.keys.iter()
+        .find(|&&(_, fingerprint)| of_fingerprints.contains(&fingerprint))
+        .map(|&(ref key, fingerprint)| (&**key, fingerprint))
+}
 
-        if of_fingerprints.contains(&fingerprint) {
-            return Ok(Some((key, fingerprint)));
-        }
+const DH_PRIME_BITS: i32 = 2048;
+// Probability of a composite passing is at most 4^-64
+const DH_PRIME_CHECKS: i32 = 64;
+
+/// Checks Diffie-Hellman parameters received from the server.
+///
+/// `dh_prime` must be a 2048-bit safe prime, `g` must generate a
+/// subgroup of prime order `(dh_prime - 1) / 2` and `g_a` must lie in
+/// `[2^1984, dh_prime - 2^1984]`.
+pub fn check_dh_params(g: u32, dh_prime: &[u8], g_a: &[u8]) -> error::Result<()> {
+    let mut ctx = bn::BigNumContext::new()?;
+    let dh_prime = bn::BigNum::from_slice(dh_prime)?;
+    let g_a = bn::BigNum::from_slice(g_a)?;
+
+    check_dh_prime(&dh_prime, &mut ctx)?;
+    check_dh_generator(g, &dh_prime)?;
+    check_dh_value_range(&g_a, &dh_prime)
+}
+
+fn check_dh_prime(dh_prime: &bn::BigNumRef, ctx: &mut bn::BigNumContextRef) -> error::Result<()> {
+    if dh_prime.num_bits() != DH_PRIME_BITS || !dh_prime.is_prime(DH_PRIME_CHECKS, ctx)? {
+        bail!(ErrorKind::InvalidDhPrime);
+    }
+
+    // dh_prime is an odd prime now, so this is (dh_prime - 1) / 2
+    let mut half = bn::BigNum::new()?;
+    half.rshift1(dh_prime)?;
+    if !half.is_prime(DH_PRIME_CHECKS, ctx)? {
+        bail!(ErrorKind::InvalidDhPrime);
+    }
+
+    Ok(())
+}
+
+fn check_dh_generator(g: u32, dh_prime: &bn::BigNumRef) -> error::Result<()> {
+    // Quadratic reciprocity conditions from https://core.telegram.org/mtproto/auth_key
+    let is_valid = match g {
+        2 => dh_prime.mod_word(8)? == 7,
+        3 => dh_prime.mod_word(3)? == 2,
+        4 => true,
+        5 => [1, 4].contains(&dh_prime.mod_word(5)?),
+        6 => [19, 23].contains(&dh_prime.mod_word(24)?),
+        7 => [3, 5, 6].contains(&dh_prime.mod_word(7)?),
+        _ => false,
+    };
+
+    if !is_valid {
+        bail!(ErrorKind::InvalidDhGenerator(g));
+    }
+
+    Ok(())
+}
+
+fn check_dh_value_range(value: &bn::BigNumRef, dh_prime: &bn::BigNumRef) -> error::Result<()> {
+    let mut lower_bound = bn::BigNum::new()?;
+    lower_bound.set_bit(DH_PRIME_BITS - 64)?;
+    let mut upper_bound = bn::BigNum::new()?;
+    upper_bound.checked_sub(dh_prime, &lower_bound)?;
+
+    if *value < *lower_bound || *value > *upper_bound {
+        bail!(ErrorKind::DhValueOutOfRange);
     }
 
-    Ok(None)
+    Ok(())
 }
 
 pub fn calculate_auth_key(g: u32, dh_prime: &[u8], g_a: &[u8]) -> error::Result<(AuthKey, Vec<u8>)> {
+    check_dh_params(g, dh_prime, g_a)?;
+
     let mut ctx = bn::BigNumContext::new()?;
     let g = bn::BigNum::from_u32(g)?;
     let dh_prime = bn::BigNum::from_slice(dh_prime)?;
@@ -180,22 +351,55 @@ pub fn calculate_auth_key(g: u32, dh_prime: &[u8], g_a: &[u8]) -> error::Result<
 
     loop {
         let mut b = bn::BigNum::new()?;
-        b.rand(2048, bn::MSB_MAYBE_ZERO, false)?;
-        let mut g_b = bn::BigNum::new()?;
-        g_b.mod_exp(&g, &b, &dh_prime, &mut ctx)?;
-        // .num_bytes() returns i32 and AUTH_KEY_SIZE is usize, so use u64 since it embraces
-        // both i32 and usize (until 128-bit machines are in the wild)
-        if g_b.num_bytes() as u64 != super::AUTH_KEY_SIZE as u64 || g_b >= dh_prime {
-            continue;
-        }
-        let mut auth_key = bn::BigNum::new()?;
-        auth_key.mod_exp(&g_a, &b, &dh_prime, &mut ctx)?;
-        // Same here
-        if auth_key.num_bytes() as u64 != super::AUTH_KEY_SIZE as u64 {
-            continue;
+        let attempt = try_dh_exchange(&g, &dh_prime, &g_a, &mut b, &mut ctx);
+        // `BigNum` is freed without zeroing on drop
+        b.clear();
+
+        if let Some(result) = attempt? {
+            return Ok(result);
         }
-        let auth_key = AuthKey::new(&auth_key.to_vec())?;
-        return Ok((auth_key, g_b.to_vec()));
+    }
+}
+
+/// Generates a secret `b` and computes `g_b` and the authorization key
+/// from it, returning `None` if either is unsuitable and the exchange
+/// must be retried with another `b`.
+///
+/// The authorization key is cleared from the `BigNum` it is computed in.
+fn try_dh_exchange(g: &bn::BigNumRef,
+                   dh_prime: &bn::BigNumRef,
+                   g_a: &bn::BigNumRef,
+                   b: &mut bn::BigNumRef,
+                   ctx: &mut bn::BigNumContextRef)
+                  -> error::Result<Option<(AuthKey, Vec<u8>)>> {
+    b.rand(2048, bn::MSB_MAYBE_ZERO, false)?;
+    let mut g_b = bn::BigNum::new()?;
+    g_b.mod_exp(g, b, dh_prime, ctx)?;
+    // .num_bytes() returns i32 and AUTH_KEY_SIZE is usize, so use u64 since it embraces
+    // both i32 and usize (until 128-bit machines are in the wild)
+    if g_b.num_bytes() as u64 != super::AUTH_KEY_SIZE as u64 || &*g_b >= dh_prime {
+        return Ok(None);
+    }
+    if check_dh_value_range(&g_b, dh_prime).is_err() {
+        return Ok(None);
+    }
+
+    let mut auth_key = bn::BigNum::new()?;
+    let computed = auth_key.mod_exp(g_a, b, dh_prime, ctx);
+    let mut auth_key_bytes = auth_key.to_vec();
+    auth_key.clear();
+    computed?;
+
+    let result = if auth_key_bytes.len() == super::AUTH_KEY_SIZE {
+        Some(AuthKey::new(&auth_key_bytes))
+    } else {
+        None
+    };
+    zero_bytes(&mut auth_key_bytes);
+
+    match result {
+        Some(auth_key) => Ok(Some((auth_key?, g_b.to_vec()))),
+        None => Ok(None),
     }
 }
 
@@ -208,6 +412,22 @@ fn ceil_isqrt(x: u64) -> u64 {
     ret
 }
 
+/// Interprets `pq` of `resPQ` as a big-endian number.
+///
+/// `pq` is expected to be 8 bytes long, but shorter values are accepted
+/// as well since they represent the same number without leading zeros.
+pub fn pq_from_bytes(pq: &[u8]) -> error::Result<u64> {
+    if pq.is_empty() || pq.len() > 8 {
+        bail!(ErrorKind::InvalidPqLength(pq.len()));
+    }
+
+    // Restore leading zeros
+    let mut bytes = [0; 8];
+    bytes[8 - pq.len()..].copy_from_slice(pq);
+
+    Ok(BeU64::from_bytes(bytes).0)
+}
+
 /// Decomposes a large composite number into 2 primes.
 ///
 /// Uses [Fermat's factorization method][fermat].
@@ -229,6 +449,17 @@ pub fn decompose_pq(pq: u64) -> error::Result<(u32, u32)> {
         let q = safe_int_cast::<u64, u32>(if pq_sqrt > y { pq_sqrt - y } else { y - pq_sqrt })?;
         let (p, q) = if p > q {(q, p)} else {(p, q)};
         debug!("decompose_pq({}) = ({}, {})", pq, p, q);
+        check_pq_factors(pq, p, q)?;
         return Ok((p, q))
     }
 }
+
+/// Checks that `p * q == pq`, so that a wrong factorization is caught
+/// before it is sent to the server.
+pub fn check_pq_factors(pq: u64, p: u32, q: u32) -> error::Result<()> {
+    if (p as u64).checked_mul(q as u64) != Some(pq) {
+        bail!(ErrorKind::FactorizationFailureWrongFactors(pq, p, q));
+    }
+
+    Ok(())
+}
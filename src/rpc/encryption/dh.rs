@@ -0,0 +1,141 @@
+//! Encryption of the inner data exchanged during the Diffie-Hellman
+//! step of key generation.
+
+use byteorder::{ByteOrder, LittleEndian};
+use extprim::i128::i128;
+use serde_mtproto::{self, Boxed, MtProtoSized};
+
+use error::{self, ErrorKind};
+use rpc::utils::sha1_bytes;
+use schema::{Client_DH_Inner_Data, Server_DH_Params, Server_DH_inner_data, Set_client_DH_params_answer,
+             server_DH_params_ok};
+
+use super::nonce::Nonce128;
+use super::symm::{AesParams, AuthKey, i128_to_le_bytes};
+
+
+const SHA1_LEN: usize = 20;
+
+
+/// Encrypts `client_DH_inner_data` to be sent as `encrypted_data` of
+/// `set_client_DH_params`.
+///
+/// The serialized data is prepended with its SHA1 hash and padded with
+/// random bytes to a multiple of 16 bytes before AES-IGE encryption.
+pub fn encrypt_client_dh_inner_data(inner: Client_DH_Inner_Data, aes: AesParams) -> error::Result<Vec<u8>> {
+    let serialized = serde_mtproto::to_bytes(&Boxed::new(inner))?;
+    aes.ige_encrypt_random_padded(&serialized)
+}
+
+/// Checks `nonce` and `server_nonce` of a key exchange reply against the
+/// ones of the current exchange.
+pub fn check_nonces(nonce: i128, server_nonce: i128, found_nonce: i128, found_server_nonce: i128)
+    -> error::Result<()>
+{
+    Nonce128::from(nonce).expect_match(&Nonce128::from(found_nonce))?;
+
+    if Nonce128::from(found_server_nonce) != Nonce128::from(server_nonce) {
+        bail!(ErrorKind::ServerNonceMismatch(server_nonce, found_server_nonce));
+    }
+
+    Ok(())
+}
+
+/// Checks the nonces of a `req_DH_params` reply and extracts
+/// `server_DH_params_ok` from it.
+///
+/// `server_DH_params_fail` is reported as `DhParamsInvalid`.
+pub fn check_server_dh_params(params: Server_DH_Params, nonce: i128, server_nonce: i128)
+    -> error::Result<server_DH_params_ok>
+{
+    match params {
+        Server_DH_Params::server_DH_params_ok(ok) => {
+            check_nonces(nonce, server_nonce, ok.nonce, ok.server_nonce)?;
+            Ok(ok)
+        },
+        Server_DH_Params::server_DH_params_fail(fail) => {
+            check_nonces(nonce, server_nonce, fail.nonce, fail.server_nonce)?;
+            bail!(ErrorKind::DhParamsInvalid)
+        },
+    }
+}
+
+/// Decrypts `encrypted_answer` of `server_DH_params_ok` and verifies its
+/// embedded SHA1 hash.
+pub fn decrypt_server_dh_inner_data(encrypted_answer: &[u8], aes: AesParams) -> error::Result<Server_DH_inner_data> {
+    let decrypted = aes.ige_decrypt(encrypted_answer)?;
+
+    if decrypted.len() < SHA1_LEN {
+        bail!(ErrorKind::InnerDataTooShort(decrypted.len()));
+    }
+
+    // Trailing padding is ignored by the deserializer...
+    let inner: Boxed<Server_DH_inner_data> = serde_mtproto::from_bytes(&decrypted[SHA1_LEN..], None)?;
+    // ... but must not be hashed
+    let inner_len = inner.size_hint()?;
+
+    let sha1 = sha1_bytes(&[&decrypted[SHA1_LEN..SHA1_LEN + inner_len]])?;
+    if sha1.as_slice() != &decrypted[0..SHA1_LEN] {
+        bail!(ErrorKind::InnerDataSha1Mismatch);
+    }
+
+    Ok(inner.into_inner())
+}
+
+
+/// Successful outcomes of `set_client_DH_params`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DhGenOutcome {
+    /// The authorization key is ready to use.
+    Ok,
+    /// `set_client_DH_params` must be resent with a new `g_b` and this
+    /// `retry_id`.
+    Retry { retry_id: i64 },
+}
+
+/// Verifies `new_nonce_hash{1,2,3}` of a `set_client_DH_params` reply
+/// against the locally computed authorization key.
+///
+/// `dh_gen_fail` is reported as an error once its hash is verified.
+pub fn check_dh_gen_answer(answer: &Set_client_DH_params_answer,
+                           new_nonce: (i128, i128),
+                           auth_key: &AuthKey)
+                          -> error::Result<DhGenOutcome> {
+    let (number, new_nonce_hash) = match *answer {
+        Set_client_DH_params_answer::dh_gen_ok(ref ok) => (1, ok.new_nonce_hash1),
+        Set_client_DH_params_answer::dh_gen_retry(ref retry) => (2, retry.new_nonce_hash2),
+        Set_client_DH_params_answer::dh_gen_fail(ref fail) => (3, fail.new_nonce_hash3),
+    };
+
+    if calculate_new_nonce_hash(new_nonce, number, auth_key.aux_hash())? != new_nonce_hash {
+        bail!(ErrorKind::NewNonceHashMismatch(number));
+    }
+
+    match number {
+        1 => Ok(DhGenOutcome::Ok),
+        2 => Ok(DhGenOutcome::Retry { retry_id: auth_key.aux_hash() }),
+        _ => bail!(ErrorKind::DhGenFail),
+    }
+}
+
+/// Computes the lower 128 bits of
+/// `SHA1(new_nonce + [number] + auth_key_aux_hash)`.
+pub fn calculate_new_nonce_hash(new_nonce: (i128, i128), number: u8, aux_hash: i64) -> error::Result<i128> {
+    let mut aux_hash_bytes = [0; 8];
+    LittleEndian::write_i64(&mut aux_hash_bytes, aux_hash);
+
+    let sha1 = sha1_bytes(&[&i128_to_le_bytes(new_nonce.0), &i128_to_le_bytes(new_nonce.1), &[number], &aux_hash_bytes])?;
+    let lo = LittleEndian::read_u64(&sha1[4..12]);
+    let hi = LittleEndian::read_i64(&sha1[12..20]);
+
+    Ok(i128::from_parts(hi, lo))
+}
+
+/// Computes the first server salt of a new authorization key as
+/// `substr(new_nonce, 0, 8) XOR substr(server_nonce, 0, 8)`.
+pub fn initial_server_salt(new_nonce: (i128, i128), server_nonce: i128) -> i64 {
+    let new_nonce_start = LittleEndian::read_i64(&i128_to_le_bytes(new_nonce.0)[0..8]);
+    let server_nonce_start = LittleEndian::read_i64(&i128_to_le_bytes(server_nonce)[0..8]);
+
+    new_nonce_start ^ server_nonce_start
+}
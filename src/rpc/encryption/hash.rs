@@ -0,0 +1,24 @@
+//! Hash functions used throughout MTProto.
+//!
+//! These are thin wrappers around OpenSSL, exposed for those who build
+//! their own inner data structures or message keys.
+
+use error;
+use rpc::utils::{sha1_bytes, sha256_bytes};
+
+
+/// Compute the SHA-1 digest of `data`.
+pub fn sha1(data: &[u8]) -> error::Result<[u8; 20]> {
+    let mut digest = [0; 20];
+    digest.copy_from_slice(&sha1_bytes(&[data])?);
+
+    Ok(digest)
+}
+
+/// Compute the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> error::Result<[u8; 32]> {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&sha256_bytes(&[data])?);
+
+    Ok(digest)
+}
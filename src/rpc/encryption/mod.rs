@@ -1,12 +1,21 @@
 //! Wrappers and functions around low-level encryption primitives.
 
 pub mod asymm;
+pub mod dh;
+pub mod hash;
+pub mod msg_key;
+pub mod nonce;
+pub mod obfuscation;
 pub mod symm;
 mod utils;
 
-pub use self::asymm::{RsaPublicKey,
-                      calculate_auth_key, decompose_pq, find_first_key, find_first_key_fail_safe};
-pub use self::symm::{AesParams, AuthKey};
+pub use self::asymm::{PublicKeySet, RsaPublicKey,
+                      all_fingerprints, calculate_auth_key, check_dh_params, check_pq_factors, decompose_pq,
+                      find_first_key, find_first_key_fail_safe, key_for_fingerprint, pq_from_bytes};
+pub use self::dh::{DhGenOutcome, check_dh_gen_answer, check_nonces, check_server_dh_params,
+                   decrypt_server_dh_inner_data, encrypt_client_dh_inner_data, initial_server_salt};
+pub use self::nonce::{Nonce128, Nonce256};
+pub use self::symm::{AesParams, AuthKey, ProtocolVersion, Sender};
 
 
 const AUTH_KEY_SIZE: usize = 256;
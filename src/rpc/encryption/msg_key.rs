@@ -0,0 +1,80 @@
+//! Computation and verification of `msg_key`, which both authenticates
+//! an encrypted message and defines the AES parameters it is encrypted
+//! with.
+//!
+//! More information: https://core.telegram.org/mtproto/description#defining-aes-key-and-initialization-vector.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{self, ErrorKind};
+use rpc::utils::{sha1_bytes, sha256_bytes};
+
+use super::symm::{AuthKey, Sender};
+
+
+/// Length of `salt + session_id + message_id + seq_no`, which precedes
+/// `message_data_length` in decrypted data.
+const DECRYPTED_DATA_HEADER_LEN: usize = 28;
+
+/// Computes the MTProto 1.0 `msg_key` of `plaintext`, the lower 128
+/// bits of `SHA1(plaintext)`.
+///
+/// `plaintext` must not include padding. Unlike in MTProto 2.0, the
+/// result doesn't depend on the authorization key.
+pub fn compute_v1(plaintext: &[u8]) -> error::Result<[u8; 16]> {
+    let sha1 = sha1_bytes(&[plaintext])?;
+
+    let mut msg_key = [0; 16];
+    msg_key.copy_from_slice(&sha1[4..20]);
+
+    Ok(msg_key)
+}
+
+/// Checks the MTProto 1.0 `msg_key` of a decrypted message against its
+/// `padded` data.
+///
+/// Padding is excluded from the hash using `message_data_length` of the
+/// decrypted header.
+pub fn verify_v1(padded: &[u8], msg_key: &[u8; 16]) -> error::Result<()> {
+    let body_start = DECRYPTED_DATA_HEADER_LEN + 4;
+    if padded.len() < body_start {
+        bail!(ErrorKind::InvalidPadding(0, padded.len()));
+    }
+
+    let message_data_len = LittleEndian::read_u32(&padded[DECRYPTED_DATA_HEADER_LEN..body_start]);
+    if message_data_len as usize > padded.len() - body_start {
+        bail!(ErrorKind::InvalidPadding(message_data_len, padded.len()));
+    }
+
+    if compute_v1(&padded[..body_start + message_data_len as usize])? != *msg_key {
+        bail!(ErrorKind::MessageKeyMismatch);
+    }
+
+    Ok(())
+}
+
+/// Computes the MTProto 2.0 `msg_key` of `padded` data sent by `sender`,
+/// the middle 128 bits of
+/// `msg_key_large = SHA256(substr(auth_key, 88 + x, 32) + padded)`.
+///
+/// `x` is 0 for messages sent by the client and 8 for messages sent by
+/// the server.
+pub fn compute_v2(auth_key: &AuthKey, padded: &[u8], sender: Sender) -> error::Result<[u8; 16]> {
+    let x = sender.auth_key_offset();
+    let msg_key_large = sha256_bytes(&[&auth_key.auth_key[88+x..88+x+32], padded])?;
+
+    let mut msg_key = [0; 16];
+    msg_key.copy_from_slice(&msg_key_large[8..24]);
+
+    Ok(msg_key)
+}
+
+/// Checks the MTProto 2.0 `msg_key` of a decrypted message against its
+/// `padded` data, including padding.
+pub fn verify_v2(auth_key: &AuthKey, padded: &[u8], sender: Sender, msg_key: &[u8; 16]) -> error::Result<()> {
+    if compute_v2(auth_key, padded, sender)? != *msg_key {
+        bail!(ErrorKind::MessageKeyMismatch);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Nonces exchanged during key generation.
+//!
+//! The schema represents `int128` and `int256` with `i128` and
+//! `(i128, i128)`, so these types convert to and from both. They are
+//! compared in constant time, since nonces protect the exchange from
+//! being answered by a third party.
+
+use extprim::i128::i128;
+use openssl::memcmp;
+use rand::{Rand, Rng};
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_mtproto::{self, MtProtoSized};
+
+use error::{self, ErrorKind};
+
+use super::symm::{i128_from_le_bytes, i128_to_le_bytes};
+
+
+/// A 128-bit nonce, such as `nonce` and `server_nonce`.
+#[derive(Clone, Copy, Debug)]
+pub struct Nonce128([u8; 16]);
+
+/// A 256-bit nonce, such as `new_nonce`.
+#[derive(Clone, Copy)]
+pub struct Nonce256([u8; 32]);
+
+impl Nonce128 {
+    /// Construct a nonce from its little-endian bytes.
+    pub fn from_bytes(bytes: [u8; 16]) -> Nonce128 {
+        Nonce128(bytes)
+    }
+
+    /// Return the little-endian bytes of the nonce.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Return the nonce as the schema represents `int128`.
+    pub fn to_i128(&self) -> i128 {
+        i128_from_le_bytes(&self.0)
+    }
+
+    /// Checks that a nonce `found` in a reply is this nonce, failing
+    /// with `NonceMismatch` otherwise.
+    pub fn expect_match(&self, found: &Nonce128) -> error::Result<()> {
+        if self != found {
+            bail!(ErrorKind::NonceMismatch(self.to_i128(), found.to_i128()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Nonce256 {
+    /// Construct a nonce from its little-endian bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Nonce256 {
+        Nonce256(bytes)
+    }
+
+    /// Return the little-endian bytes of the nonce.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Return the nonce as the schema represents `int256`.
+    pub fn to_i128_pair(&self) -> (i128, i128) {
+        (i128_from_le_bytes(&self.0[0..16]), i128_from_le_bytes(&self.0[16..32]))
+    }
+}
+
+impl From<i128> for Nonce128 {
+    fn from(num: i128) -> Nonce128 {
+        Nonce128(i128_to_le_bytes(num))
+    }
+}
+
+impl From<(i128, i128)> for Nonce256 {
+    fn from(nums: (i128, i128)) -> Nonce256 {
+        let mut bytes = [0; 32];
+        bytes[0..16].copy_from_slice(&i128_to_le_bytes(nums.0));
+        bytes[16..32].copy_from_slice(&i128_to_le_bytes(nums.1));
+
+        Nonce256(bytes)
+    }
+}
+
+impl PartialEq for Nonce128 {
+    fn eq(&self, other: &Nonce128) -> bool {
+        memcmp::eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Nonce128 {}
+
+impl PartialEq for Nonce256 {
+    fn eq(&self, other: &Nonce256) -> bool {
+        memcmp::eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Nonce256 {}
+
+// `[u8; 32]` doesn't implement `Debug`
+impl ::std::fmt::Debug for Nonce256 {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_tuple("Nonce256")
+            .field(&&self.0[0..16])
+            .field(&&self.0[16..32])
+            .finish()
+    }
+}
+
+impl Rand for Nonce128 {
+    fn rand<R: Rng>(rng: &mut R) -> Nonce128 {
+        let mut bytes = [0; 16];
+        rng.fill_bytes(&mut bytes);
+
+        Nonce128(bytes)
+    }
+}
+
+impl Rand for Nonce256 {
+    fn rand<R: Rng>(rng: &mut R) -> Nonce256 {
+        let mut bytes = [0; 32];
+        rng.fill_bytes(&mut bytes);
+
+        Nonce256(bytes)
+    }
+}
+
+impl Serialize for Nonce128 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        self.to_i128().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Nonce128 {
+    fn deserialize<D>(deserializer: D) -> Result<Nonce128, D::Error>
+        where D: Deserializer<'de>
+    {
+        i128::deserialize(deserializer).map(Nonce128::from)
+    }
+}
+
+impl MtProtoSized for Nonce128 {
+    fn size_hint(&self) -> serde_mtproto::Result<usize> {
+        Ok(16)
+    }
+}
+
+impl Serialize for Nonce256 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        self.to_i128_pair().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Nonce256 {
+    fn deserialize<D>(deserializer: D) -> Result<Nonce256, D::Error>
+        where D: Deserializer<'de>
+    {
+        <(i128, i128)>::deserialize(deserializer).map(Nonce256::from)
+    }
+}
+
+impl MtProtoSized for Nonce256 {
+    fn size_hint(&self) -> serde_mtproto::Result<usize> {
+        Ok(32)
+    }
+}
@@ -0,0 +1,123 @@
+//! Obfuscation of TCP transports against deep packet inspection.
+//!
+//! More information:
+//! https://core.telegram.org/mtproto/mtproto-transports#transport-obfuscation.
+
+use std::fmt;
+
+use openssl::symm;
+use rand::{self, Rng};
+
+use error;
+use rpc::utils::sha256_bytes;
+
+
+/// Protocol tag of the abridged TCP mode.
+pub const ABRIDGED_TAG: [u8; 4] = [0xef, 0xef, 0xef, 0xef];
+/// Protocol tag of the intermediate TCP mode.
+pub const INTERMEDIATE_TAG: [u8; 4] = [0xee, 0xee, 0xee, 0xee];
+
+/// Values the first 4 bytes of the init frame must not be equal to, so
+/// that it won't be confused with other protocols.
+const FORBIDDEN_FIRST_WORDS: &'static [&'static [u8; 4]] = &[
+    b"HEAD", b"POST", b"GET ", b"OPTI",
+    b"\xee\xee\xee\xee", b"\xdd\xdd\xdd\xdd", b"\x16\x03\x01\x02",
+];
+
+
+/// AES-256-CTR ciphers for both directions of an obfuscated
+/// connection.
+///
+/// The state of each cipher advances with each call, so all traffic
+/// must go through the same instance in order.
+pub struct ObfuscatedCipher {
+    encryptor: symm::Crypter,
+    decryptor: symm::Crypter,
+}
+
+impl fmt::Debug for ObfuscatedCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ObfuscatedCipher [AES-256-CTR state]")
+    }
+}
+
+impl ObfuscatedCipher {
+    /// Encrypts outgoing bytes.
+    pub fn encrypt(&mut self, data: &[u8]) -> error::Result<Vec<u8>> {
+        apply_crypter(&mut self.encryptor, data)
+    }
+
+    /// Decrypts incoming bytes.
+    pub fn decrypt(&mut self, data: &[u8]) -> error::Result<Vec<u8>> {
+        apply_crypter(&mut self.decryptor, data)
+    }
+}
+
+
+/// Generates a 64-byte init frame which must be sent before any other
+/// data and the ciphers derived from it.
+///
+/// `secret` is an optional MTProxy secret which is mixed into the keys.
+pub fn generate_init_frame(protocol_tag: [u8; 4], secret: Option<&[u8; 16]>)
+    -> error::Result<([u8; 64], ObfuscatedCipher)>
+{
+    let mut rng = rand::thread_rng();
+    let mut init = [0; 64];
+
+    loop {
+        rng.fill_bytes(&mut init);
+
+        if is_valid_init_frame(&init) {
+            break;
+        }
+    }
+
+    init[56..60].copy_from_slice(&protocol_tag);
+
+    let mut reversed = [0; 48];
+    reversed.copy_from_slice(&init[8..56]);
+    reversed.reverse();
+
+    let encrypt_key = derive_key(&init[8..40], secret)?;
+    let decrypt_key = derive_key(&reversed[0..32], secret)?;
+
+    let cipher = symm::Cipher::aes_256_ctr();
+    let mut encryptor = symm::Crypter::new(cipher, symm::Mode::Encrypt, &encrypt_key, Some(&init[40..56]))?;
+    let decryptor = symm::Crypter::new(cipher, symm::Mode::Decrypt, &decrypt_key, Some(&reversed[32..48]))?;
+
+    // Only the protocol tag and the 4 bytes after it are sent encrypted,
+    // but the encryptor must process the whole frame to be in sync with the server
+    let encrypted_init = apply_crypter(&mut encryptor, &init)?;
+    init[56..64].copy_from_slice(&encrypted_init[56..64]);
+
+    let obfuscated_cipher = ObfuscatedCipher {
+        encryptor: encryptor,
+        decryptor: decryptor,
+    };
+
+    Ok((init, obfuscated_cipher))
+}
+
+/// Checks whether the beginning of an init frame can't be mistaken for
+/// another protocol.
+pub fn is_valid_init_frame(init: &[u8]) -> bool {
+    init.len() >= 8
+        && init[0] != 0xef
+        && !FORBIDDEN_FIRST_WORDS.iter().any(|word| &init[0..4] == &word[..])
+        && &init[4..8] != &[0; 4][..]
+}
+
+fn derive_key(key: &[u8], secret: Option<&[u8; 16]>) -> error::Result<Vec<u8>> {
+    match secret {
+        Some(secret) => sha256_bytes(&[key, &secret[..]]),
+        None => Ok(key.to_vec()),
+    }
+}
+
+fn apply_crypter(crypter: &mut symm::Crypter, input: &[u8]) -> error::Result<Vec<u8>> {
+    let mut output = vec![0; input.len() + symm::Cipher::aes_256_ctr().block_size()];
+    let len = crypter.update(input, &mut output)?;
+    output.truncate(len);
+
+    Ok(output)
+}
@@ -7,10 +7,11 @@ use extprim::i128::i128;
 use openssl::{aes, symm};
 
 use error::{self, ErrorKind};
-use rpc::utils::sha1_bytes;
+use rpc::utils::{sha1_bytes, sha256_bytes};
 
 use super::AUTH_KEY_SIZE;
-use super::utils::{Padding, sha1_and_or_pad, set_slice_parts};
+use super::msg_key;
+use super::utils::{Padding, sha1_and_or_pad, set_slice_parts, zero_bytes};
 
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -25,6 +26,12 @@ impl AesParams {
         self.run_ige(&input, symm::Mode::Encrypt)
     }
 
+    /// Prepend SHA1 of `decrypted`, pad it with random bytes and encrypt.
+    pub fn ige_encrypt_random_padded(self, decrypted: &[u8]) -> error::Result<Vec<u8>> {
+        let input = sha1_and_or_pad(decrypted, true, Padding::Mod16Random)?;
+        self.run_ige(&input, symm::Mode::Encrypt)
+    }
+
     pub fn ige_decrypt(self, encrypted: &[u8]) -> error::Result<Vec<u8>> {
         self.run_ige(encrypted, symm::Mode::Decrypt)
     }
@@ -47,31 +54,92 @@ impl AesParams {
         Ok(output)
     }
 
-    // TODO: uncomment after getting rpc functions working
-    /*pub fn from_pq_inner_data(data: &P_Q_inner_data) -> Result<AesParams> {
-        let new_nonce = data.new_nonce();
-        let server_nonce = data.server_nonce();
+    /// Construct AES parameters from a raw key and initialization vector.
+    pub fn new(key: [u8; 32], iv: [u8; 32]) -> AesParams {
+        AesParams { key: key, iv: iv }
+    }
 
-        let sha1_a = sha1_nonces(&[new_nonce.0, new_nonce.1, server_nonce])?;
-        let sha1_b = sha1_nonces(&[server_nonce, new_nonce.0, new_nonce.1])?;
-        let sha1_c = sha1_nonces(&[new_nonce.0, new_nonce.1, new_nonce.0, new_nonce.1])?;
+    /// Derive the temporary AES key and IV used to encrypt inner data
+    /// during the Diffie-Hellman exchange.
+    pub fn from_nonces(server_nonce: i128, new_nonce: (i128, i128)) -> error::Result<AesParams> {
+        let server_nonce = i128_to_le_bytes(server_nonce);
+        let new_nonce_lo = i128_to_le_bytes(new_nonce.0);
+        let new_nonce_hi = i128_to_le_bytes(new_nonce.1);
 
-        let mut tmp = [0u8; 8];
-        LittleEndian::write_i64(&mut tmp, (new_nonce.0).0);
+        let sha1_a = sha1_bytes(&[&new_nonce_lo, &new_nonce_hi, &server_nonce])?;
+        let sha1_b = sha1_bytes(&[&server_nonce, &new_nonce_lo, &new_nonce_hi])?;
+        let sha1_c = sha1_bytes(&[&new_nonce_lo, &new_nonce_hi, &new_nonce_lo, &new_nonce_hi])?;
 
         let mut ret: AesParams = Default::default();
         set_slice_parts(&mut ret.key, &[&sha1_a, &sha1_b[..12]]);
-        set_slice_parts(&mut ret.iv, &[&sha1_b[12..], &sha1_c, &tmp[..4]]);
+        set_slice_parts(&mut ret.iv, &[&sha1_b[12..], &sha1_c, &new_nonce_lo[..4]]);
 
         Ok(ret)
-    }*/
+    }
+}
+
+pub(super) fn i128_to_le_bytes(num: i128) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    LittleEndian::write_u64(&mut bytes[0..8], num.low64());
+    LittleEndian::write_i64(&mut bytes[8..16], num.high64());
+
+    bytes
+}
+
+
+/// Version of MTProto which determines how message keys and AES
+/// parameters are derived.
+///
+/// More information: https://core.telegram.org/mtproto/description.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProtocolVersion {
+    /// MTProto 1.0, based on SHA1. Deprecated by Telegram.
+    V1,
+    /// MTProto 2.0, based on SHA256.
+    V2,
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> ProtocolVersion {
+        ProtocolVersion::V2
+    }
+}
+
+impl ProtocolVersion {
+    /// Length of `len` bytes of decrypted data after padding.
+    pub fn padded_len(&self, len: usize) -> usize {
+        match *self {
+            ProtocolVersion::V1 => len + (16 - len % 16) % 16,
+            // At least 12 bytes of padding are required
+            ProtocolVersion::V2 => len + 12 + (16 - (len + 12) % 16) % 16,
+        }
+    }
+}
+
+/// Party which sends an encrypted message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sender {
+    Client,
+    Server,
+}
+
+impl Sender {
+    /// Offset `x` into the authorization key used to derive message keys
+    /// and AES parameters.
+    pub(super) fn auth_key_offset(&self) -> usize {
+        match *self {
+            Sender::Client => 0,
+            Sender::Server => 8,
+        }
+    }
 }
 
 
-// FIXME: sensitive data! implement zeroing on drop!
 /// Holds data obtained after a successful authorization.
+///
+/// The raw key is overwritten with zeros on drop.
 pub struct AuthKey {
-    auth_key: [u8; AUTH_KEY_SIZE],
+    pub(super) auth_key: [u8; AUTH_KEY_SIZE],
     aux_hash: i64,
     fingerprint: i64,
 }
@@ -90,6 +158,12 @@ impl Clone for AuthKey {
     }
 }
 
+impl Drop for AuthKey {
+    fn drop(&mut self) {
+        zero_bytes(&mut self.auth_key);
+    }
+}
+
 impl fmt::Debug for AuthKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("AuthKey")
@@ -139,74 +213,147 @@ impl AuthKey {
         let aux_hash = LittleEndian::read_i64(&sha1[0..8]);
         let fingerprint = LittleEndian::read_i64(&sha1[12..20]);
 
-        Ok(AuthKey {
+        let auth_key = AuthKey {
             auth_key: key,
             aux_hash: aux_hash,
             fingerprint: fingerprint,
-        })
+        };
+        // `key` was copied, so wipe the original
+        zero_bytes(&mut key);
+
+        Ok(auth_key)
+    }
+
+    /// ID of the key which is sent as `auth_key_id` in encrypted
+    /// messages.
+    pub fn key_id(&self) -> i64 {
+        self.fingerprint
+    }
+
+    /// Auxiliary hash of the key, used as `retry_id` and in
+    /// `new_nonce_hash{1,2,3}` of `Set_client_DH_params_answer`.
+    pub fn aux_hash(&self) -> i64 {
+        self.aux_hash
     }
 
-    /// Encrypts an arbitrary sequence of bytes using the internally
-    /// stored key.
+    /// Encrypts an arbitrary sequence of bytes sent by the client using
+    /// MTProto 1.0.
     ///
     /// Returns an authorization key ID, message key and encrypted data,
     /// respectively.
     pub fn encrypt_message_bytes(&self, message_bytes: &[u8]) -> error::Result<(i64, i128, Vec<u8>)> {
-        let auth_key_id = self.fingerprint;
-
-        let message_hash = sha1_bytes(&[message_bytes])?;
-        let mut message_key_bytes = [0; 16];
-        message_key_bytes.copy_from_slice(&message_hash[4..20]);
-
-        let message_key_lo = LittleEndian::read_u64(&message_key_bytes[0..8]);
-        let message_key_hi = LittleEndian::read_i64(&message_key_bytes[8..16]);
-        let message_key = i128::from_parts(message_key_hi, message_key_lo);
-
-        let aes = self.generate_message_aes_params(message_key, symm::Mode::Encrypt)?;
-        let encrypted_data = aes.ige_encrypt(message_bytes, false)?;
-
-        Ok((auth_key_id, message_key, encrypted_data))
+        self.encrypt_message_bytes_as(ProtocolVersion::V1, Sender::Client, message_bytes)
     }
 
-    /// Decrypts a sequence of bytes and returns decrypted raw data.
+    /// Decrypts a sequence of bytes sent by the server using MTProto 1.0
+    /// and returns decrypted raw data.
     pub fn decrypt_message_bytes(&self,
                                  auth_key_id: i64,
                                  message_key: i128,
                                  message_bytes: &[u8])
                                 -> error::Result<Vec<u8>> {
-        if auth_key_id != self.fingerprint {
-            bail!(ErrorKind::WrongFingerprint(self.fingerprint, auth_key_id));
-        }
-
-        let aes = self.generate_message_aes_params(message_key, symm::Mode::Decrypt)?;
-        aes.ige_decrypt(message_bytes)
+        self.decrypt_message_bytes_as(ProtocolVersion::V1, Sender::Server, auth_key_id, message_key, message_bytes)
     }
 
-    fn generate_message_aes_params(&self, msg_key: i128, mode: symm::Mode) -> error::Result<AesParams> {
-        let mut msg_key_bytes = [0; 16];
-        LittleEndian::write_u64(&mut msg_key_bytes[0..8], msg_key.low64());
-        LittleEndian::write_i64(&mut msg_key_bytes[8..16], msg_key.high64());
+    /// Encrypts an arbitrary sequence of bytes sent by `sender` using the
+    /// internally stored key and the scheme of `version`.
+    ///
+    /// Returns an authorization key ID, message key and encrypted data,
+    /// respectively.
+    pub fn encrypt_message_bytes_as(&self,
+                                    version: ProtocolVersion,
+                                    sender: Sender,
+                                    message_bytes: &[u8])
+                                   -> error::Result<(i64, i128, Vec<u8>)> {
+        let auth_key_id = self.fingerprint;
 
-        let mut pos = match mode {
-            symm::Mode::Encrypt => 0,
-            symm::Mode::Decrypt => 8,
+        let (message_key, input) = match version {
+            ProtocolVersion::V1 => {
+                let message_key = msg_key::compute_v1(message_bytes)?;
+                (i128_from_le_bytes(&message_key), sha1_and_or_pad(message_bytes, false, Padding::Mod16)?)
+            },
+            ProtocolVersion::V2 => {
+                let padded = sha1_and_or_pad(message_bytes, false, Padding::Mod16Min12Random)?;
+                (i128_from_le_bytes(&msg_key::compute_v2(self, &padded, sender)?), padded)
+            },
         };
 
-        let mut auth_key_take = |len| {
-            let ret = &self.auth_key[pos..pos+len];
-            pos += len;
-            ret
-        };
+        let aes = self.generate_message_aes_params(version, sender, message_key)?;
+        let encrypted_data = aes.ige_encrypt(&input, false)?;
+
+        Ok((auth_key_id, message_key, encrypted_data))
+    }
+
+    /// Decrypts a sequence of bytes sent by `sender` using the scheme of
+    /// `version` and returns decrypted raw data.
+    ///
+    /// The message key is verified against decrypted data, so tampered
+    /// messages are rejected with `MessageKeyMismatch`.
+    pub fn decrypt_message_bytes_as(&self,
+                                    version: ProtocolVersion,
+                                    sender: Sender,
+                                    auth_key_id: i64,
+                                    message_key: i128,
+                                    message_bytes: &[u8])
+                                   -> error::Result<Vec<u8>> {
+        if auth_key_id != self.fingerprint {
+            bail!(ErrorKind::WrongFingerprint(self.fingerprint, auth_key_id));
+        }
+
+        let aes = self.generate_message_aes_params(version, sender, message_key)?;
+        let decrypted = aes.ige_decrypt(message_bytes)?;
 
-        let sha1_a = sha1_bytes(&[&msg_key_bytes, auth_key_take(32)])?;
-        let sha1_b = sha1_bytes(&[auth_key_take(16), &msg_key_bytes, auth_key_take(16)])?;
-        let sha1_c = sha1_bytes(&[auth_key_take(32), &msg_key_bytes])?;
-        let sha1_d = sha1_bytes(&[&msg_key_bytes, auth_key_take(32)])?;
+        let msg_key_bytes = i128_to_le_bytes(message_key);
+        match version {
+            ProtocolVersion::V1 => msg_key::verify_v1(&decrypted, &msg_key_bytes)?,
+            ProtocolVersion::V2 => msg_key::verify_v2(self, &decrypted, sender, &msg_key_bytes)?,
+        }
+
+        Ok(decrypted)
+    }
 
+    fn generate_message_aes_params(&self,
+                                   version: ProtocolVersion,
+                                   sender: Sender,
+                                   msg_key: i128)
+                                  -> error::Result<AesParams> {
+        let msg_key_bytes = i128_to_le_bytes(msg_key);
+        let x = sender.auth_key_offset();
         let mut ret: AesParams = Default::default();
-        set_slice_parts(&mut ret.key, &[&sha1_a[0..8], &sha1_b[8..20], &sha1_c[4..16]]);
-        set_slice_parts(&mut ret.iv, &[&sha1_a[8..20], &sha1_b[0..8], &sha1_c[16..20], &sha1_d[0..8]]);
+
+        match version {
+            ProtocolVersion::V1 => {
+                let mut pos = x;
+                let mut auth_key_take = |len| {
+                    let ret = &self.auth_key[pos..pos+len];
+                    pos += len;
+                    ret
+                };
+
+                let sha1_a = sha1_bytes(&[&msg_key_bytes, auth_key_take(32)])?;
+                let sha1_b = sha1_bytes(&[auth_key_take(16), &msg_key_bytes, auth_key_take(16)])?;
+                let sha1_c = sha1_bytes(&[auth_key_take(32), &msg_key_bytes])?;
+                let sha1_d = sha1_bytes(&[&msg_key_bytes, auth_key_take(32)])?;
+
+                set_slice_parts(&mut ret.key, &[&sha1_a[0..8], &sha1_b[8..20], &sha1_c[4..16]]);
+                set_slice_parts(&mut ret.iv, &[&sha1_a[8..20], &sha1_b[0..8], &sha1_c[16..20], &sha1_d[0..8]]);
+            },
+            ProtocolVersion::V2 => {
+                let sha256_a = sha256_bytes(&[&msg_key_bytes, &self.auth_key[x..x+36]])?;
+                let sha256_b = sha256_bytes(&[&self.auth_key[40+x..40+x+36], &msg_key_bytes])?;
+
+                set_slice_parts(&mut ret.key, &[&sha256_a[0..8], &sha256_b[8..24], &sha256_a[24..32]]);
+                set_slice_parts(&mut ret.iv, &[&sha256_b[0..8], &sha256_a[8..24], &sha256_b[24..32]]);
+            },
+        }
 
         Ok(ret)
     }
 }
+
+pub(super) fn i128_from_le_bytes(bytes: &[u8]) -> i128 {
+    let lo = LittleEndian::read_u64(&bytes[0..8]);
+    let hi = LittleEndian::read_i64(&bytes[8..16]);
+
+    i128::from_parts(hi, lo)
+}
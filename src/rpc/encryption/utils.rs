@@ -1,4 +1,5 @@
 use std::io::{Cursor, Write};
+use std::ptr;
 
 use error::{self, ErrorKind};
 use rand::{self, Rng};
@@ -8,6 +9,8 @@ use rpc::utils::sha1_bytes;
 pub(super) enum Padding {
     Total255Random,
     Mod16,
+    Mod16Random,
+    Mod16Min12Random,
 }
 
 pub(super) fn sha1_and_or_pad(input: &[u8], prepend_sha1: bool, padding: Padding) -> error::Result<Vec<u8>> {
@@ -36,6 +39,22 @@ pub(super) fn sha1_and_or_pad(input: &[u8], prepend_sha1: bool, padding: Padding
             let new_len = old_len + (16 - (old_len % 16)) % 16; // == ceil_div(old_len, 16) * 16
             result.resize(new_len, 0);
         },
+        Padding::Mod16Random => {
+            let old_len = result.len();
+            let new_len = old_len + (16 - (old_len % 16)) % 16;
+            result.resize(new_len, 0);
+
+            let mut rng = rand::thread_rng();
+            rng.fill_bytes(&mut result[old_len..]);
+        },
+        Padding::Mod16Min12Random => {
+            let old_len = result.len();
+            let new_len = old_len + 12 + (16 - ((old_len + 12) % 16)) % 16;
+            result.resize(new_len, 0);
+
+            let mut rng = rand::thread_rng();
+            rng.fill_bytes(&mut result[old_len..]);
+        },
     }
 
     Ok(result)
@@ -51,3 +70,11 @@ pub(super) fn set_slice_parts(result: &mut [u8], parts: &[&[u8]]) {
         cursor.write(part).unwrap();
     }
 }
+
+/// Overwrites `bytes` with zeros in a way which is not optimized out.
+pub(super) fn zero_bytes(bytes: &mut [u8]) {
+    for byte in bytes {
+        // Safe because `byte` is a valid mutable reference
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
@@ -0,0 +1,115 @@
+//! Wrappers which make the server process a query only after other
+//! messages were processed or using a specific schema layer.
+//!
+//! More information: https://core.telegram.org/api/invoking.
+
+use serde_mtproto::{Boxed, Identifiable};
+
+use schema::LAYER;
+
+use super::RpcFunction;
+
+
+/// Constructor id of `invokeAfterMsg`.
+pub const INVOKE_AFTER_MSG_ID: u32 = 0xcb9f372d;
+/// Constructor id of `invokeAfterMsgs`.
+pub const INVOKE_AFTER_MSGS_ID: u32 = 0x3dc4b4f0;
+/// Constructor id of `invokeWithLayer`.
+pub const INVOKE_WITH_LAYER_ID: u32 = 0xda9b0d0d;
+
+/// A statically-typed `invokeAfterMsg` which wraps an RPC function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, MtProtoSized)]
+pub struct InvokeAfterMsg<T> {
+    /// The message ID the query must be processed after.
+    pub msg_id: i64,
+    /// The query itself.
+    pub query: Boxed<T>,
+}
+
+impl<T> Identifiable for InvokeAfterMsg<T> {
+    fn type_id(&self) -> u32 {
+        INVOKE_AFTER_MSG_ID
+    }
+
+    fn enum_variant_id(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl<T: RpcFunction> RpcFunction for InvokeAfterMsg<T> {
+    type Reply = T::Reply;
+}
+
+/// A statically-typed `invokeAfterMsgs` which wraps an RPC function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, MtProtoSized)]
+pub struct InvokeAfterMsgs<T> {
+    /// The message IDs the query must be processed after.
+    pub msg_ids: Boxed<Vec<i64>>,
+    /// The query itself.
+    pub query: Boxed<T>,
+}
+
+impl<T> Identifiable for InvokeAfterMsgs<T> {
+    fn type_id(&self) -> u32 {
+        INVOKE_AFTER_MSGS_ID
+    }
+
+    fn enum_variant_id(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl<T: RpcFunction> RpcFunction for InvokeAfterMsgs<T> {
+    type Reply = T::Reply;
+}
+
+/// A statically-typed `invokeWithLayer` which wraps an RPC function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, MtProtoSized)]
+pub struct InvokeWithLayer<T> {
+    /// The schema layer the query is serialized with.
+    pub layer: i32,
+    /// The query itself.
+    pub query: Boxed<T>,
+}
+
+impl<T> Identifiable for InvokeWithLayer<T> {
+    fn type_id(&self) -> u32 {
+        INVOKE_WITH_LAYER_ID
+    }
+
+    fn enum_variant_id(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl<T: RpcFunction> RpcFunction for InvokeWithLayer<T> {
+    type Reply = T::Reply;
+}
+
+
+/// Wrap `query` so that the server processes it only after the message
+/// with `prev_msg_id`.
+pub fn invoke_after<T: RpcFunction>(prev_msg_id: i64, query: T) -> InvokeAfterMsg<T> {
+    InvokeAfterMsg {
+        msg_id: prev_msg_id,
+        query: Boxed::new(query),
+    }
+}
+
+/// Wrap `query` so that the server processes it only after all messages
+/// with `prev_msg_ids`.
+pub fn invoke_after_msgs<T: RpcFunction>(prev_msg_ids: Vec<i64>, query: T) -> InvokeAfterMsgs<T> {
+    InvokeAfterMsgs {
+        msg_ids: Boxed::new(prev_msg_ids),
+        query: Boxed::new(query),
+    }
+}
+
+/// Wrap `query` so that the server processes it using the schema layer
+/// this crate was generated from, i.e. `schema::LAYER`.
+pub fn invoke_with_layer<T: RpcFunction>(query: T) -> InvokeWithLayer<T> {
+    InvokeWithLayer {
+        layer: LAYER,
+        query: Boxed::new(query),
+    }
+}
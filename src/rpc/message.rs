@@ -1,16 +1,19 @@
 //! Message-related definitions.
 
+use std::borrow::Cow;
 use std::fmt;
 use std::marker::PhantomData;
 
+use byteorder::{ByteOrder, LittleEndian};
 use extprim::i128::i128;
 use serde::ser::{self, Error as SerError, Serialize};
 use serde::de::{self, DeserializeOwned, DeserializeSeed, Error as DeError, SeqAccess, Visitor};
 use serde_mtproto::{self, Boxed, Identifiable, MtProtoSized, WithSize, UnsizedByteBuf, UnsizedByteBufSeed, size_hint_from_unsized_byte_seq_len};
 
 use error::{self, ErrorKind};
+use tl::dynamic::{inflate, DEFAULT_MAX_INFLATED_LEN};
 
-use super::encryption::AuthKey;
+use super::encryption::{AuthKey, ProtocolVersion, Sender};
 use super::utils::EitherRef;
 
 
@@ -52,6 +55,279 @@ pub struct DecryptedData<T> {
     #[serde(skip)]
     #[mtproto_sized(skip)]
     pub(super) key: AuthKey,
+    #[serde(skip)]
+    #[mtproto_sized(skip)]
+    pub(super) version: ProtocolVersion,
+}
+
+/// Constructor id of `rpc_result`.
+pub const RPC_RESULT_ID: u32 = 0xf35c6d01;
+
+/// A statically-typed `rpc_result` which wraps a reply to an RPC
+/// function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, MtProtoSized)]
+pub struct RpcResult<T> {
+    /// The message ID of the request this is a reply to.
+    pub req_msg_id: i64,
+    /// The reply itself.
+    pub result: Boxed<T>,
+}
+
+impl<T> Identifiable for RpcResult<T> {
+    fn type_id(&self) -> u32 {
+        RPC_RESULT_ID
+    }
+
+    fn enum_variant_id(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Constructor id of `rpc_error`.
+pub const RPC_ERROR_ID: u32 = 0x2144ca19;
+
+/// Constructor id of `gzip_packed`.
+pub const GZIP_PACKED_ID: u32 = 0x3072cfa1;
+
+/// Inflates a serialized boxed `gzip_packed` into the serialized boxed
+/// object it packs.
+///
+/// Any other object is returned as is. Corrupt compressed data fails
+/// with `GzipInflateFailed` and objects inflating to more than
+/// `DEFAULT_MAX_INFLATED_LEN` bytes with `DeserializationLimitExceeded`.
+pub fn unpack_gzip(bytes: &[u8]) -> error::Result<Cow<[u8]>> {
+    if bytes.len() < 4 || LittleEndian::read_u32(&bytes[0..4]) != GZIP_PACKED_ID {
+        return Ok(Cow::Borrowed(bytes));
+    }
+
+    let gzip_packed: Boxed<::schema::manual::GzipPacked> = serde_mtproto::from_bytes(bytes, None)?;
+    let unpacked = inflate(&gzip_packed.inner().packed_data, DEFAULT_MAX_INFLATED_LEN)?;
+
+    Ok(Cow::Owned(unpacked))
+}
+
+/// Error code the server replies with if it doesn't know the
+/// authorization key of a message, e.g. after the key expired.
+pub const AUTH_KEY_UNKNOWN_CODE: i32 = 404;
+
+/// Returns the transport error code of a received packet, if it is
+/// one.
+///
+/// Servers send errors as a lone negative `int` in place of a message.
+pub fn transport_error_code(bytes: &[u8]) -> Option<i32> {
+    if bytes.len() == 4 {
+        Some(-LittleEndian::read_i32(bytes))
+    } else {
+        None
+    }
+}
+
+/// Constructor id of `msg_container`.
+pub const MSG_CONTAINER_ID: u32 = 0x73f1f8dc;
+
+/// Constructor id of `msg_copy`.
+pub const MSG_COPY_ID: u32 = 0xe06046b2;
+
+/// A message received from the server, with its body left serialized
+/// so that it can be dispatched according to its constructor id.
+///
+/// This is used both for top-level messages and for messages nested in
+/// a `msg_container`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainedMessage {
+    pub msg_id: i64,
+    pub seqno: i32,
+    /// Serialized boxed body, constructor id included.
+    pub body: Vec<u8>,
+}
+
+impl ContainedMessage {
+    /// Returns the constructor id of the body, if there is one.
+    pub fn constructor_id(&self) -> Option<u32> {
+        if self.body.len() >= 4 {
+            Some(LittleEndian::read_u32(&self.body[0..4]))
+        } else {
+            None
+        }
+    }
+
+    /// Returns `req_msg_id` of the body if it is an `rpc_result`.
+    pub fn rpc_result_req_msg_id(&self) -> Option<i64> {
+        if self.constructor_id() == Some(RPC_RESULT_ID) && self.body.len() >= 12 {
+            Some(LittleEndian::read_i64(&self.body[4..12]))
+        } else {
+            None
+        }
+    }
+
+    /// Deserializes the result of an `rpc_result` body as a `T`.
+    ///
+    /// The result is inflated first if it is `gzip_packed`.
+    pub fn read_rpc_result<T: DeserializeOwned + Identifiable>(&self) -> error::Result<T> {
+        if self.rpc_result_req_msg_id().is_none() {
+            bail!(ErrorKind::UnknownConstructorId("RpcResult", self.constructor_id().unwrap_or(0)));
+        }
+
+        // Skip the constructor id and req_msg_id
+        let result = unpack_gzip(&self.body[12..])?;
+        ::tl::read_boxed(&result)
+    }
+
+    /// Returns the error of an `rpc_result` body if it wraps an
+    /// `rpc_error` and `None` if it wraps anything else.
+    pub fn read_rpc_error(&self) -> error::Result<Option<::schema::RpcError>> {
+        if self.rpc_result_req_msg_id().is_none() {
+            return Ok(None);
+        }
+
+        let result = unpack_gzip(&self.body[12..])?;
+        if result.len() < 4 || LittleEndian::read_u32(&result[0..4]) != RPC_ERROR_ID {
+            return Ok(None);
+        }
+
+        ::tl::read_boxed(&result).map(Some)
+    }
+
+    /// Whether the message needs to be acknowledged.
+    pub fn is_content_related(&self) -> bool {
+        self.seqno & 1 == 1
+    }
+
+    /// Deserializes the body as a `T`.
+    pub fn deserialize_body<T: DeserializeOwned>(&self) -> error::Result<T> {
+        serde_mtproto::from_bytes(&self.body, None).map_err(Into::into)
+    }
+
+    /// Deserializes the boxed body as a `T`, dispatching on its
+    /// constructor id.
+    pub fn read_boxed_body<T: DeserializeOwned + Identifiable>(&self) -> error::Result<T> {
+        ::tl::read_boxed(&self.body)
+    }
+
+    /// Splits the body into nested messages if it is a `msg_container`.
+    ///
+    /// Otherwise returns the message itself. Messages wrapped in
+    /// `msg_copy` are replaced with the original ones in both cases.
+    pub fn unpack(self) -> error::Result<Vec<ContainedMessage>> {
+        self.unpack_container()?.into_iter().map(ContainedMessage::unwrap_msg_copy).collect()
+    }
+
+    /// Splits the body into nested messages if it is a `msg_container`,
+    /// leaving `msg_copy` wrappers in place.
+    pub fn unpack_container(self) -> error::Result<Vec<ContainedMessage>> {
+        if self.constructor_id() == Some(MSG_CONTAINER_ID) {
+            parse_msg_container(&self.body)
+        } else {
+            Ok(vec![self])
+        }
+    }
+
+    /// Returns the original message if the body is a `msg_copy` and the
+    /// message itself otherwise.
+    pub fn unwrap_msg_copy(self) -> error::Result<ContainedMessage> {
+        if self.constructor_id() == Some(MSG_COPY_ID) {
+            parse_msg_copy(&self.body)
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// Reads a bare `message` at `pos` of `bytes`, returning it with the
+/// position right after it, or `None` if `bytes` are too short.
+fn read_bare_message(bytes: &[u8], pos: usize) -> Option<(ContainedMessage, usize)> {
+    // msg_id, seqno and body length
+    if bytes.len() - pos < 16 {
+        return None;
+    }
+
+    let msg_id = LittleEndian::read_i64(&bytes[pos..pos+8]);
+    let seqno = LittleEndian::read_i32(&bytes[pos+8..pos+12]);
+    let len = LittleEndian::read_u32(&bytes[pos+12..pos+16]) as usize;
+    let pos = pos + 16;
+
+    if bytes.len() - pos < len {
+        return None;
+    }
+
+    let message = ContainedMessage {
+        msg_id: msg_id,
+        seqno: seqno,
+        body: bytes[pos..pos+len].to_vec(),
+    };
+
+    Some((message, pos + len))
+}
+
+/// Parses a serialized boxed `msg_container` into messages it
+/// contains.
+pub fn parse_msg_container(bytes: &[u8]) -> error::Result<Vec<ContainedMessage>> {
+    if bytes.len() < 8 || LittleEndian::read_u32(&bytes[0..4]) != MSG_CONTAINER_ID {
+        bail!(ErrorKind::InvalidMsgContainer(bytes.len()));
+    }
+
+    let count = LittleEndian::read_u32(&bytes[4..8]);
+    let mut messages = Vec::new();
+    let mut pos = 8;
+
+    for _ in 0..count {
+        let (message, next_pos) = read_bare_message(bytes, pos)
+            .ok_or(error::Error::from(ErrorKind::InvalidMsgContainer(bytes.len())))?;
+
+        messages.push(message);
+        pos = next_pos;
+    }
+
+    Ok(messages)
+}
+
+/// Parses a serialized boxed `msg_copy` into the original message it
+/// wraps.
+pub fn parse_msg_copy(bytes: &[u8]) -> error::Result<ContainedMessage> {
+    if bytes.len() < 4 || LittleEndian::read_u32(&bytes[0..4]) != MSG_COPY_ID {
+        bail!(ErrorKind::InvalidMsgCopy(bytes.len()));
+    }
+
+    match read_bare_message(bytes, 4) {
+        Some((message, _)) => Ok(message),
+        None => bail!(ErrorKind::InvalidMsgCopy(bytes.len())),
+    }
+}
+
+/// Decrypts a serialized encrypted message without deserializing its
+/// body.
+///
+/// Messages sent within a session other than `session_id` fail with
+/// `SessionIdMismatch`.
+pub(super) fn decrypt_message(message_bytes: &[u8],
+                              auth_key: &AuthKey,
+                              version: ProtocolVersion,
+                              session_id: i64)
+                             -> error::Result<ContainedMessage> {
+    // auth_key_id and msg_key
+    if message_bytes.len() < 24 {
+        bail!(ErrorKind::NotEnoughFields("Message::Decrypted", 0));
+    }
+
+    let auth_key_id = LittleEndian::read_i64(&message_bytes[0..8]);
+    let msg_key: i128 = serde_mtproto::from_bytes(&message_bytes[8..24], None)?;
+    let decrypted_data = auth_key.decrypt_message_bytes_as(
+        version, Sender::Server, auth_key_id, msg_key, &message_bytes[24..])?;
+    check_message_data_len(&decrypted_data, version)?;
+
+    let found_session_id = LittleEndian::read_i64(&decrypted_data[8..16]);
+    if found_session_id != session_id {
+        bail!(ErrorKind::SessionIdMismatch(session_id, found_session_id));
+    }
+
+    let body_start = DECRYPTED_DATA_HEADER_LEN + 4;
+    let body_len = LittleEndian::read_u32(&decrypted_data[DECRYPTED_DATA_HEADER_LEN..body_start]) as usize;
+
+    Ok(ContainedMessage {
+        msg_id: LittleEndian::read_i64(&decrypted_data[16..24]),
+        seqno: LittleEndian::read_i32(&decrypted_data[24..28]),
+        body: decrypted_data[body_start..body_start + body_len].to_vec(),
+    })
 }
 
 #[derive(Debug, Serialize)]
@@ -84,7 +360,7 @@ impl<T: MtProtoSized> MtProtoSized for Message<T> {
             Message::Decrypted { ref decrypted_data } => {
                 // just a dummy value, not an actual one
                 let msg_key_size = i128::size_hint(&i128::new(0))?;
-                let minimum_encrypted_data_size = decrypted_data.size_hint()?;
+                let minimum_encrypted_data_size = decrypted_data.version.padded_len(decrypted_data.size_hint()?);
                 let actual_encrypted_data_size =
                     size_hint_from_unsized_byte_seq_len(minimum_encrypted_data_size)?;
 
@@ -98,6 +374,40 @@ impl<T: MtProtoSized> MtProtoSized for Message<T> {
 
 
 impl<T: Identifiable + MtProtoSized> Message<T> {
+    /// Returns the ID of this message.
+    pub fn message_id(&self) -> i64 {
+        match *self {
+            Message::PlainText { message_id, .. } => message_id,
+            Message::Decrypted { ref decrypted_data } => decrypted_data.message_id,
+        }
+    }
+
+    /// Returns the server salt of the message if it is encrypted.
+    pub fn salt(&self) -> Option<i64> {
+        match *self {
+            Message::PlainText { .. } => None,
+            Message::Decrypted { ref decrypted_data } => Some(decrypted_data.salt),
+        }
+    }
+
+    /// Returns a reference to the body of the message regardless of
+    /// whether it was plain-text or encrypted.
+    pub fn body(&self) -> &T {
+        match *self {
+            Message::PlainText { ref body, .. } => body.inner().inner(),
+            Message::Decrypted { ref decrypted_data } => decrypted_data.body.inner().inner(),
+        }
+    }
+
+    /// Returns the body of the message regardless of whether it was
+    /// plain-text or encrypted.
+    pub fn into_body(self) -> T {
+        match self {
+            Message::PlainText { body, .. } => body.into_inner().into_inner(),
+            Message::Decrypted { decrypted_data } => decrypted_data.body.into_inner().into_inner(),
+        }
+    }
+
     /// Returns `Some(body)` if the message was plain-text.
     /// Otherwise returns `None`.
     pub fn into_plain_text_body(self) -> Option<T> {
@@ -136,6 +446,20 @@ impl<T: Identifiable + MtProtoSized> Message<T> {
 }
 
 impl<T> Message<T> {
+    /// Serializes the message into `buf`, replacing its contents.
+    ///
+    /// The allocation of `buf` is reused, so a sender can keep a single
+    /// buffer for all messages instead of allocating one per message.
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) -> error::Result<()>
+        where T: fmt::Debug + Serialize + MtProtoSized
+    {
+        buf.clear();
+        buf.reserve(self.size_hint()?);
+        serde_mtproto::to_writer(&mut *buf, self)?;
+
+        Ok(())
+    }
+
     fn to_raw_message<'msg>(&'msg self) -> error::Result<RawMessage<'msg, T>>
         where T: fmt::Debug + Serialize
     {
@@ -152,7 +476,7 @@ impl<T> Message<T> {
                 debug!("Serialized data to be encrypted: {:?}", &decrypted_data_serialized);
 
                 let (auth_key_id, msg_key, encrypted_data) = decrypted_data.key
-                    .encrypt_message_bytes(&decrypted_data_serialized)?;
+                    .encrypt_message_bytes_as(decrypted_data.version, Sender::Client, &decrypted_data_serialized)?;
 
                 RawMessage::Encrypted {
                     auth_key_id: auth_key_id,
@@ -168,7 +492,8 @@ impl<T> Message<T> {
     }
 
     fn from_raw_message<'msg>(raw_message: RawMessage<'msg, T>,
-                              opt_key: Option<AuthKey>)
+                              opt_key: Option<AuthKey>,
+                              version: ProtocolVersion)
                              -> error::Result<Message<T>>
         where T: fmt::Debug + DeserializeOwned
     {
@@ -183,14 +508,16 @@ impl<T> Message<T> {
             },
             RawMessage::Encrypted { auth_key_id, msg_key, encrypted_data } => {
                 let key = opt_key.ok_or(ErrorKind::NoAuthKey)?;
-                let decrypted_data_serialized = key
-                    .decrypt_message_bytes(auth_key_id, msg_key, &encrypted_data.into_inner())?;
+                let decrypted_data_serialized = key.decrypt_message_bytes_as(
+                    version, Sender::Server, auth_key_id, msg_key, &encrypted_data.into_inner())?;
                 debug!("Decrypted data to be deserialized: {:?}", &decrypted_data_serialized);
+                check_message_data_len(&decrypted_data_serialized, version)?;
 
                 let mut decrypted_data: DecryptedData<T> =
                     serde_mtproto::from_reader(decrypted_data_serialized.as_slice(), None)?;
 
                 decrypted_data.key = key;
+                decrypted_data.version = version;
 
                 Message::Decrypted {
                     decrypted_data: decrypted_data,
@@ -204,6 +531,40 @@ impl<T> Message<T> {
     }
 }
 
+/// Length of `salt`, `session_id`, `message_id` and `seq_no` which precede
+/// `message_data_length` in decrypted data.
+const DECRYPTED_DATA_HEADER_LEN: usize = 8 + 8 + 8 + 4;
+
+/// Checks that `message_data_length` of decrypted data fits into it and
+/// leaves as much padding as `version` allows: less than 16 bytes for
+/// MTProto 1.0 and 12 to 1024 bytes for MTProto 2.0.
+fn check_message_data_len(decrypted_data: &[u8], version: ProtocolVersion) -> error::Result<()> {
+    let decrypted_len = decrypted_data.len();
+    if decrypted_len < DECRYPTED_DATA_HEADER_LEN + 4 {
+        bail!(ErrorKind::InvalidPadding(0, decrypted_len));
+    }
+
+    let message_data_len = LittleEndian::read_u32(
+        &decrypted_data[DECRYPTED_DATA_HEADER_LEN..DECRYPTED_DATA_HEADER_LEN + 4]);
+    let max_message_data_len = decrypted_len - DECRYPTED_DATA_HEADER_LEN - 4;
+
+    if message_data_len % 4 != 0 || message_data_len as usize > max_message_data_len {
+        bail!(ErrorKind::InvalidPadding(message_data_len, decrypted_len));
+    }
+
+    let padding_len = max_message_data_len - message_data_len as usize;
+    let valid_padding = match version {
+        ProtocolVersion::V1 => padding_len < 16,
+        ProtocolVersion::V2 => padding_len >= 12 && padding_len <= 1024,
+    };
+
+    if !valid_padding {
+        bail!(ErrorKind::InvalidPadding(message_data_len, decrypted_len));
+    }
+
+    Ok(())
+}
+
 impl<T: fmt::Debug + Serialize> Serialize for Message<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: ser::Serializer
@@ -219,14 +580,19 @@ impl<T: fmt::Debug + Serialize> Serialize for Message<T> {
 #[derive(Debug)]
 pub struct MessageSeed<T> {
     opt_key: Option<AuthKey>,
+    version: ProtocolVersion,
     encrypted_data_len: Option<u32>,
     phantom: PhantomData<T>,
 }
 
 impl<T: DeserializeOwned> MessageSeed<T> {
-    pub fn new(opt_key: Option<AuthKey>, encrypted_data_len: Option<u32>) -> MessageSeed<T> {
+    pub fn new(opt_key: Option<AuthKey>,
+               version: ProtocolVersion,
+               encrypted_data_len: Option<u32>)
+              -> MessageSeed<T> {
         MessageSeed {
             opt_key: opt_key,
+            version: version,
             encrypted_data_len: encrypted_data_len,
             phantom: PhantomData,
         }
@@ -241,6 +607,7 @@ impl<'de, T: fmt::Debug + DeserializeOwned> DeserializeSeed<'de> for MessageSeed
     {
         struct MessageVisitor<T> {
             opt_key: Option<AuthKey>,
+            version: ProtocolVersion,
             encrypted_data_len: Option<u32>,
             phantom: PhantomData<T>,
         }
@@ -293,7 +660,7 @@ impl<'de, T: fmt::Debug + DeserializeOwned> DeserializeSeed<'de> for MessageSeed
                     raw_message
                 };
 
-                let message = Message::from_raw_message(raw_message, self.opt_key)
+                let message = Message::from_raw_message(raw_message, self.opt_key, self.version)
                     .map_err(A::Error::custom)?;
 
                 Ok(message)
@@ -302,6 +669,7 @@ impl<'de, T: fmt::Debug + DeserializeOwned> DeserializeSeed<'de> for MessageSeed
 
         let visitor = MessageVisitor {
             opt_key: self.opt_key,
+            version: self.version,
             encrypted_data_len: self.encrypted_data_len,
             phantom: PhantomData,
         };
@@ -8,6 +8,7 @@ use chrono::{DateTime, TimeZone, Utc};
 use envy;
 use erased_serde::Serialize as ErasedSerialize;
 use serde::Deserialize;
+use serde_json;
 use toml;
 
 use error;
@@ -15,13 +16,24 @@ use schema::FutureSalt;
 use tl::dynamic::TLObject;
 
 
+pub mod clock;
+pub mod config;
+pub mod download;
 pub mod encryption;
+pub mod invoke;
 pub mod message;
+pub mod msg_id;
+pub mod pfs;
+pub mod reliability;
 pub mod session;
+pub mod tcp_common;
+pub mod updates;
+pub mod upload;
 mod utils;
 
-pub use self::message::{Message, MessageType};
-pub use self::session::Session;
+pub use self::clock::{Clock, FixedClock, SystemClock};
+pub use self::message::{ContainedMessage, Message, MessageType, RpcResult};
+pub use self::session::{Session, SessionState};
 
 
 pub trait RpcFunction: ErasedSerialize {
@@ -87,6 +99,24 @@ impl AppInfo {
 
         Ok(app_info)
     }
+
+    /// Read an `AppInfo` from a JSON value.
+    pub fn read_from_json_value(value: serde_json::Value) -> error::Result<AppInfo> {
+        serde_json::from_value(value).map_err(Into::into)
+    }
+
+    /// Read an `AppInfo` from a JSON string.
+    pub fn read_from_json_str(s: &str) -> error::Result<AppInfo> {
+        serde_json::from_str(s).map_err(Into::into)
+    }
+
+    /// Read an `AppInfo` from a JSON file.
+    pub fn read_from_json_file<P: AsRef<Path>>(path: P) -> error::Result<AppInfo> {
+        let file = File::open(path)?;
+        let app_info = serde_json::from_reader(file)?;
+
+        Ok(app_info)
+    }
 }
 
 
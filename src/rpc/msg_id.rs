@@ -0,0 +1,42 @@
+//! Conversions between message IDs and the time they were created at.
+//!
+//! A message ID approximately equals `unixtime * 2^32` with the lower 2
+//! bits carrying the message kind, so its top 32 bits are seconds and
+//! the lower 32 bits are a fraction of a second.
+
+use std::fmt;
+
+use chrono::{DateTime, TimeZone, Timelike, Utc};
+
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+
+/// Encode a point in time as a message ID with the lower 2 bits unset.
+pub fn encode_msg_id(time: DateTime<Utc>) -> i64 {
+    let fraction = ((time.nanosecond() as u64) << 32) / NANOS_PER_SEC;
+
+    (time.timestamp() << 32) | (fraction as i64 & 0x_ffff_fffc)
+}
+
+/// Decode a message ID into the time it was created at and its lower
+/// 32 bits.
+pub fn decode_msg_id(msg_id: i64) -> (DateTime<Utc>, u32) {
+    let low = msg_id as u32; // Truncation is intended
+    let nanos = (low as u64 * NANOS_PER_SEC) >> 32;
+
+    (Utc.timestamp(msg_id >> 32, nanos as u32), low)
+}
+
+
+/// A wrapper to print message IDs along with the time they were
+/// created at.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MsgId(pub i64);
+
+impl fmt::Display for MsgId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (time, _) = decode_msg_id(self.0);
+        write!(f, "{:#018x} ({})", self.0, time.to_rfc3339())
+    }
+}
@@ -0,0 +1,75 @@
+//! Binding of temporary authorization keys to a permanent one for
+//! perfect forward secrecy.
+//!
+//! More information: https://core.telegram.org/api/pfs.
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand::{self, Rng};
+use serde_mtproto::{self, Boxed};
+
+use error;
+use schema::manual::BindAuthKeyInner;
+use schema::rpc::auth::bindTempAuthKey;
+
+use super::encryption::AuthKey;
+use super::message::Message;
+use super::session::Session;
+
+
+/// Create an `auth.bindTempAuthKey` message which binds the key of
+/// `session` to `perm_key` for `expires_in` seconds.
+///
+/// `session` must have the temporary key adopted already and the
+/// message must be sent as is, since its ID is part of the binding.
+pub fn bind_temp_auth_key(session: &mut Session, perm_key: &AuthKey, expires_in: i32)
+    -> error::Result<Message<bindTempAuthKey>>
+{
+    let temp_auth_key_id = session.fresh_auth_key()?.key_id();
+    let temp_session_id = session.session_id();
+    let expires_at = session.server_time().timestamp() as i32 + expires_in; // Fits until 2038
+    let nonce = rand::thread_rng().gen();
+
+    session.create_encrypted_message_with_own_id(|msg_id| {
+        let inner = BindAuthKeyInner {
+            nonce: nonce,
+            temp_auth_key_id: temp_auth_key_id,
+            perm_auth_key_id: perm_key.key_id(),
+            temp_session_id: temp_session_id,
+            expires_at: expires_at,
+        };
+
+        Ok(bindTempAuthKey {
+            perm_auth_key_id: perm_key.key_id(),
+            nonce: nonce,
+            expires_at: expires_at,
+            encrypted_message: encrypt_bind_auth_key_inner(perm_key, msg_id, &inner)?.into(),
+        })
+    })
+}
+
+/// Encrypt `bind_auth_key_inner` with `perm_key` as a MTProto 1.0
+/// message with the same `msg_id` as the `auth.bindTempAuthKey` message
+/// which carries it.
+///
+/// Salt and session ID of the message are random and `seq_no` is 0.
+pub fn encrypt_bind_auth_key_inner(perm_key: &AuthKey, msg_id: i64, inner: &BindAuthKeyInner)
+    -> error::Result<Vec<u8>>
+{
+    let body = serde_mtproto::to_bytes(&Boxed::new(inner.clone()))?;
+
+    let mut rng = rand::thread_rng();
+    // salt, session_id, msg_id, seq_no and message_data_length
+    let mut decrypted_data = vec![0; 8 + 8 + 8 + 4 + 4];
+    rng.fill_bytes(&mut decrypted_data[0..16]);
+    LittleEndian::write_i64(&mut decrypted_data[16..24], msg_id);
+    LittleEndian::write_u32(&mut decrypted_data[28..32], body.len() as u32);
+    decrypted_data.extend(body);
+
+    let (auth_key_id, msg_key, encrypted_data) = perm_key.encrypt_message_bytes(&decrypted_data)?;
+
+    let mut encrypted_message = serde_mtproto::to_bytes(&auth_key_id)?;
+    encrypted_message.extend(serde_mtproto::to_bytes(&msg_key)?);
+    encrypted_message.extend(encrypted_data);
+
+    Ok(encrypted_message)
+}
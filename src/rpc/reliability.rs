@@ -0,0 +1,83 @@
+//! Delivery status of sent messages as reported by `msgs_state_info`
+//! and tracking of messages which may need to be resent.
+//!
+//! More information: https://core.telegram.org/mtproto/service_messages_about_messages.
+
+use chrono::{DateTime, Utc};
+
+use error::{self, ErrorKind};
+use schema::MsgsStateInfo;
+use tl::TLObject;
+
+
+/// A content message which was sent, but whose delivery wasn't
+/// confirmed yet.
+#[derive(Clone, Debug)]
+pub struct InFlightMessage {
+    /// ID the message was last sent with.
+    pub msg_id: i64,
+    /// Body to send again if the message is not confirmed in time.
+    pub body: Box<TLObject>,
+    /// When the message was last sent.
+    pub sent_at: DateTime<Utc>,
+}
+
+
+/// Status of a single message as reported by the other party.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MsgState(u8);
+
+impl MsgState {
+    /// Construct a `MsgState` from a status byte of `msgs_state_info`.
+    pub fn new(byte: u8) -> MsgState {
+        MsgState(byte)
+    }
+
+    /// Return the raw status byte.
+    pub fn as_byte(&self) -> u8 {
+        self.0
+    }
+
+    /// Nothing is known about the message.
+    pub fn is_unknown(&self) -> bool {
+        self.0 & 0x07 == 1
+    }
+
+    /// The message was not received, either with its id falling within
+    /// the stored range or being too low.
+    pub fn is_not_received(&self) -> bool {
+        let status = self.0 & 0x07;
+        status == 2 || status == 3
+    }
+
+    /// The message was received.
+    pub fn is_received(&self) -> bool {
+        self.0 & 0x07 == 4
+    }
+
+    /// An acknowledgment of the message was already received.
+    pub fn is_ack_received(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    /// Whether the message must be sent again.
+    pub fn needs_resend(&self) -> bool {
+        self.is_not_received()
+    }
+}
+
+/// Pair each message id of a `msgs_state_req` with its state from the
+/// matching `msgs_state_info`.
+pub fn parse_msgs_state_info(msg_ids: &[i64], msgs_state_info: &MsgsStateInfo) -> error::Result<Vec<(i64, MsgState)>> {
+    let info = &msgs_state_info.info;
+    if info.len() != msg_ids.len() {
+        bail!(ErrorKind::MsgsStateInfoLengthMismatch(msg_ids.len(), info.len()));
+    }
+
+    let states = msg_ids.iter()
+        .cloned()
+        .zip(info.iter().cloned().map(MsgState::new))
+        .collect();
+
+    Ok(states)
+}
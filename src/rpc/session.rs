@@ -1,29 +1,29 @@
 //! MTProto session.
 
+use std::cell::Cell;
 use std::cmp;
+use std::collections::VecDeque;
 use std::fmt;
 use std::mem;
 
-use chrono::{Timelike, Utc};
+use chrono::{DateTime, Duration, Utc};
+use rand::{self, Rng};
 use serde::de::{DeserializeSeed, DeserializeOwned};
 use serde_mtproto::{Boxed, Identifiable, MtProtoSized, WithSize};
 
 use error::{self, ErrorKind};
 use manual_types::Object;
+use schema::FutureSalt;
 use tl::TLObject;
 
-use super::{AppInfo, Salt};
-use super::encryption::AuthKey;
-use super::message::{DecryptedData, Message, MessageSeed};
+use super::{AppInfo, RpcFunction, Salt};
+use super::clock::{Clock, SystemClock};
+use super::encryption::{AuthKey, ProtocolVersion};
+use super::message::{self, ContainedMessage, DecryptedData, Message, MessageSeed, RpcResult};
+use super::{msg_id, reliability, updates};
+use super::reliability::InFlightMessage;
 
 
-fn next_message_id() -> i64 {
-    let time = Utc::now();
-    let timestamp = time.timestamp();
-    let nano = time.nanosecond() as i64; // from u32
-
-    ((timestamp << 32) | (nano & 0x_ffff_fffc))
-}
 
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -32,6 +32,46 @@ enum MessagePurpose {
     NonContent,
 }
 
+/// Constructor id of `new_session_created`.
+const NEW_SESSION_CREATED_ID: u32 = 0x9ec20908;
+/// Constructor id of `msgs_ack`.
+const MSGS_ACK_ID: u32 = 0x62d6b459;
+
+/// How old a message received from the server may be.
+const MAX_SERVER_MSG_AGE_SECS: i64 = 300;
+/// How far ahead of the server time a message received from the server
+/// may be.
+const MAX_SERVER_MSG_LEAD_SECS: i64 = 30;
+
+/// How many IDs of received messages are remembered by default to
+/// detect duplicates.
+pub const DEFAULT_MAX_RECENT_MSG_IDS: usize = 1024;
+
+/// How much longer than the keepalive interval the server waits for the
+/// next ping before closing the connection.
+const KEEPALIVE_DISCONNECT_MARGIN_SECS: i64 = 15;
+
+/// Error code of `rpc_error` if a request was sent too often.
+const FLOOD_CODE: i32 = 420;
+const FLOOD_WAIT_PREFIX: &str = "FLOOD_WAIT_";
+
+/// Part of a `Session` which needs to be preserved to resume it later.
+#[derive(Clone, Debug)]
+pub struct SessionState {
+    /// Authorization key, if the session was authorized.
+    pub auth_key: Option<AuthKey>,
+    /// Server salts known so far.
+    pub server_salts: Vec<Salt>,
+    /// Sequence number of the next non-content message.
+    pub seq_no: i32,
+    /// ID of the last message created in the session.
+    pub last_msg_id: i64,
+    /// Difference between server and local time in seconds.
+    pub time_offset: i32,
+    /// MTProto version messages are encrypted with.
+    pub protocol_version: ProtocolVersion,
+}
+
 // We use signed integers here because that's the default integer representation in MTProto;
 // by trying to match representations we can synchronize the range of allowed values
 /// Represents a session attached to the client device and user key ID.
@@ -46,6 +86,20 @@ pub struct Session {
     seq_no: i32,
     auth_key: Option<AuthKey>,
     to_ack: Vec<i64>,
+    sent_msg_ids: Vec<i64>,
+    in_flight: Vec<InFlightMessage>,
+    pending_messages: Vec<ContainedMessage>,
+    recent_msg_ids: VecDeque<i64>,
+    max_recent_msg_ids: usize,
+    last_msg_id: Cell<i64>,
+    time_offset: Cell<i32>,
+    protocol_version: ProtocolVersion,
+    reauth_on_auth_key_error: bool,
+    keepalive_interval: Option<Duration>,
+    keepalive_delay_disconnect: bool,
+    last_sent_at: DateTime<Utc>,
+    max_flood_wait: Option<Duration>,
+    clock: Box<Clock>,
     app_info: AppInfo,
 }
 
@@ -58,10 +112,165 @@ impl Session {
             seq_no: 0,
             auth_key: None,
             to_ack: Vec::new(),
+            sent_msg_ids: Vec::new(),
+            in_flight: Vec::new(),
+            pending_messages: Vec::new(),
+            recent_msg_ids: VecDeque::new(),
+            max_recent_msg_ids: DEFAULT_MAX_RECENT_MSG_IDS,
+            last_msg_id: Cell::new(0),
+            time_offset: Cell::new(0),
+            protocol_version: ProtocolVersion::default(),
+            reauth_on_auth_key_error: false,
+            keepalive_interval: None,
+            keepalive_delay_disconnect: false,
+            last_sent_at: SystemClock.now(),
+            max_flood_wait: None,
+            clock: Box::new(SystemClock),
             app_info: app_info,
         }
     }
 
+    /// Construct a new `Session` with a session ID drawn from `rng`.
+    ///
+    /// A seeded `rng` makes session IDs reproducible, e.g. in tests.
+    pub fn new_with_rng<R: Rng>(rng: &mut R, app_info: AppInfo) -> Session {
+        Session::new(rng.gen(), app_info)
+    }
+
+    /// Resume a `Session` from its previously saved state.
+    pub fn with_state(session_id: i64, app_info: AppInfo, state: SessionState) -> Session {
+        let mut session = Session::new(session_id, app_info);
+        session.server_salts = state.server_salts;
+        session.seq_no = state.seq_no;
+        session.auth_key = state.auth_key;
+        session.time_offset.set(state.time_offset);
+        session.protocol_version = state.protocol_version;
+
+        session.restore_last_msg_id(state.last_msg_id);
+        session
+    }
+
+    /// Return the state needed to resume this session later.
+    pub fn state(&self) -> SessionState {
+        SessionState {
+            auth_key: self.auth_key.clone(),
+            server_salts: self.server_salts.clone(),
+            seq_no: self.seq_no,
+            last_msg_id: self.last_msg_id.get(),
+            time_offset: self.time_offset.get(),
+            protocol_version: self.protocol_version,
+        }
+    }
+
+    /// Construct a new `Session` which encrypts messages using the
+    /// specified MTProto version instead of the default 2.0.
+    pub fn with_protocol_version(session_id: i64, app_info: AppInfo, version: ProtocolVersion) -> Session {
+        let mut session = Session::new(session_id, app_info);
+        session.protocol_version = version;
+
+        session
+    }
+
+    /// Switch to a new session with `session_id`, keeping the
+    /// authorization key and server salts.
+    ///
+    /// Sequence numbers and message ID tracking start over, and pending
+    /// acknowledgments and IDs of messages received in the old session
+    /// are dropped. The server replies to the next message with
+    /// `new_session_created`.
+    ///
+    /// Messages whose delivery wasn't confirmed are kept, so that
+    /// `resend_timed_out()` sends them in the new session, and so are
+    /// received messages not yet retrieved with
+    /// `take_pending_messages()`.
+    pub fn start_new_session(&mut self, session_id: i64) {
+        self.session_id = session_id;
+        self.seq_no = 0;
+        self.last_msg_id.set(0);
+        self.to_ack.clear();
+        self.recent_msg_ids.clear();
+    }
+
+    /// Switch to a new session with a session ID drawn from `rng`, see
+    /// `start_new_session()`.
+    pub fn start_new_session_with_rng<R: Rng>(&mut self, rng: &mut R) {
+        self.start_new_session(rng.gen());
+    }
+
+    /// Return the MTProto version used to encrypt and decrypt messages.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Return the unique ID of this session.
+    pub fn session_id(&self) -> i64 {
+        self.session_id
+    }
+
+    /// Return the difference between server and local time in seconds.
+    pub fn time_offset(&self) -> i32 {
+        self.time_offset.get()
+    }
+
+    /// Set the difference between server and local time in seconds,
+    /// e.g. if the correct time is already known from elsewhere.
+    pub fn set_time_offset(&self, time_offset: i32) {
+        self.time_offset.set(time_offset);
+    }
+
+    /// Return the clock local time is read from.
+    pub fn clock(&self) -> &Clock {
+        &*self.clock
+    }
+
+    /// Read local time from `clock` instead of the system clock, e.g. to
+    /// use time which is already synchronized or to freeze time in tests.
+    ///
+    /// The server time offset is kept, call `set_time_offset()` if it no
+    /// longer applies to the new clock.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.last_sent_at = clock.now();
+        self.clock = Box::new(clock);
+    }
+
+    /// Synchronize with server time using the ID of a message the server
+    /// has just sent.
+    pub fn update_time_offset(&self, server_msg_id: i64) {
+        let (server_time, _) = msg_id::decode_msg_id(server_msg_id);
+        let time_offset = server_time.timestamp() - self.clock.now().timestamp();
+
+        self.time_offset.set(time_offset as i32); // Truncation is fine for any sane clock
+    }
+
+    /// Make sure message IDs created in this session exceed
+    /// `last_msg_id`, e.g. the last ID of a previous session which used
+    /// the same authorization key.
+    ///
+    /// The restored ID never lowers the current one, so message IDs stay
+    /// monotonic.
+    pub fn restore_last_msg_id(&self, last_msg_id: i64) {
+        // Client message IDs must be divisible by 4
+        let last_msg_id = last_msg_id + (4 - last_msg_id.wrapping_rem(4)) % 4;
+
+        if last_msg_id > self.last_msg_id.get() {
+            self.last_msg_id.set(last_msg_id);
+        }
+    }
+
+    pub(super) fn server_time(&self) -> DateTime<Utc> {
+        self.clock.now() + Duration::seconds(self.time_offset.get() as i64)
+    }
+
+    fn next_message_id(&self) -> i64 {
+        let message_id = msg_id::encode_msg_id(self.server_time());
+        // Message IDs must increase monotonically even if the clock goes backwards
+        let last_msg_id = self.last_msg_id.get();
+        let message_id = if message_id > last_msg_id { message_id } else { last_msg_id + 4 };
+        self.last_msg_id.set(message_id);
+
+        message_id
+    }
+
     fn next_seq_no(&mut self, purpose: MessagePurpose) -> i32 {
         match purpose {
             MessagePurpose::Content => {
@@ -83,16 +292,23 @@ impl Session {
     }
 
     fn latest_server_salt(&mut self) -> error::Result<i64> {
+        let now = self.server_time();
         let time = {
             let last_salt: &Salt = self.server_salts.last().ok_or(error::Error::from(ErrorKind::NoServerSalts))?;
 
             // Make sure at least one salt is retained.
-            cmp::min(Utc::now(), last_salt.valid_until.clone())
+            cmp::min(now, last_salt.valid_until.clone())
         };
 
         self.server_salts.retain(|s| &s.valid_until >= &time);
         assert!(self.server_salts.len() >= 1);
-        let salt = self.server_salts[0].salt;
+
+        // Salts which are not valid yet are skipped unless nothing else
+        // is left, in which case the last known salt is used
+        let salt = self.server_salts.iter()
+            .find(|s| s.valid_since <= now)
+            .unwrap_or(&self.server_salts[0])
+            .salt;
 
         Ok(salt)
     }
@@ -105,6 +321,163 @@ impl Session {
         self.server_salts.sort_by(|a, b| a.valid_since.cmp(&b.valid_since));
     }
 
+    /// Whether an unknown authorization key drops the key instead of
+    /// failing, see `process_transport_error()`.
+    pub fn reauth_on_auth_key_error(&self) -> bool {
+        self.reauth_on_auth_key_error
+    }
+
+    /// Set whether an unknown authorization key drops the key instead of
+    /// failing, see `process_transport_error()`.
+    pub fn set_reauth_on_auth_key_error(&mut self, reauth: bool) {
+        self.reauth_on_auth_key_error = reauth;
+    }
+
+    /// Return how long the session may stay idle before a keepalive ping
+    /// is due, if keepalive is enabled.
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval
+    }
+
+    /// Enable keepalive pings after `interval` without sent encrypted
+    /// messages, or disable them with `None`.
+    ///
+    /// With `use_delay_disconnect` pings are sent as
+    /// `ping_delay_disconnect`, so that the server closes the connection
+    /// if the next one doesn't arrive in time. See
+    /// `create_keepalive_message()`.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, use_delay_disconnect: bool) {
+        self.keepalive_interval = interval;
+        self.keepalive_delay_disconnect = use_delay_disconnect;
+    }
+
+    /// Whether keepalive is enabled and no encrypted message was created
+    /// within the keepalive interval.
+    pub fn keepalive_due(&self) -> bool {
+        match self.keepalive_interval {
+            Some(interval) => self.last_sent_at + interval <= self.clock.now(),
+            None => false,
+        }
+    }
+
+    /// Create an encrypted keepalive ping if one is due, see
+    /// `keepalive_due()`.
+    ///
+    /// This is meant to be called periodically, e.g. from a timer. Any
+    /// other encrypted message postpones the next ping, since it keeps
+    /// the connection busy as well. Pings aren't resent, the next one
+    /// replaces them.
+    pub fn create_keepalive_message(&mut self) -> error::Result<Option<Message<Object>>> {
+        let interval = match self.keepalive_interval {
+            Some(interval) if self.keepalive_due() => interval,
+            _ => return Ok(None),
+        };
+
+        let ping_id = rand::thread_rng().gen();
+        let ping = if self.keepalive_delay_disconnect {
+            let disconnect_delay = interval.num_seconds() + KEEPALIVE_DISCONNECT_MARGIN_SECS;
+            Box::new(::schema::rpc::ping_delay_disconnect {
+                ping_id: ping_id,
+                disconnect_delay: cmp::min(disconnect_delay, i32::max_value() as i64) as i32,
+            }) as Object
+        } else {
+            Box::new(::schema::rpc::ping { ping_id: ping_id }) as Object
+        };
+
+        let message = self.impl_create_decrypted_message(ping, MessagePurpose::Content)?;
+
+        Ok(Some(message))
+    }
+
+    /// Return how long a request may be delayed after `FLOOD_WAIT_X` to
+    /// be retried, if flood wait handling is enabled.
+    pub fn max_flood_wait(&self) -> Option<Duration> {
+        self.max_flood_wait
+    }
+
+    /// Enable retrying requests which failed with `FLOOD_WAIT_X` if `X`
+    /// seconds don't exceed `max_flood_wait`, or disable it with `None`.
+    ///
+    /// See `flood_wait_retry_delay()` and `retry_flood_wait()`.
+    pub fn set_max_flood_wait(&mut self, max_flood_wait: Option<Duration>) {
+        self.max_flood_wait = max_flood_wait;
+    }
+
+    /// Return how long to wait before sending the same request again if
+    /// it failed with `FLOOD_WAIT_X` and `X` seconds don't exceed
+    /// `max_flood_wait()`.
+    ///
+    /// Returns `None` for any other error, in which case it should be
+    /// reported to the caller as is.
+    pub fn flood_wait_retry_delay(&self, error: &error::Error) -> Option<Duration> {
+        let max_flood_wait = match self.max_flood_wait {
+            Some(max_flood_wait) => max_flood_wait,
+            None => return None,
+        };
+
+        match *error.kind() {
+            ErrorKind::RpcError(FLOOD_CODE, ref error_message) => {
+                flood_wait_secs(error_message)
+                    .map(|secs| Duration::seconds(secs as i64))
+                    .and_then(|wait| if wait <= max_flood_wait { Some(wait) } else { None })
+            },
+            _ => None,
+        }
+    }
+
+    /// Perform a request with `send`, retrying it for as long as it fails
+    /// with a `FLOOD_WAIT_X` which `flood_wait_retry_delay()` allows.
+    ///
+    /// `send` is called once per attempt, so that it creates a fresh
+    /// message for the same request each time, and `sleep` waits for the
+    /// delay before the next attempt, e.g. with `std::thread::sleep`. Any
+    /// other result is returned as is.
+    pub fn retry_flood_wait<T, F, S>(&mut self, mut send: F, mut sleep: S) -> error::Result<T>
+        where F: FnMut(&mut Session) -> error::Result<T>,
+              S: FnMut(Duration)
+    {
+        loop {
+            let error = match send(self) {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+
+            match self.flood_wait_retry_delay(&error) {
+                Some(delay) => {
+                    info!("Retrying request after {} for {:?}", delay, error);
+                    sleep(delay);
+                },
+                None => return Err(error),
+            }
+        }
+    }
+
+    /// Whether a new authorization key must be generated before sending
+    /// encrypted messages.
+    pub fn needs_auth_key(&self) -> bool {
+        self.auth_key.is_none()
+    }
+
+    /// Handle a transport error code received instead of a message, see
+    /// `message::transport_error_code()`.
+    ///
+    /// If the server doesn't know the authorization key and
+    /// `reauth_on_auth_key_error()` is set, the key and its salts are
+    /// dropped and `Ok(true)` is returned: the caller must perform a new
+    /// key exchange and `adopt_key()` the result. Other codes are
+    /// reported as `TransportError`.
+    pub fn process_transport_error(&mut self, code: i32) -> error::Result<bool> {
+        if code == message::AUTH_KEY_UNKNOWN_CODE && self.reauth_on_auth_key_error && self.auth_key.is_some() {
+            warn!("Server doesn't know the authorization key, dropping it");
+            self.auth_key = None;
+            self.server_salts.clear();
+
+            return Ok(true);
+        }
+
+        bail!(ErrorKind::TransportError(code))
+    }
+
     /// Adopt an `AuthKey` after successful authorization.
     pub fn adopt_key(&mut self, auth_key: AuthKey) {
         self.auth_key = Some(auth_key);
@@ -114,7 +487,75 @@ impl Session {
         self.to_ack.push(id);
     }
 
-    fn fresh_auth_key(&self) -> error::Result<AuthKey> {
+    /// Return IDs of content messages sent in this session whose
+    /// delivery wasn't confirmed yet.
+    pub fn sent_msg_ids(&self) -> &[i64] {
+        &self.sent_msg_ids
+    }
+
+    /// Return content messages sent in this session whose delivery wasn't
+    /// confirmed yet.
+    pub fn in_flight_messages(&self) -> &[InFlightMessage] {
+        &self.in_flight
+    }
+
+    fn track_sent(&mut self, msg_id: i64, body: Object) {
+        self.sent_msg_ids.push(msg_id);
+        self.in_flight.push(InFlightMessage {
+            msg_id: msg_id,
+            body: body,
+            sent_at: self.clock.now(),
+        });
+    }
+
+    fn forget_sent(&mut self, msg_id: i64) {
+        self.sent_msg_ids.retain(|&id| id != msg_id);
+        self.in_flight.retain(|m| m.msg_id != msg_id);
+    }
+
+    /// Stop tracking content messages whose delivery was confirmed by
+    /// `msgs_ack`, so that they aren't resent.
+    pub fn acknowledge(&mut self, msg_ids: &[i64]) {
+        for &msg_id in msg_ids {
+            self.forget_sent(msg_id);
+        }
+    }
+
+    /// Create messages to resend content messages which weren't
+    /// confirmed within `timeout` since they were last sent.
+    ///
+    /// Each message gets a fresh ID and its deadline is reset. This is
+    /// meant to be called periodically, e.g. from a timer, to achieve
+    /// at-least-once delivery.
+    pub fn resend_timed_out(&mut self, timeout: Duration) -> error::Result<Vec<Message<Object>>> {
+        let now = self.clock.now();
+        let mut messages = Vec::new();
+
+        for i in 0..self.in_flight.len() {
+            if self.in_flight[i].sent_at + timeout > now {
+                continue;
+            }
+
+            let body = self.in_flight[i].body.clone();
+            let message = self.impl_create_decrypted_message(body, MessagePurpose::Content)?;
+            let old_msg_id = self.in_flight[i].msg_id;
+            let new_msg_id = message.message_id();
+
+            for id in &mut self.sent_msg_ids {
+                if *id == old_msg_id {
+                    *id = new_msg_id;
+                }
+            }
+            self.in_flight[i].msg_id = new_msg_id;
+            self.in_flight[i].sent_at = now;
+
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+
+    pub(super) fn fresh_auth_key(&self) -> error::Result<AuthKey> {
         match self.auth_key {
             Some(ref key) => Ok(key.clone()),
             None => bail!(ErrorKind::NoAuthKey),
@@ -126,7 +567,7 @@ impl Session {
         where T: TLObject
     {
         Ok(Message::PlainText {
-            message_id: next_message_id(),
+            message_id: self.next_message_id(),
             body: WithSize::new(Boxed::new(body))?,
         })
     }
@@ -142,11 +583,35 @@ impl Session {
             return Ok(None);
         }
 
+        let tracked_body = body.clone_to_box();
         let message = self.impl_create_decrypted_message(body, MessagePurpose::Content)?;
+        self.track_sent(message.message_id(), tracked_body);
 
         Ok(Some(message))
     }
 
+    /// Create an encrypted content message whose body refers to the ID of
+    /// the message itself, which is passed to `make_body`.
+    pub(super) fn create_encrypted_message_with_own_id<T, F>(&mut self, make_body: F)
+        -> error::Result<Message<T>>
+        where T: TLObject,
+              F: FnOnce(i64) -> error::Result<T>
+    {
+        let message_id = self.next_message_id();
+        let body = make_body(message_id)?;
+
+        let tracked_body = body.clone_to_box();
+        let mut message = self.impl_create_decrypted_message(body, MessagePurpose::Content)?;
+
+        match *&mut message {
+            Message::PlainText { .. } => unreachable!(),
+            Message::Decrypted { ref mut decrypted_data } => decrypted_data.message_id = message_id,
+        }
+        self.track_sent(message_id, tracked_body);
+
+        Ok(message)
+    }
+
     /// Create an encrypted message with acks.
     ///
     /// On success returns `Ok(message)` if there are acks in this
@@ -159,6 +624,7 @@ impl Session {
             return Ok(None);
         }
 
+        let tracked_body = body.clone_to_box();
         let acks = ::schema::MsgsAck {
             msg_ids: Boxed::new(mem::replace(&mut self.to_ack, vec![])),
         };
@@ -166,12 +632,12 @@ impl Session {
         let msg_container = ::schema::manual::MessageContainer {
             messages: vec![
                 ::schema::manual::Message {
-                    msg_id: next_message_id(),
+                    msg_id: self.next_message_id(),
                     seqno: self.next_seq_no(MessagePurpose::NonContent),
                     body: WithSize::new(Boxed::new(Box::new(acks) as Object))?,
                 },
                 ::schema::manual::Message {
-                    msg_id: next_message_id(),
+                    msg_id: self.next_message_id(),
                     seqno: self.next_seq_no(MessagePurpose::Content),
                     body: WithSize::new(Boxed::new(Box::new(body) as Object))?,
                 }
@@ -185,26 +651,154 @@ impl Session {
             Message::PlainText { .. } => unreachable!(),
             Message::Decrypted { ref mut decrypted_data } => decrypted_data.message_id = msg_container_id,
         }
+        self.track_sent(msg_container_id, tracked_body);
 
         Ok(Some(message))
     }
 
+    /// Create an encrypted `msg_container` which carries several content
+    /// messages at once, along with pending acks if there are any.
+    ///
+    /// Returns the container and IDs of the messages created for
+    /// `bodies`, in the same order, to match replies with
+    /// `process_encrypted_batch_reply()`.
+    pub fn create_encrypted_batch_message(&mut self, bodies: Vec<Object>)
+        -> error::Result<(Message<::schema::manual::MessageContainer>, Vec<i64>)>
+    {
+        let mut messages = Vec::new();
+
+        if !self.to_ack.is_empty() {
+            let acks = ::schema::MsgsAck {
+                msg_ids: Boxed::new(mem::replace(&mut self.to_ack, vec![])),
+            };
+
+            messages.push(::schema::manual::Message {
+                msg_id: self.next_message_id(),
+                seqno: self.next_seq_no(MessagePurpose::NonContent),
+                body: WithSize::new(Boxed::new(Box::new(acks) as Object))?,
+            });
+        }
+
+        let mut msg_ids = Vec::new();
+        for body in bodies {
+            let msg_id = self.next_message_id();
+            self.track_sent(msg_id, body.clone());
+            msg_ids.push(msg_id);
+
+            messages.push(::schema::manual::Message {
+                msg_id: msg_id,
+                seqno: self.next_seq_no(MessagePurpose::Content),
+                body: WithSize::new(Boxed::new(body))?,
+            });
+        }
+
+        let msg_container = ::schema::manual::MessageContainer {
+            messages: messages,
+        };
+        let message = self.impl_create_decrypted_message(msg_container, MessagePurpose::NonContent)?;
+
+        Ok((message, msg_ids))
+    }
+
+    /// Create an encrypted `destroy_session` message which notifies the
+    /// server that it may forget the data of the session with the
+    /// specified ID.
+    ///
+    /// The server replies with either `destroy_session_ok` or
+    /// `destroy_session_none`. Note that the result of destroying the
+    /// current session is undefined by MTProto, so this is meant for
+    /// other sessions bound to the same authorization key.
+    pub fn create_destroy_session_message(&mut self, session_id: i64)
+        -> error::Result<Message<::schema::rpc::destroy_session>>
+    {
+        let destroy_session = ::schema::rpc::destroy_session {
+            session_id: session_id,
+        };
+
+        let tracked_body = Box::new(destroy_session.clone()) as Object;
+        let message = self.impl_create_decrypted_message(destroy_session, MessagePurpose::Content)?;
+        self.track_sent(message.message_id(), tracked_body);
+
+        Ok(message)
+    }
+
+    /// Create an encrypted `http_wait` message which makes the server
+    /// hold an HTTP request open until messages for this session arrive.
+    ///
+    /// The server answers after at most `max_wait` milliseconds, or
+    /// `wait_after` milliseconds after the first pending message,
+    /// delaying each reply by at most `max_delay` milliseconds. Updates
+    /// received in the reply can be retrieved with `take_updates()` once
+    /// it is passed to `process_encrypted_reply()`.
+    pub fn create_http_wait_message(&mut self, max_delay: i32, wait_after: i32, max_wait: i32)
+        -> error::Result<Message<::schema::HttpWait>>
+    {
+        let http_wait = ::schema::HttpWait {
+            max_delay: max_delay,
+            wait_after: wait_after,
+            max_wait: max_wait,
+        };
+
+        self.impl_create_decrypted_message(http_wait, MessagePurpose::NonContent)
+    }
+
+    /// Create an encrypted `msgs_state_req` message which asks the
+    /// server about the delivery status of all unconfirmed content
+    /// messages of this session.
+    ///
+    /// The server replies with `msgs_state_info` which should be passed
+    /// to `process_msgs_state_info()` along with `sent_msg_ids()` as of
+    /// the time this request was created.
+    pub fn create_msgs_state_req_message(&mut self) -> error::Result<Message<::schema::MsgsStateReq>> {
+        let msgs_state_req = ::schema::MsgsStateReq {
+            msg_ids: Boxed::new(self.sent_msg_ids.clone()),
+        };
+
+        self.impl_create_decrypted_message(msgs_state_req, MessagePurpose::Content)
+    }
+
+    /// Process `msgs_state_info` obtained in reply to `msgs_state_req`
+    /// which asked about `msg_ids`.
+    ///
+    /// Stops tracking messages which were received by the server and
+    /// returns IDs of messages which need to be sent again.
+    pub fn process_msgs_state_info(&mut self, msg_ids: &[i64], msgs_state_info: &::schema::MsgsStateInfo)
+        -> error::Result<Vec<i64>>
+    {
+        let states = reliability::parse_msgs_state_info(msg_ids, msgs_state_info)?;
+
+        for &(id, state) in &states {
+            if state.is_received() {
+                self.forget_sent(id);
+            }
+        }
+
+        let to_resend = states.into_iter()
+            .filter(|&(_, state)| state.needs_resend())
+            .map(|(id, _)| id)
+            .collect();
+
+        Ok(to_resend)
+    }
+
     fn impl_create_decrypted_message<T>(&mut self, body: T, purpose: MessagePurpose) -> error::Result<Message<T>>
         where T: Identifiable + MtProtoSized
     {
         let decrypted_data = DecryptedData {
             salt: self.latest_server_salt()?,
             session_id: self.session_id,
-            message_id: next_message_id(),
+            message_id: self.next_message_id(),
             seq_no: self.next_seq_no(purpose),
             body: WithSize::new(Boxed::new(body))?,
 
             key: self.fresh_auth_key()?,
+            version: self.protocol_version,
         };
 
         let message = Message::Decrypted {
             decrypted_data: decrypted_data,
         };
+        self.last_sent_at = self.clock.now();
 
         Ok(message)
     }
@@ -216,8 +810,274 @@ impl Session {
         use serde_mtproto::Deserializer;
 
         let mut deserializer = Deserializer::new(message_bytes, None);
-        let seed = MessageSeed::new(self.auth_key.clone(), encrypted_data_len);
+        let seed = MessageSeed::new(self.auth_key.clone(), self.protocol_version, encrypted_data_len);
 
         seed.deserialize(&mut deserializer).map_err(Into::into)
     }
+
+    /// Reads a reply to the `request` message from raw bytes.
+    ///
+    /// Unlike `process_message()`, the type of the reply doesn't need to
+    /// be specified since it is determined by the RPC function sent in
+    /// `request`.
+    ///
+    /// Plain-text replies, such as `resPQ`, are used to synchronize with
+    /// server time, since they are sent right before a key is created.
+    pub fn process_reply<F>(&self,
+                            _request: &Message<F>,
+                            reply_bytes: &[u8],
+                            encrypted_data_len: Option<u32>)
+                           -> error::Result<Message<F::Reply>>
+        where F: RpcFunction,
+              F::Reply: fmt::Debug + DeserializeOwned
+    {
+        let reply = self.process_message(reply_bytes, encrypted_data_len)?;
+
+        if encrypted_data_len.is_none() {
+            self.update_time_offset(reply.message_id());
+        }
+
+        Ok(reply)
+    }
+
+    /// Reads an `rpc_result` reply to the `request` message from raw
+    /// bytes and returns the result it wraps.
+    ///
+    /// Fails if the `rpc_result` doesn't refer to `request`.
+    pub fn process_rpc_result<F>(&self,
+                                 request: &Message<F>,
+                                 reply_bytes: &[u8],
+                                 encrypted_data_len: Option<u32>)
+                                -> error::Result<F::Reply>
+        where F: RpcFunction + Identifiable + MtProtoSized,
+              F::Reply: fmt::Debug + DeserializeOwned
+    {
+        let message: Message<RpcResult<F::Reply>> = self.process_message(reply_bytes, encrypted_data_len)?;
+        let rpc_result = message.into_body();
+
+        let request_msg_id = request.message_id();
+        if rpc_result.req_msg_id != request_msg_id {
+            bail!(ErrorKind::UnexpectedReplyMsgId(request_msg_id, rpc_result.req_msg_id));
+        }
+
+        Ok(rpc_result.result.into_inner())
+    }
+
+    /// Reads an encrypted reply to the `request` message, which may be a
+    /// `msg_container` of several messages.
+    ///
+    /// Returns the result of an `rpc_result` referring to `request` or
+    /// `None` if there is no such one. Content-related messages are
+    /// queued to be acknowledged, but duplicates of recently received
+    /// messages are skipped after that. Salts from `new_session_created`
+    /// are adopted, messages confirmed by `msgs_ack` are no longer
+    /// tracked and all other messages are kept to be retrieved with
+    /// `take_pending_messages()`.
+    pub fn process_encrypted_reply<F>(&mut self, request: &Message<F>, reply_bytes: &[u8])
+        -> error::Result<Option<F::Reply>>
+        where F: RpcFunction + Identifiable + MtProtoSized,
+              F::Reply: fmt::Debug + DeserializeOwned
+    {
+        let messages = self.decrypt_and_unpack(reply_bytes)?;
+
+        let request_msg_id = request.message_id();
+        let mut reply = None;
+
+        for msg in messages {
+            self.note_received(&msg);
+            if !self.remember_received(msg.msg_id) {
+                continue;
+            }
+
+            if reply.is_none() && msg.rpc_result_req_msg_id() == Some(request_msg_id) {
+                reply = Some(msg.read_rpc_result()?);
+                continue;
+            }
+
+            self.dispatch_received(msg)?;
+        }
+
+        Ok(reply)
+    }
+
+    /// Reads an encrypted reply to a batch created with
+    /// `create_encrypted_batch_message()`.
+    ///
+    /// Returns an element per ID in `req_msg_ids`, in the same order:
+    /// `None` if there is no reply to the request yet, the `rpc_result`
+    /// message itself if the request succeeded or `RpcError` if it
+    /// failed. Other messages are handled as in
+    /// `process_encrypted_reply()`.
+    pub fn process_encrypted_batch_reply(&mut self, req_msg_ids: &[i64], reply_bytes: &[u8])
+        -> error::Result<Vec<Option<error::Result<ContainedMessage>>>>
+    {
+        let messages = self.decrypt_and_unpack(reply_bytes)?;
+        let mut replies: Vec<_> = req_msg_ids.iter().map(|_| None).collect();
+
+        for msg in messages {
+            self.note_received(&msg);
+            if !self.remember_received(msg.msg_id) {
+                continue;
+            }
+
+            let index = msg.rpc_result_req_msg_id().and_then(|id| req_msg_ids.iter().position(|&r| r == id));
+            match index {
+                Some(i) if replies[i].is_none() => {
+                    let reply = match msg.read_rpc_error()? {
+                        Some(rpc_error) => Err(ErrorKind::RpcError(rpc_error.error_code, rpc_error.error_message).into()),
+                        None => Ok(msg),
+                    };
+                    replies[i] = Some(reply);
+                },
+                _ => self.dispatch_received(msg)?,
+            }
+        }
+
+        Ok(replies)
+    }
+
+    /// Decrypts an encrypted reply, splits it into messages if it is a
+    /// `msg_container` and checks the IDs the server sent them with.
+    fn decrypt_and_unpack(&self, reply_bytes: &[u8]) -> error::Result<Vec<ContainedMessage>> {
+        let top_level = message::decrypt_message(
+            reply_bytes, &self.fresh_auth_key()?, self.protocol_version, self.session_id)?;
+        self.check_server_msg_id(top_level.msg_id)?;
+
+        let messages = top_level.unpack_container()?;
+        for msg in &messages {
+            self.check_server_msg_id(msg.msg_id)?;
+        }
+
+        // Originals wrapped in `msg_copy` are resent and may be older than
+        // the time window, so their IDs are only matched against recently
+        // received ones
+        messages.into_iter().map(ContainedMessage::unwrap_msg_copy).collect()
+    }
+
+    /// Checks that a message ID could have been generated by the server
+    /// for a message received just now.
+    ///
+    /// Server message IDs are odd and must lie between 300 seconds in the
+    /// past and 30 seconds in the future of the server time, which
+    /// rejects replayed and bogus messages.
+    pub fn check_server_msg_id(&self, msg_id: i64) -> error::Result<()> {
+        if msg_id & 1 == 0 {
+            bail!(ErrorKind::InvalidServerMsgId(msg_id));
+        }
+
+        let (msg_time, _) = msg_id::decode_msg_id(msg_id);
+        let lead = msg_time.signed_duration_since(self.server_time());
+        if lead < -Duration::seconds(MAX_SERVER_MSG_AGE_SECS) || lead > Duration::seconds(MAX_SERVER_MSG_LEAD_SECS) {
+            bail!(ErrorKind::InvalidServerMsgId(msg_id));
+        }
+
+        Ok(())
+    }
+
+    /// Queue a received message for acknowledgment if needed and stop
+    /// tracking the request it is a result for.
+    fn note_received(&mut self, msg: &ContainedMessage) {
+        if msg.is_content_related() {
+            self.ack_id(msg.msg_id);
+        }
+
+        // A result confirms delivery of the request it refers to
+        if let Some(req_msg_id) = msg.rpc_result_req_msg_id() {
+            self.forget_sent(req_msg_id);
+        }
+    }
+
+    /// Return whether a message with `msg_id` was already received
+    /// recently.
+    ///
+    /// Only the last `max_recent_msg_ids()` IDs are remembered.
+    pub fn is_duplicate(&self, msg_id: i64) -> bool {
+        self.recent_msg_ids.contains(&msg_id)
+    }
+
+    /// Return how many IDs of received messages are remembered to detect
+    /// duplicates.
+    pub fn max_recent_msg_ids(&self) -> usize {
+        self.max_recent_msg_ids
+    }
+
+    /// Set how many IDs of received messages are remembered to detect
+    /// duplicates, forgetting the oldest ones if there are more already.
+    pub fn set_max_recent_msg_ids(&mut self, max_recent_msg_ids: usize) {
+        self.max_recent_msg_ids = max_recent_msg_ids;
+        while self.recent_msg_ids.len() > max_recent_msg_ids {
+            self.recent_msg_ids.pop_front();
+        }
+    }
+
+    /// Remember the ID of a received message, returning `false` if it is
+    /// a duplicate which must not be handled again.
+    fn remember_received(&mut self, msg_id: i64) -> bool {
+        if self.is_duplicate(msg_id) {
+            return false;
+        }
+
+        if self.max_recent_msg_ids > 0 {
+            if self.recent_msg_ids.len() == self.max_recent_msg_ids {
+                self.recent_msg_ids.pop_front();
+            }
+            self.recent_msg_ids.push_back(msg_id);
+        }
+
+        true
+    }
+
+    /// Handle a received message which isn't a reply the caller waits for.
+    fn dispatch_received(&mut self, msg: ContainedMessage) -> error::Result<()> {
+        match msg.constructor_id() {
+            Some(NEW_SESSION_CREATED_ID) => {
+                let new_session: Boxed<::schema::NewSession> = msg.deserialize_body()?;
+                self.adopt_new_session_salt(new_session.inner().server_salt);
+            },
+            Some(MSGS_ACK_ID) => {
+                let msgs_ack: Boxed<::schema::MsgsAck> = msg.deserialize_body()?;
+                self.acknowledge(msgs_ack.inner().msg_ids.inner());
+            },
+            _ => self.pending_messages.push(msg),
+        }
+
+        Ok(())
+    }
+
+    /// Return messages received in replies which weren't handled by the
+    /// session itself.
+    pub fn take_pending_messages(&mut self) -> Vec<ContainedMessage> {
+        mem::replace(&mut self.pending_messages, Vec::new())
+    }
+
+    /// Return `Updates` received in replies so far, leaving other
+    /// pending messages in place.
+    pub fn take_updates(&mut self) -> Vec<ContainedMessage> {
+        let (updates, rest) = mem::replace(&mut self.pending_messages, Vec::new())
+            .into_iter()
+            .partition(updates::is_updates);
+        self.pending_messages = rest;
+
+        updates
+    }
+
+    fn adopt_new_session_salt(&mut self, salt: i64) {
+        let now = self.clock.now().timestamp() as i32; // Fits until 2038
+        self.add_server_salts(vec![FutureSalt {
+            valid_since: now,
+            // Server salts are changed every 30 minutes
+            valid_until: now + 30 * 60,
+            salt: salt,
+        }]);
+    }
+}
+
+/// Return how many seconds must pass before a request is sent again if
+/// `error_message` of `rpc_error` is `FLOOD_WAIT_X`.
+pub fn flood_wait_secs(error_message: &str) -> Option<u32> {
+    if error_message.starts_with(FLOOD_WAIT_PREFIX) {
+        error_message[FLOOD_WAIT_PREFIX.len()..].parse().ok()
+    } else {
+        None
+    }
 }
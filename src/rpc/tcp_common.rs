@@ -0,0 +1,297 @@
+//! Framing of serialized messages for the TCP transports.
+//!
+//! The functions here lay out a single frame. `FrameEncoder` also
+//! writes protocol tags which precede the first frame of the abridged
+//! and intermediate modes, see `encryption::obfuscation`. Received
+//! frames are split back with `FrameDecoder`.
+//!
+//! More information: https://core.telegram.org/mtproto/mtproto-transports.
+
+use std::io::{self, Read};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+
+use error::{self, ErrorKind};
+use rpc::encryption::obfuscation::{ABRIDGED_TAG, INTERMEDIATE_TAG};
+
+
+/// Longest payload the 3-byte word count of the abridged mode can
+/// describe.
+const ABRIDGED_MAX_LEN: usize = 0xff_ff_ff * 4;
+/// Longest payload a 4-byte length prefix can describe.
+const INTERMEDIATE_MAX_LEN: usize = 0xff_ff_ff_ff;
+/// Longest payload of the full mode, whose length includes itself,
+/// `seq_no` and the checksum.
+const FULL_MAX_LEN: usize = 0xff_ff_ff_ff - 12;
+
+
+/// Lays out a frame of the abridged mode as `length + body`.
+///
+/// The length is counted in 4-byte words: a single byte if it is below
+/// `0x7f` and `0x7f` followed by 3 little-endian bytes otherwise, so
+/// bodies whose length isn't a multiple of 4 fail with
+/// `UnalignedFrameBody`.
+pub fn frame_abridged(body: &[u8]) -> error::Result<Vec<u8>> {
+    if body.len() % 4 != 0 {
+        bail!(ErrorKind::UnalignedFrameBody(body.len()));
+    }
+    if body.len() > ABRIDGED_MAX_LEN {
+        bail!(ErrorKind::FrameTooLong(ABRIDGED_MAX_LEN, body.len()));
+    }
+
+    let words = body.len() / 4;
+    let mut frame = Vec::with_capacity(4 + body.len());
+
+    if words < 0x7f {
+        frame.push(words as u8);
+    } else {
+        let mut len_bytes = [0x7f, 0, 0, 0];
+        LittleEndian::write_uint(&mut len_bytes[1..4], words as u64, 3);
+        frame.extend_from_slice(&len_bytes);
+    }
+    frame.extend_from_slice(body);
+
+    Ok(frame)
+}
+
+/// Lays out a frame of the intermediate mode as `length + body`, with a
+/// 4-byte little-endian length.
+pub fn frame_intermediate(body: &[u8]) -> error::Result<Vec<u8>> {
+    if body.len() > INTERMEDIATE_MAX_LEN {
+        bail!(ErrorKind::FrameTooLong(INTERMEDIATE_MAX_LEN, body.len()));
+    }
+
+    let mut frame = vec![0; 4];
+    LittleEndian::write_u32(&mut frame, body.len() as u32);
+    frame.extend_from_slice(body);
+
+    Ok(frame)
+}
+
+/// Lays out a frame of the full mode as `length + seq_no + body + crc32`.
+///
+/// `length` counts the whole frame, and `seq_no` counts frames sent over
+/// the connection starting at 0.
+pub fn frame_full(seq_no: u32, body: &[u8]) -> error::Result<Vec<u8>> {
+    if body.len() > FULL_MAX_LEN {
+        bail!(ErrorKind::FrameTooLong(FULL_MAX_LEN, body.len()));
+    }
+
+    let len = body.len() + 12;
+    let mut frame = vec![0; len];
+
+    LittleEndian::write_u32(&mut frame[0..4], len as u32);
+    LittleEndian::write_u32(&mut frame[4..8], seq_no);
+    frame[8..len - 4].copy_from_slice(body);
+
+    let crc = crc32::checksum_ieee(&frame[0..len - 4]);
+    LittleEndian::write_u32(&mut frame[len - 4..], crc);
+
+    Ok(frame)
+}
+
+
+/// Converts a frame length read from the wire to `usize`, failing
+/// instead of truncating if it exceeds `max_len`.
+pub fn checked_frame_len(len: u64, max_len: usize) -> error::Result<usize> {
+    // No `TryFrom` on this compiler, so convert only after checking the
+    // value fits into `usize` of the target
+    if len > usize::max_value() as u64 {
+        bail!(ErrorKind::ResponseTooLong(len));
+    }
+
+    let ulen = len as usize;
+    if ulen > max_len {
+        bail!(ErrorKind::ResponseTooLong(len));
+    }
+
+    Ok(ulen)
+}
+
+
+/// Layout of frames received over a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameMode {
+    Abridged,
+    Intermediate,
+    Full,
+}
+
+/// Lays out frames sent over a connection.
+///
+/// The first frame of the abridged and intermediate modes is prepended
+/// with the protocol tag so that both are sent in a single write, and
+/// frames of the full mode are numbered starting at 0.
+#[derive(Debug)]
+pub struct FrameEncoder {
+    mode: FrameMode,
+    is_first_frame: bool,
+    send_seq_no: u32,
+}
+
+impl FrameEncoder {
+    pub fn new(mode: FrameMode) -> FrameEncoder {
+        FrameEncoder {
+            mode: mode,
+            is_first_frame: true,
+            send_seq_no: 0,
+        }
+    }
+
+    /// Lays out the next frame.
+    pub fn frame(&mut self, body: &[u8]) -> error::Result<Vec<u8>> {
+        let (tag, frame) = match self.mode {
+            // Unlike the obfuscated transport, only a single byte of the
+            // abridged tag is sent
+            FrameMode::Abridged => (vec![ABRIDGED_TAG[0]], frame_abridged(body)?),
+            FrameMode::Intermediate => (INTERMEDIATE_TAG.to_vec(), frame_intermediate(body)?),
+            FrameMode::Full => {
+                let frame = frame_full(self.send_seq_no, body)?;
+                self.send_seq_no = self.send_seq_no.wrapping_add(1);
+
+                return Ok(frame);
+            },
+        };
+
+        if self.is_first_frame {
+            self.is_first_frame = false;
+
+            let mut data = tag;
+            data.extend(frame);
+            Ok(data)
+        } else {
+            Ok(frame)
+        }
+    }
+}
+
+/// Splits bytes received over a connection into frame bodies.
+///
+/// Bytes may be fed in chunks of any size: those past the end of a frame
+/// are kept for the next one. Lengths above `max_len` are rejected before
+/// the frame is buffered, and frames of the full mode are checked for
+/// their `seq_no` and checksum.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    mode: FrameMode,
+    max_len: usize,
+    recv_seq_no: u32,
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new(mode: FrameMode, max_len: usize) -> FrameDecoder {
+        FrameDecoder {
+            mode: mode,
+            max_len: max_len,
+            recv_seq_no: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Appends bytes received over the connection.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the body of the next frame if all of it has been fed.
+    pub fn next_frame(&mut self) -> error::Result<Option<Vec<u8>>> {
+        let len = match self.frame_len()? {
+            Some(len) if self.buf.len() >= len => len,
+            _ => return Ok(None),
+        };
+        let header_len = self.header_len();
+        let frame: Vec<u8> = self.buf.drain(..len).collect();
+
+        if self.mode != FrameMode::Full {
+            return Ok(Some(frame[header_len..].to_vec()));
+        }
+
+        let seq_no = LittleEndian::read_u32(&frame[4..8]);
+        if seq_no != self.recv_seq_no {
+            bail!(ErrorKind::SeqNoMismatch(self.recv_seq_no, seq_no));
+        }
+
+        let checksum = LittleEndian::read_u32(&frame[len - 4..]);
+        let crc = crc32::checksum_ieee(&frame[0..len - 4]);
+        if crc != checksum {
+            bail!(ErrorKind::FrameChecksumMismatch(checksum, crc));
+        }
+
+        self.recv_seq_no = self.recv_seq_no.wrapping_add(1);
+
+        Ok(Some(frame[8..len - 4].to_vec()))
+    }
+
+    /// Returns the error to report if the connection closes before the
+    /// next frame is complete.
+    ///
+    /// Both byte counts cover the whole frame including its length, which
+    /// is the only count known before it is received.
+    pub fn closed_mid_message(&self) -> error::Error {
+        let expected = match self.frame_len() {
+            Ok(Some(len)) => len,
+            _ => self.header_len(),
+        };
+
+        ErrorKind::ConnectionClosedMidMessage(expected, self.buf.len()).into()
+    }
+
+    /// Length of the length prefix of the next frame, as far as it is
+    /// known.
+    fn header_len(&self) -> usize {
+        match self.mode {
+            FrameMode::Abridged => match self.buf.first() {
+                Some(&0x7f) => 4,
+                _ => 1,
+            },
+            FrameMode::Intermediate | FrameMode::Full => 4,
+        }
+    }
+
+    /// Length of the whole next frame, if its length prefix has been fed.
+    fn frame_len(&self) -> error::Result<Option<usize>> {
+        let header_len = self.header_len();
+        if self.buf.len() < header_len {
+            return Ok(None);
+        }
+
+        let body_len = match self.mode {
+            FrameMode::Abridged if header_len == 1 => self.buf[0] as u64 * 4,
+            FrameMode::Abridged => LittleEndian::read_uint(&self.buf[1..4], 3) * 4,
+            FrameMode::Intermediate => LittleEndian::read_u32(&self.buf[0..4]) as u64,
+            FrameMode::Full => {
+                // The length of the full mode counts the whole frame
+                let len = checked_frame_len(LittleEndian::read_u32(&self.buf[0..4]) as u64, self.max_len)?;
+                if len < 12 {
+                    bail!(ErrorKind::FrameTooShort(len));
+                }
+
+                return Ok(Some(len));
+            },
+        };
+
+        Ok(Some(header_len + checked_frame_len(body_len, self.max_len)?))
+    }
+}
+
+/// Reads from `reader` until `decoder` holds a whole frame and returns
+/// its body.
+pub fn read_frame<R: Read>(reader: &mut R, decoder: &mut FrameDecoder) -> error::Result<Vec<u8>> {
+    let mut chunk = [0; 4096];
+
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(frame);
+        }
+
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => return Err(decoder.closed_mid_message()),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        decoder.feed(&chunk[..read]);
+    }
+}
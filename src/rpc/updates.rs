@@ -0,0 +1,21 @@
+//! Updates which the server pushes outside of request/reply exchanges.
+
+use super::message::ContainedMessage;
+
+
+/// Constructor ids of all `Updates` constructors: `updatesTooLong`,
+/// `updateShortMessage`, `updateShortChatMessage`, `updateShort`,
+/// `updatesCombined`, `updates` and `updateShortSentMessage`.
+pub const UPDATES_CONSTRUCTOR_IDS: &[u32] = &[
+    0xe317af7e, 0x914fbf11, 0x16812688, 0x78d4dec1, 0x725b04c3, 0x74ae4240, 0x11f1331c,
+];
+
+/// Whether the message body is one of `Updates` constructors.
+///
+/// Such messages can be deserialized with
+/// `message.read_boxed_body::<schema::Updates>()`.
+pub fn is_updates(message: &ContainedMessage) -> bool {
+    message.constructor_id()
+        .map(|id| UPDATES_CONSTRUCTOR_IDS.contains(&id))
+        .unwrap_or(false)
+}
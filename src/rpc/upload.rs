@@ -0,0 +1,107 @@
+//! Splitting files into parts for `upload.saveFilePart`.
+
+use std::io::{self, Read};
+
+use serde_bytes::ByteBuf;
+
+use error::{self, ErrorKind};
+use schema::rpc::upload::saveFilePart;
+
+
+/// Largest part size accepted by the server.
+pub const MAX_PART_SIZE: usize = 512 * 1024;
+
+
+/// Iterator over `upload.saveFilePart` requests for a file read from a
+/// `Read` instance.
+///
+/// Parts are yielded in order with consecutive `file_part` indices
+/// starting from 0. After the iterator is exhausted, `parts_count()`
+/// returns the value to be used in the follow-up `inputFile`.
+#[derive(Debug)]
+pub struct FileParts<R> {
+    reader: R,
+    file_id: i64,
+    part_size: usize,
+    next_part: i32,
+    finished: bool,
+}
+
+impl<R: Read> FileParts<R> {
+    /// Construct a `FileParts` iterator.
+    ///
+    /// `part_size` must be divisible by 1 KB and 512 KB must be
+    /// divisible by `part_size` (i.e. it must also evenly divide 1 MB).
+    pub fn new(reader: R, file_id: i64, part_size: usize) -> error::Result<FileParts<R>> {
+        if part_size == 0 || part_size % 1024 != 0 || MAX_PART_SIZE % part_size != 0 {
+            bail!(ErrorKind::InvalidFilePartSize(part_size));
+        }
+
+        Ok(FileParts {
+            reader: reader,
+            file_id: file_id,
+            part_size: part_size,
+            next_part: 0,
+            finished: false,
+        })
+    }
+
+    /// Return the number of parts yielded so far.
+    pub fn parts_count(&self) -> i32 {
+        self.next_part
+    }
+
+    fn read_part(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; self.part_size];
+        let mut filled = 0;
+
+        while filled < self.part_size {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        buf.truncate(filled);
+
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Iterator for FileParts<R> {
+    type Item = error::Result<saveFilePart>;
+
+    fn next(&mut self) -> Option<error::Result<saveFilePart>> {
+        if self.finished {
+            return None;
+        }
+
+        let bytes = match self.read_part() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.finished = true;
+                return Some(Err(e.into()));
+            },
+        };
+
+        // A short part can only be the last one
+        if bytes.len() < self.part_size {
+            self.finished = true;
+
+            if bytes.is_empty() {
+                return None;
+            }
+        }
+
+        let part = saveFilePart {
+            file_id: self.file_id,
+            file_part: self.next_part,
+            bytes: ByteBuf::from(bytes),
+        };
+        self.next_part += 1;
+
+        Some(Ok(part))
+    }
+}
@@ -16,6 +16,17 @@ pub(crate) fn sha1_bytes(parts: &[&[u8]]) -> error::Result<Vec<u8>> {
     Ok(bytes)
 }
 
+pub(crate) fn sha256_bytes(parts: &[&[u8]]) -> error::Result<Vec<u8>> {
+    let mut hasher = hash::Hasher::new(hash::MessageDigest::sha256())?;
+    for part in parts {
+        hasher.update(part)?;
+    }
+
+    let bytes = hasher.finish2().map(|b| b.to_vec())?;
+
+    Ok(bytes)
+}
+
 
 #[derive(Debug)]
 pub enum EitherRef<'a, T: 'a> {
@@ -0,0 +1,41 @@
+//! Deserialization of boxed values dispatched on their constructor id.
+
+use serde::de::DeserializeOwned;
+use serde_mtproto::{self, Boxed, Identifiable};
+
+use error::{self, ErrorKind, ResultExt};
+use schema;
+
+
+/// Deserializes a boxed `T` from `bytes`.
+///
+/// Enums generated from the schema need to know which variant to
+/// deserialize beforehand, so it is looked up by the leading constructor
+/// id. A constructor id which doesn't belong to `T` is an error.
+pub fn read_boxed<T>(bytes: &[u8]) -> error::Result<T>
+    where T: DeserializeOwned + Identifiable
+{
+    let ctor_id: u32 = serde_mtproto::from_bytes(bytes, None)?;
+    let boxed: Boxed<T> = serde_mtproto::from_bytes(bytes, schema::enum_variant_id(ctor_id))
+        .chain_err(|| ErrorKind::UnknownConstructorId("Boxed<T>", ctor_id))?;
+
+    if boxed.inner().type_id() != ctor_id {
+        bail!(ErrorKind::UnknownConstructorId("Boxed<T>", ctor_id));
+    }
+
+    Ok(boxed.into_inner())
+}
+
+
+/// Types which can be deserialized from their boxed serialized form,
+/// e.g. a captured reply.
+pub trait FromTlBytes: Sized {
+    /// Deserializes a boxed `Self` from `bytes`, see `read_boxed()`.
+    fn from_tl_bytes(bytes: &[u8]) -> error::Result<Self>;
+}
+
+impl<T: DeserializeOwned + Identifiable> FromTlBytes for T {
+    fn from_tl_bytes(bytes: &[u8]) -> error::Result<T> {
+        read_boxed(bytes)
+    }
+}
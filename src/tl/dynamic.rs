@@ -4,13 +4,19 @@
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
+use std::io::Read;
 
 use erased_serde::{self, Serialize as ErasedSerialize, Deserializer as ErasedDeserializer};
+use flate2::read::GzDecoder;
 use serde::ser::{Serialize, Serializer};
-use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, Error as DeError};
-use serde_mtproto::{Identifiable, MtProtoSized};
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, Error as DeError};
+use serde_bytes::ByteBuf;
+use serde_mtproto::{self, Boxed, Identifiable, MtProtoSized};
 
-use error::{self, ErrorKind};
+use error::{self, ErrorKind, ResultExt};
+use rpc::message::GZIP_PACKED_ID;
+
+use super::limits::{DeserializationLimits, LimitedDeserializer, LimitState};
 
 
 /// \[**IMPLEMENTATION DETAIL**]
@@ -36,6 +42,22 @@ impl<T: 'static + Clone + TLObject> TLObjectCloneToBox for T {
 pub trait TLObject: Any + ErasedSerialize + Identifiable + MtProtoSized + TLObjectCloneToBox {
     fn as_any(&self) -> &Any;
     fn as_box_any(self: Box<Self>) -> Box<Any>;
+
+    /// Serializes this object in its boxed form, i.e. prefixed with its
+    /// constructor id.
+    fn to_tl_bytes(&self) -> error::Result<Vec<u8>>;
+
+    /// Returns the size of the boxed form of this object in bytes.
+    fn tl_size_hint(&self) -> error::Result<usize> {
+        // Constructor id takes 4 bytes
+        Ok(4 + MtProtoSized::size_hint(self)?)
+    }
+
+    /// Returns the TL name of the constructor of this object, if it is
+    /// known to the schema.
+    fn constructor_name(&self) -> Option<&'static str> {
+        ::schema::constructor_name(Identifiable::type_id(self))
+    }
 }
 
 // TLObject impls
@@ -89,6 +111,25 @@ impl Identifiable for Box<TLObject> {
 impl<T: Clone + Any + Serialize + Identifiable + MtProtoSized> TLObject for T {
     fn as_any(&self) -> &Any { self }
     fn as_box_any(self: Box<Self>) -> Box<Any> { self }
+
+    fn to_tl_bytes(&self) -> error::Result<Vec<u8>> {
+        Ok(serde_mtproto::to_bytes(&Boxed::new(self.clone()))?)
+    }
+}
+
+
+pub(crate) fn inflate(packed_data: &[u8], max_len: usize) -> error::Result<Vec<u8>> {
+    let mut unpacked = Vec::new();
+    // Read one byte more to tell a maximum length object from a longer one
+    GzDecoder::new(packed_data)
+        .and_then(|decoder| decoder.take(max_len as u64 + 1).read_to_end(&mut unpacked))
+        .chain_err(|| ErrorKind::GzipInflateFailed(packed_data.len()))?;
+
+    if unpacked.len() > max_len {
+        bail!(ErrorKind::DeserializationLimitExceeded("inflated length", max_len));
+    }
+
+    Ok(unpacked)
 }
 
 
@@ -113,24 +154,120 @@ impl fmt::Debug for TLConstructor {
     }
 }
 
+/// Default maximum nesting depth of inflated `gzip_packed` objects.
+pub const DEFAULT_MAX_GZIP_DEPTH: usize = 4;
+/// Default maximum length of an inflated `gzip_packed` object.
+pub const DEFAULT_MAX_INFLATED_LEN: usize = 16 * 1024 * 1024;
+
 /// A mapping between TL constructor ids and corresponding TL constructor bodies.
 #[derive(Debug)]
-pub struct TLConstructorsMap(pub(crate) HashMap<u32, TLConstructor>);
+pub struct TLConstructorsMap {
+    pub(crate) ctors: HashMap<u32, TLConstructor>,
+    inflate_gzip: bool,
+    max_gzip_depth: usize,
+    max_inflated_len: usize,
+    limits: DeserializationLimits,
+}
 
 impl TLConstructorsMap {
     pub fn new() -> TLConstructorsMap {
-        TLConstructorsMap(HashMap::new())
+        TLConstructorsMap {
+            ctors: HashMap::new(),
+            inflate_gzip: false,
+            max_gzip_depth: DEFAULT_MAX_GZIP_DEPTH,
+            max_inflated_len: DEFAULT_MAX_INFLATED_LEN,
+            limits: DeserializationLimits::default(),
+        }
     }
 
     pub fn add<T: TLObject + DeserializeOwned>(&mut self, type_id: u32) {
-        self.0.insert(type_id, TLConstructor(Box::new(|deserializer| {
+        self.ctors.insert(type_id, TLConstructor(Box::new(|deserializer| {
             erased_serde::deserialize::<T>(deserializer)
                 .map(|obj| Box::new(obj) as Box<TLObject>)
         })));
     }
 
     pub fn get(&self, type_id: u32) -> Option<&TLConstructor> {
-        self.0.get(&type_id)
+        self.ctors.get(&type_id)
+    }
+
+    /// Deserializes a boxed object of any registered constructor from
+    /// `bytes`, e.g. a received message whose type isn't known
+    /// beforehand.
+    ///
+    /// The map is only borrowed, so that it can be reused for every
+    /// message. Register all schema constructors with
+    /// `schema::register_ctors()`. An unregistered constructor id fails
+    /// with `UnknownConstructorId` carrying the id, and data exceeding
+    /// the limits of the map with `DeserializationLimitExceeded`.
+    pub fn read_dynamic(&self, bytes: &[u8]) -> error::Result<Box<TLObject>> {
+        let type_id: u32 = serde_mtproto::from_bytes(bytes, None)?;
+        let inflated = type_id == GZIP_PACKED_ID && self.inflate_gzip;
+        if !inflated && !self.ctors.contains_key(&type_id) {
+            bail!(ErrorKind::UnknownConstructorId("Box<TLObject>", type_id));
+        }
+
+        let state = LimitState::new(self.limits);
+        let mut deserializer = serde_mtproto::Deserializer::new(bytes, None);
+        let result = self.deserialize_limited(&mut deserializer, &state);
+
+        state.finish(result)
+    }
+
+    fn deserialize_limited<'de, D>(&self, deserializer: D, state: &LimitState) -> Result<Box<TLObject>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let visitor = BoxTLObjectVisitor { cmap: self, state: state, gzip_depth: 0 };
+        LimitedDeserializer::new(deserializer, state).deserialize_tuple(2, visitor)
+    }
+
+    /// Whether `gzip_packed` objects are inflated and the objects they
+    /// pack are returned instead.
+    pub fn inflates_gzip(&self) -> bool {
+        self.inflate_gzip
+    }
+
+    /// Set whether `gzip_packed` objects are inflated, which works at any
+    /// nesting level, e.g. for `gzip_packed` inside `gzip_packed`.
+    ///
+    /// Disabled by default, so that `gzip_packed` can still be read as
+    /// is if it is registered.
+    pub fn set_inflate_gzip(&mut self, inflate_gzip: bool) {
+        self.inflate_gzip = inflate_gzip;
+    }
+
+    /// Set how deep `gzip_packed` objects may be nested in each other,
+    /// so that crafted input can't make inflation recurse endlessly.
+    ///
+    /// Deeper nesting fails with `DeserializationLimitExceeded`.
+    pub fn set_max_gzip_depth(&mut self, max_gzip_depth: usize) {
+        self.max_gzip_depth = max_gzip_depth;
+    }
+
+    /// Set the maximum length of an inflated `gzip_packed` object, so
+    /// that a small compressed input can't exhaust memory.
+    ///
+    /// Longer objects fail with `DeserializationLimitExceeded`.
+    pub fn set_max_inflated_len(&mut self, max_inflated_len: usize) {
+        self.max_inflated_len = max_inflated_len;
+    }
+
+    /// Set the maximum element count of a single vector, so that a
+    /// crafted count can't make deserialization loop for billions of
+    /// elements.
+    ///
+    /// Longer vectors fail with `DeserializationLimitExceeded`.
+    pub fn set_max_vector_len(&mut self, max_vector_len: usize) {
+        self.limits.max_vector_len = max_vector_len;
+    }
+
+    /// Set how deep vectors and constructors may be nested in each other,
+    /// including across inflated `gzip_packed` objects, so that crafted
+    /// input can't overflow the stack.
+    ///
+    /// Deeper nesting fails with `DeserializationLimitExceeded`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.limits.max_depth = max_depth;
     }
 }
 
@@ -140,46 +277,89 @@ impl<'de> DeserializeSeed<'de> for TLConstructorsMap {
     fn deserialize<D>(self, deserializer: D) -> Result<Box<TLObject>, D::Error>
         where D: Deserializer<'de>
     {
-        fn errconv<E: DeError>(kind: ErrorKind) -> E {
-            E::custom(error::Error::from(kind))
-        }
+        DeserializeSeed::deserialize(&self, deserializer)
+    }
+}
 
-        struct BoxTLObjectVisitor(TLConstructorsMap);
+impl<'a, 'de> DeserializeSeed<'de> for &'a TLConstructorsMap {
+    type Value = Box<TLObject>;
 
-        impl<'de> de::Visitor<'de> for BoxTLObjectVisitor {
-            type Value = Box<TLObject>;
+    fn deserialize<D>(self, deserializer: D) -> Result<Box<TLObject>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let state = LimitState::new(self.limits);
+        self.deserialize_limited(deserializer, &state)
+    }
+}
 
-            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("a boxed dynamically-typed value")
-            }
+fn errconv<E: DeError>(kind: ErrorKind) -> E {
+    E::custom(error::Error::from(kind))
+}
 
-            fn visit_seq<A>(self, mut seq: A) -> Result<Box<TLObject>, A::Error>
-                where A: de::SeqAccess<'de>
-            {
-                struct BoxTLObjectSeed(TLConstructorsMap, u32);
+/// Reads a boxed object, keeping track of how deep it is nested in
+/// inflated `gzip_packed` objects.
+struct BoxTLObjectVisitor<'a> {
+    cmap: &'a TLConstructorsMap,
+    state: &'a LimitState,
+    gzip_depth: usize,
+}
 
-                impl<'de> DeserializeSeed<'de> for BoxTLObjectSeed {
-                    type Value = Box<TLObject>;
+impl<'a, 'de> de::Visitor<'de> for BoxTLObjectVisitor<'a> {
+    type Value = Box<TLObject>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a boxed dynamically-typed value")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Box<TLObject>, A::Error>
+        where A: de::SeqAccess<'de>
+    {
+        let type_id = seq.next_element()?
+            .ok_or(errconv(ErrorKind::NotEnoughFields("Box<TLObject>", 0)))?;
+        let seed = BoxTLObjectSeed { cmap: self.cmap, state: self.state, gzip_depth: self.gzip_depth, type_id: type_id };
+        let object = seq.next_element_seed(seed)?
+            .ok_or(errconv(ErrorKind::NotEnoughFields("Box<TLObject>", 1)))?;
+
+        Ok(object)
+    }
+}
 
-                    fn deserialize<D>(self, deserializer: D) -> Result<Box<TLObject>, D::Error>
-                        where D: Deserializer<'de>
-                    {
-                        let ctor = &(self.0).0.get(&self.1)
-                            .ok_or(errconv(ErrorKind::UnknownConstructorId("Box<TLObject>", self.1)))?.0;
+/// Reads the body of a boxed object whose constructor id is already
+/// known.
+struct BoxTLObjectSeed<'a> {
+    cmap: &'a TLConstructorsMap,
+    state: &'a LimitState,
+    gzip_depth: usize,
+    type_id: u32,
+}
 
-                        ctor(&mut ErasedDeserializer::erase(deserializer)).map_err(|e| D::Error::custom(e))
-                    }
-                }
+impl<'a, 'de> DeserializeSeed<'de> for BoxTLObjectSeed<'a> {
+    type Value = Box<TLObject>;
 
-                let type_id = seq.next_element()?
-                    .ok_or(errconv(ErrorKind::NotEnoughFields("Box<TLObject>", 0)))?;
-                let object = seq.next_element_seed(BoxTLObjectSeed(self.0, type_id))?
-                    .ok_or(errconv(ErrorKind::NotEnoughFields("Box<TLObject>", 1)))?;
+    fn deserialize<D>(self, deserializer: D) -> Result<Box<TLObject>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let cmap = self.cmap;
 
-                Ok(object)
+        if self.type_id == GZIP_PACKED_ID && cmap.inflate_gzip {
+            if self.gzip_depth >= cmap.max_gzip_depth {
+                return Err(self.state.exceed("gzip_packed nesting depth", cmap.max_gzip_depth));
             }
+
+            let packed_data = ByteBuf::deserialize(deserializer)?;
+            let unpacked = inflate(&packed_data, cmap.max_inflated_len).map_err(D::Error::custom)?;
+
+            // The packed object is boxed and may be packed again
+            let visitor = BoxTLObjectVisitor { cmap: cmap, state: self.state, gzip_depth: self.gzip_depth + 1 };
+            let mut unpacked_deserializer = serde_mtproto::Deserializer::new(&unpacked[..], None);
+            return LimitedDeserializer::new(&mut unpacked_deserializer, self.state)
+                .deserialize_tuple(2, visitor)
+                .map_err(D::Error::custom);
         }
 
-        deserializer.deserialize_tuple(2, BoxTLObjectVisitor(self))
+        let ctor = &cmap.ctors.get(&self.type_id)
+            .ok_or(errconv(ErrorKind::UnknownConstructorId("Box<TLObject>", self.type_id)))?.0;
+
+        ctor(&mut ErasedDeserializer::erase(deserializer)).map_err(|e| D::Error::custom(e))
     }
 }
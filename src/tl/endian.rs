@@ -0,0 +1,95 @@
+//! Integers with an explicit byte order.
+//!
+//! TL integers are little-endian, but some values embedded in `bytes`
+//! fields, like `pq` of `resPQ`, are big-endian. Wrapping them in these
+//! types makes the byte order part of the field type.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_mtproto::{self, MtProtoSized};
+
+
+/// A `u64` serialized in big-endian byte order.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BeU64(pub u64);
+
+/// A `u64` serialized in little-endian byte order, same as TL `long`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct LeU64(pub u64);
+
+impl BeU64 {
+    /// Read a number from its 8-byte big-endian representation.
+    pub fn from_bytes(bytes: [u8; 8]) -> BeU64 {
+        BeU64(BigEndian::read_u64(&bytes))
+    }
+
+    /// Return the 8-byte big-endian representation of the number.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        BigEndian::write_u64(&mut bytes, self.0);
+
+        bytes
+    }
+}
+
+impl LeU64 {
+    /// Read a number from its 8-byte little-endian representation.
+    pub fn from_bytes(bytes: [u8; 8]) -> LeU64 {
+        LeU64(LittleEndian::read_u64(&bytes))
+    }
+
+    /// Return the 8-byte little-endian representation of the number.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, self.0);
+
+        bytes
+    }
+}
+
+// TL serializes `u64` as little-endian, so swapping bytes beforehand
+// yields big-endian output on any host
+impl Serialize for BeU64 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_u64(self.0.swap_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for BeU64 {
+    fn deserialize<D>(deserializer: D) -> Result<BeU64, D::Error>
+        where D: Deserializer<'de>
+    {
+        u64::deserialize(deserializer).map(|n| BeU64(n.swap_bytes()))
+    }
+}
+
+impl MtProtoSized for BeU64 {
+    fn size_hint(&self) -> serde_mtproto::Result<usize> {
+        Ok(8)
+    }
+}
+
+impl Serialize for LeU64 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for LeU64 {
+    fn deserialize<D>(deserializer: D) -> Result<LeU64, D::Error>
+        where D: Deserializer<'de>
+    {
+        u64::deserialize(deserializer).map(LeU64)
+    }
+}
+
+impl MtProtoSized for LeU64 {
+    fn size_hint(&self) -> serde_mtproto::Result<usize> {
+        Ok(8)
+    }
+}
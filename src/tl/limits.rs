@@ -0,0 +1,424 @@
+//! Bounds on the shape of deserialized TL data.
+//!
+//! Vectors are prefixed with their element count and TL types may nest
+//! each other, so crafted input could make a reader loop for billions
+//! of elements or recurse until the stack overflows. The deserializer
+//! wrappers here check every sequence against `DeserializationLimits`
+//! and fail with `DeserializationLimitExceeded` as soon as one is
+//! exceeded.
+
+use std::cell::Cell;
+use std::fmt;
+
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, Error as DeError};
+use serde_mtproto;
+
+use error::{self, ErrorKind};
+
+
+/// Default maximum element count of a single vector.
+pub const DEFAULT_MAX_VECTOR_LEN: usize = 1 << 20;
+/// Default maximum nesting depth of vectors and constructors.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+
+/// Limits enforced while deserializing untrusted TL data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeserializationLimits {
+    /// Maximum element count of a single vector.
+    pub max_vector_len: usize,
+    /// Maximum nesting depth of vectors and constructors.
+    pub max_depth: usize,
+}
+
+impl Default for DeserializationLimits {
+    fn default() -> DeserializationLimits {
+        DeserializationLimits {
+            max_vector_len: DEFAULT_MAX_VECTOR_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+
+/// Deserializes a `T` from `bytes` like `serde_mtproto::from_bytes()`,
+/// but fails with `DeserializationLimitExceeded` if the data exceeds
+/// `limits`.
+pub fn from_bytes_limited<T>(bytes: &[u8],
+                             enum_variant_ids: Option<&[&'static str]>,
+                             limits: DeserializationLimits)
+                            -> error::Result<T>
+    where T: DeserializeOwned
+{
+    let state = LimitState::new(limits);
+    let mut deserializer = serde_mtproto::Deserializer::new(bytes, enum_variant_ids);
+    let result = <T as Deserialize>::deserialize(LimitedDeserializer::new(&mut deserializer, &state));
+
+    state.finish(result)
+}
+
+
+/// Progress of a single deserialization against its limits.
+///
+/// Errors raised by the wrappers pass through serde as custom errors,
+/// so the exceeded limit is also recorded here to be reported with its
+/// proper `ErrorKind`.
+#[derive(Debug)]
+pub(crate) struct LimitState {
+    limits: DeserializationLimits,
+    depth: Cell<usize>,
+    exceeded: Cell<Option<(&'static str, usize)>>,
+}
+
+impl LimitState {
+    pub(crate) fn new(limits: DeserializationLimits) -> LimitState {
+        LimitState {
+            limits: limits,
+            depth: Cell::new(0),
+            exceeded: Cell::new(None),
+        }
+    }
+
+    /// Converts the result of a deserialization, replacing the error with
+    /// `DeserializationLimitExceeded` if a limit caused it.
+    pub(crate) fn finish<T, E: Into<error::Error>>(&self, result: Result<T, E>) -> error::Result<T> {
+        match (result, self.exceeded.get()) {
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some((limit_name, limit))) => bail!(ErrorKind::DeserializationLimitExceeded(limit_name, limit)),
+            (Err(e), None) => Err(e.into()),
+        }
+    }
+
+    /// Records that the limit `limit_name` was exceeded and returns an
+    /// error to fail deserialization with.
+    pub(crate) fn exceed<E: DeError>(&self, limit_name: &'static str, limit: usize) -> E {
+        self.exceeded.set(Some((limit_name, limit)));
+        E::custom(error::Error::from(ErrorKind::DeserializationLimitExceeded(limit_name, limit)))
+    }
+
+    fn check_len<E: DeError>(&self, len: usize) -> Result<(), E> {
+        if len > self.limits.max_vector_len {
+            return Err(self.exceed("vector length", self.limits.max_vector_len));
+        }
+
+        Ok(())
+    }
+
+    fn enter<E: DeError>(&self) -> Result<DepthGuard, E> {
+        let depth = self.depth.get() + 1;
+        if depth > self.limits.max_depth {
+            return Err(self.exceed("nesting depth", self.limits.max_depth));
+        }
+
+        self.depth.set(depth);
+        Ok(DepthGuard(self))
+    }
+}
+
+/// Leaves a nesting level when dropped, whether the nested data was
+/// read successfully or not.
+struct DepthGuard<'s>(&'s LimitState);
+
+impl<'s> Drop for DepthGuard<'s> {
+    fn drop(&mut self) {
+        self.0.depth.set(self.0.depth.get() - 1);
+    }
+}
+
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, D::Error>
+                where V: de::Visitor<'de>
+            {
+                self.inner.$method($($arg,)* LimitedVisitor::new(visitor, self.state))
+            }
+        )*
+    };
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method<E: DeError>(self, v: $ty) -> Result<V::Value, E> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+
+/// Wraps a deserializer so that every visitor it calls is limited.
+pub(crate) struct LimitedDeserializer<'s, D> {
+    inner: D,
+    state: &'s LimitState,
+}
+
+impl<'s, D> LimitedDeserializer<'s, D> {
+    pub(crate) fn new(inner: D, state: &'s LimitState) -> LimitedDeserializer<'s, D> {
+        LimitedDeserializer {
+            inner: inner,
+            state: state,
+        }
+    }
+}
+
+impl<'s, 'de, D: Deserializer<'de>> Deserializer<'de> for LimitedDeserializer<'s, D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_option();
+        deserialize_unit();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq();
+        deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+        deserialize_ignored_any();
+    }
+}
+
+/// Wraps a visitor so that sequences, maps and enums it visits are
+/// limited.
+struct LimitedVisitor<'s, V> {
+    inner: V,
+    state: &'s LimitState,
+}
+
+impl<'s, V> LimitedVisitor<'s, V> {
+    fn new(inner: V, state: &'s LimitState) -> LimitedVisitor<'s, V> {
+        LimitedVisitor {
+            inner: inner,
+            state: state,
+        }
+    }
+}
+
+impl<'s, 'de, V: de::Visitor<'de>> de::Visitor<'de> for LimitedVisitor<'s, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(f)
+    }
+
+    forward_visit! {
+        visit_bool(bool);
+        visit_i8(i8);
+        visit_i16(i16);
+        visit_i32(i32);
+        visit_i64(i64);
+        visit_u8(u8);
+        visit_u16(u16);
+        visit_u32(u32);
+        visit_u64(u64);
+        visit_f32(f32);
+        visit_f64(f64);
+        visit_char(char);
+        visit_str(&str);
+        visit_borrowed_str(&'de str);
+        visit_string(String);
+        visit_bytes(&[u8]);
+        visit_borrowed_bytes(&'de [u8]);
+        visit_byte_buf(Vec<u8>);
+    }
+
+    fn visit_none<E: DeError>(self) -> Result<V::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: DeError>(self) -> Result<V::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<V::Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        self.inner.visit_some(LimitedDeserializer::new(deserializer, self.state))
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<V::Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        self.inner.visit_newtype_struct(LimitedDeserializer::new(deserializer, self.state))
+    }
+
+    fn visit_seq<A>(self, seq: A) -> Result<V::Value, A::Error>
+        where A: de::SeqAccess<'de>
+    {
+        // The element count is known upfront for TL vectors
+        if let Some(len) = seq.size_hint() {
+            self.state.check_len::<A::Error>(len)?;
+        }
+        let _guard = self.state.enter::<A::Error>()?;
+
+        self.inner.visit_seq(LimitedSeqAccess { inner: seq, state: self.state, count: 0 })
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<V::Value, A::Error>
+        where A: de::MapAccess<'de>
+    {
+        if let Some(len) = map.size_hint() {
+            self.state.check_len::<A::Error>(len)?;
+        }
+        let _guard = self.state.enter::<A::Error>()?;
+
+        self.inner.visit_map(LimitedMapAccess { inner: map, state: self.state, count: 0 })
+    }
+
+    fn visit_enum<A>(self, data: A) -> Result<V::Value, A::Error>
+        where A: de::EnumAccess<'de>
+    {
+        self.inner.visit_enum(LimitedEnumAccess { inner: data, state: self.state })
+    }
+}
+
+/// Wraps a seed so that the deserializer it is given is limited.
+struct LimitedSeed<'s, S> {
+    inner: S,
+    state: &'s LimitState,
+}
+
+impl<'s, S> LimitedSeed<'s, S> {
+    fn new(inner: S, state: &'s LimitState) -> LimitedSeed<'s, S> {
+        LimitedSeed {
+            inner: inner,
+            state: state,
+        }
+    }
+}
+
+impl<'s, 'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for LimitedSeed<'s, S> {
+    type Value = S::Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<S::Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        self.inner.deserialize(LimitedDeserializer::new(deserializer, self.state))
+    }
+}
+
+/// Counts elements of a sequence in case its length isn't known
+/// upfront.
+struct LimitedSeqAccess<'s, A> {
+    inner: A,
+    state: &'s LimitState,
+    count: usize,
+}
+
+impl<'s, 'de, A: de::SeqAccess<'de>> de::SeqAccess<'de> for LimitedSeqAccess<'s, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>, A::Error>
+        where S: DeserializeSeed<'de>
+    {
+        self.count += 1;
+        self.state.check_len::<A::Error>(self.count)?;
+
+        self.inner.next_element_seed(LimitedSeed::new(seed, self.state))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+struct LimitedMapAccess<'s, A> {
+    inner: A,
+    state: &'s LimitState,
+    count: usize,
+}
+
+impl<'s, 'de, A: de::MapAccess<'de>> de::MapAccess<'de> for LimitedMapAccess<'s, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error>
+        where K: DeserializeSeed<'de>
+    {
+        self.count += 1;
+        self.state.check_len::<A::Error>(self.count)?;
+
+        self.inner.next_key_seed(LimitedSeed::new(seed, self.state))
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value, A::Error>
+        where T: DeserializeSeed<'de>
+    {
+        self.inner.next_value_seed(LimitedSeed::new(seed, self.state))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+struct LimitedEnumAccess<'s, A> {
+    inner: A,
+    state: &'s LimitState,
+}
+
+impl<'s, 'de, A: de::EnumAccess<'de>> de::EnumAccess<'de> for LimitedEnumAccess<'s, A> {
+    type Error = A::Error;
+    type Variant = LimitedVariantAccess<'s, A::Variant>;
+
+    fn variant_seed<S>(self, seed: S) -> Result<(S::Value, Self::Variant), A::Error>
+        where S: DeserializeSeed<'de>
+    {
+        let state = self.state;
+
+        self.inner.variant_seed(LimitedSeed::new(seed, state))
+            .map(|(value, variant)| (value, LimitedVariantAccess { inner: variant, state: state }))
+    }
+}
+
+struct LimitedVariantAccess<'s, A> {
+    inner: A,
+    state: &'s LimitState,
+}
+
+impl<'s, 'de, A: de::VariantAccess<'de>> de::VariantAccess<'de> for LimitedVariantAccess<'s, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.inner.unit_variant()
+    }
+
+    fn newtype_variant_seed<S>(self, seed: S) -> Result<S::Value, A::Error>
+        where S: DeserializeSeed<'de>
+    {
+        self.inner.newtype_variant_seed(LimitedSeed::new(seed, self.state))
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value, A::Error>
+        where V: de::Visitor<'de>
+    {
+        self.inner.tuple_variant(len, LimitedVisitor::new(visitor, self.state))
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, A::Error>
+        where V: de::Visitor<'de>
+    {
+        self.inner.struct_variant(fields, LimitedVisitor::new(visitor, self.state))
+    }
+}
@@ -1,5 +1,13 @@
 //! Definitions to represent Type Language concepts in Rust.
 
+pub mod boxed;
 pub mod dynamic;
+pub mod endian;
+pub mod limits;
+pub mod raw;
 
+pub use self::boxed::{FromTlBytes, read_boxed};
 pub use self::dynamic::{TLConstructorsMap, TLObject};
+pub use self::endian::{BeU64, LeU64};
+pub use self::limits::{DeserializationLimits, from_bytes_limited};
+pub use self::raw::RawObject;
@@ -0,0 +1,65 @@
+//! Objects which are already serialized, e.g. captured from traffic.
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::ser::{Serialize, Serializer};
+use serde_mtproto::{self, Identifiable, MtProtoSized, UnsizedByteBuf, size_hint_from_unsized_byte_seq_len};
+
+use error::{self, ErrorKind};
+
+
+/// A boxed TL object kept as raw bytes.
+///
+/// It can be used as a message body like any other `TLObject`, so that
+/// captured requests or constructors unknown to the schema can be sent
+/// without going through their own `Serialize` impl.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawObject {
+    type_id: u32,
+    body: Vec<u8>,
+}
+
+impl RawObject {
+    /// Wraps serialized boxed object `bytes`, starting with its
+    /// constructor id.
+    ///
+    /// TL objects are aligned to 4 bytes, so other lengths are rejected.
+    pub fn new(bytes: &[u8]) -> error::Result<RawObject> {
+        if bytes.len() < 4 || bytes.len() % 4 != 0 {
+            bail!(ErrorKind::InvalidRawObjectLength(bytes.len()));
+        }
+
+        Ok(RawObject {
+            type_id: LittleEndian::read_u32(&bytes[0..4]),
+            body: bytes[4..].to_vec(),
+        })
+    }
+
+    /// Returns the serialized object without its constructor id.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+impl Identifiable for RawObject {
+    fn type_id(&self) -> u32 {
+        self.type_id
+    }
+
+    fn enum_variant_id(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl Serialize for RawObject {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        UnsizedByteBuf::new(self.body.clone()).serialize(serializer)
+    }
+}
+
+impl MtProtoSized for RawObject {
+    fn size_hint(&self) -> serde_mtproto::Result<usize> {
+        size_hint_from_unsized_byte_seq_len(self.body.len())
+    }
+}
@@ -0,0 +1,18 @@
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+
+
+use mtproto::rpc::AppInfo;
+
+
+#[test]
+fn test_json_and_toml_agree() {
+    let from_toml = AppInfo::read_from_toml_str("api_id = 9000\napi_hash = \"random text\"\n").unwrap();
+    let from_json = AppInfo::read_from_json_str(r#"{ "api_id": 9000, "api_hash": "random text" }"#).unwrap();
+
+    assert_eq!(from_toml, AppInfo::new(9000, "random text".to_owned()));
+    assert_eq!(from_json, from_toml);
+
+    assert!(AppInfo::read_from_json_str(r#"{ "api_id": 9000 }"#).is_err());
+}
@@ -0,0 +1,151 @@
+extern crate chrono;
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+extern crate serde_mtproto;
+
+
+use std::net::SocketAddr;
+
+use chrono::{Duration, TimeZone, Utc};
+use mtproto::rpc::config::{self, DcTable};
+use mtproto::schema::{Config, DcOption};
+use serde_mtproto::Boxed;
+
+
+fn dc_option(flags: i32, id: i32, ip_address: &str, port: i32) -> DcOption {
+    DcOption {
+        flags: flags,
+        ipv6: (),
+        media_only: (),
+        tcpo_only: (),
+        cdn: (),
+        static_: (),
+        id: id,
+        ip_address: ip_address.to_owned(),
+        port: port,
+    }
+}
+
+fn config(date: i32, expires: i32, dc_options: Vec<DcOption>) -> Config {
+    Config {
+        flags: 0,
+        phonecalls_enabled: (),
+        date: date,
+        expires: expires,
+        test_mode: false,
+        this_dc: 2,
+        dc_options: Boxed::new(dc_options),
+        chat_size_max: 200,
+        megagroup_size_max: 10000,
+        forwarded_count_max: 100,
+        online_update_period_ms: 120000,
+        offline_blur_timeout_ms: 5000,
+        offline_idle_timeout_ms: 30000,
+        online_cloud_timeout_ms: 300000,
+        notify_cloud_delay_ms: 30000,
+        notify_default_delay_ms: 1500,
+        chat_big_size: 10,
+        push_chat_period_ms: 60000,
+        push_chat_limit: 2,
+        saved_gifs_limit: 200,
+        edit_time_limit: 172800,
+        rating_e_decay: 2419200,
+        stickers_recent_limit: 30,
+        tmp_sessions: None,
+        pinned_dialogs_count_max: 5,
+        call_receive_timeout_ms: 20000,
+        call_ring_timeout_ms: 90000,
+        call_connect_timeout_ms: 30000,
+        call_packet_timeout_ms: 10000,
+        me_url_prefix: "https://t.me/".to_owned(),
+        suggested_lang_code: None,
+        lang_pack_version: None,
+        disabled_features: Boxed::new(vec![]),
+    }
+}
+
+#[test]
+fn test_dc_table() {
+    let table = DcTable::from_dc_options(&[
+        dc_option(0, 1, "149.154.175.50", 443),
+        dc_option(0b11, 2, "2001:67c:4e8:f002::a", 443),
+        dc_option(0, 2, "149.154.167.51", 443),
+    ]).unwrap();
+
+    let dc1: SocketAddr = "149.154.175.50:443".parse().unwrap();
+    let dc2: SocketAddr = "149.154.167.51:443".parse().unwrap();
+    let dc2_media_ipv6: SocketAddr = "[2001:67c:4e8:f002::a]:443".parse().unwrap();
+
+    assert_eq!(table.get(1, false, false), &[dc1]);
+    assert_eq!(table.get(2, false, false), &[dc2]);
+    assert_eq!(table.get(2, true, true), &[dc2_media_ipv6]);
+    assert!(table.get(1, true, false).is_empty());
+
+    assert!(DcTable::from_dc_options(&[dc_option(0, 3, "not an ip", 443)]).is_err());
+}
+
+#[test]
+fn test_dc_table_prefer_ipv6() {
+    let table = DcTable::from_dc_options(&[
+        dc_option(0, 1, "149.154.175.50", 443),
+        dc_option(0b1, 1, "2001:b28:f23d:f001::a", 443),
+        dc_option(0, 2, "149.154.167.51", 443),
+    ]).unwrap();
+
+    let dc1: SocketAddr = "149.154.175.50:443".parse().unwrap();
+    let dc1_ipv6: SocketAddr = "[2001:b28:f23d:f001::a]:443".parse().unwrap();
+    let dc2: SocketAddr = "149.154.167.51:443".parse().unwrap();
+
+    assert_eq!(table.preferred_addr(1, false, true), Some(dc1_ipv6));
+    assert_eq!(table.preferred_addr(1, false, false), Some(dc1));
+    // Falls back to IPv4 if there are no IPv6 addresses
+    assert_eq!(table.preferred_addr(2, false, true), Some(dc2));
+    assert_eq!(table.preferred_addr(3, false, true), None);
+}
+
+#[test]
+fn test_true_flag_accessors() {
+    let mut dc_option = dc_option(0, 1, "149.154.175.50", 443);
+    let len = serde_mtproto::to_bytes(&dc_option).unwrap().len();
+    assert!(!dc_option.is_ipv6() && !dc_option.is_media_only() && !dc_option.is_static());
+
+    dc_option.set_media_only(true);
+    dc_option.set_static(true);
+    assert_eq!(dc_option.flags, 0b10010);
+    assert!(dc_option.is_media_only() && dc_option.is_static() && !dc_option.is_ipv6());
+    // `true` fields are set by their bit alone
+    assert_eq!(serde_mtproto::to_bytes(&dc_option).unwrap().len(), len);
+
+    dc_option.set_media_only(false);
+    assert_eq!(dc_option.flags, 0b10000);
+    assert!(!dc_option.is_media_only());
+}
+
+#[test]
+fn test_resolve_localhost() {
+    let addr = config::resolve_host("localhost", 443, false).unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 443);
+
+    assert!(config::resolve_host("nonexistent.invalid", 443, false).is_err());
+}
+
+#[test]
+fn test_config_expiry() {
+    let now = Utc.timestamp(0x5a00_0000, 0);
+
+    let expired = config(0x5a00_0000 - 7200, 0x5a00_0000 - 3600, vec![]);
+    assert!(expired.is_expired(now));
+    assert_eq!(expired.refresh_in(now), Duration::zero());
+
+    let fresh = config(0x5a00_0000, 0x5a00_0000 + 3600, vec![dc_option(0, 2, "149.154.167.51", 443)]);
+    assert!(!fresh.is_expired(now));
+    assert_eq!(fresh.refresh_in(now), Duration::seconds(3600));
+
+    let table = DcTable::from_config(&fresh).unwrap();
+    assert_eq!(table.expires_at(), Some(Utc.timestamp(0x5a00_0000 + 3600, 0)));
+    assert!(!table.is_expired(now));
+    assert!(table.is_expired(now + Duration::seconds(3600)));
+    assert!(!DcTable::new().is_expired(now));
+}
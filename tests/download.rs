@@ -0,0 +1,61 @@
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+
+
+use mtproto::rpc::download::{self, FileDownload, MAX_CHUNK_SIZE};
+use mtproto::schema::{self, InputFileLocation};
+
+
+fn location() -> InputFileLocation {
+    InputFileLocation::inputFileLocation(schema::inputFileLocation {
+        volume_id: 0x7357,
+        local_id: 1,
+        secret: 0x5ec7e7,
+    })
+}
+
+fn chunk(bytes: &[u8]) -> schema::upload::File {
+    schema::upload::File::file(schema::upload::file {
+        type_: schema::storage::FileType::filePartial,
+        mtime: 0,
+        bytes: bytes.to_vec().into(),
+    })
+}
+
+#[test]
+fn test_file_download() {
+    let file: Vec<u8> = (0..2 * 1024 + 100).map(|i| i as u8).collect();
+    let mut download = FileDownload::new(Vec::new(), location(), 1024).unwrap();
+
+    for (i, bytes) in file.chunks(1024).enumerate() {
+        let request = download.next_request().unwrap();
+        assert_eq!(request.offset, i as i32 * 1024);
+        assert_eq!(request.limit, 1024);
+
+        download.process_chunk(chunk(bytes)).unwrap();
+    }
+
+    assert!(download.is_finished());
+    assert!(download.next_request().is_none());
+    assert_eq!(download.offset(), file.len());
+    assert_eq!(download.into_writer(), file);
+}
+
+#[test]
+fn test_file_download_invalid_chunks() {
+    assert!(FileDownload::new(Vec::new(), location(), 0).is_err());
+    assert!(FileDownload::new(Vec::new(), location(), 1000).is_err());
+    assert!(FileDownload::new(Vec::new(), location(), 3 * 1024).is_err());
+    assert!(FileDownload::new(Vec::new(), location(), 2 * MAX_CHUNK_SIZE).is_err());
+
+    let mut download = FileDownload::new(Vec::new(), location(), 1024).unwrap();
+    assert!(download.process_chunk(chunk(&[0; 2048])).is_err());
+}
+
+#[test]
+fn test_file_migrate_dc() {
+    assert_eq!(download::file_migrate_dc("FILE_MIGRATE_4"), Some(4));
+    assert_eq!(download::file_migrate_dc("FILE_MIGRATE_"), None);
+    assert_eq!(download::file_migrate_dc("PHONE_MIGRATE_4"), None);
+}
@@ -0,0 +1,384 @@
+extern crate extprim;
+extern crate mtproto;
+extern crate openssl;
+#[macro_use]
+extern crate pretty_assertions;
+extern crate rand;
+extern crate serde_mtproto;
+
+
+use std::str;
+
+use extprim::i128::i128;
+use mtproto::rpc::encryption::{asymm, dh, hash, msg_key, AesParams, AuthKey, DhGenOutcome, Nonce128, Nonce256,
+                               ProtocolVersion, PublicKeySet, Sender};
+use mtproto::{schema, ErrorKind};
+use serde_mtproto::Boxed;
+use mtproto::rpc::encryption::obfuscation::{self, INTERMEDIATE_TAG};
+use openssl::symm;
+use rand::Rng;
+
+
+#[test]
+fn test_obfuscation_init_frame() {
+    for _ in 0..100 {
+        let (init, mut cipher) = obfuscation::generate_init_frame(INTERMEDIATE_TAG, None).unwrap();
+
+        assert!(init[0] != 0xef);
+        assert!(&init[0..4] != b"HEAD" && &init[0..4] != b"POST" && &init[0..4] != b"GET ");
+        assert!(&init[0..4] != &INTERMEDIATE_TAG[..]);
+        assert!(&init[4..8] != &[0; 4][..]);
+        assert!(obfuscation::is_valid_init_frame(&init));
+
+        // Act as the server: it decrypts client traffic with the key and IV from the frame
+        let mut server_decryptor = symm::Crypter::new(
+            symm::Cipher::aes_256_ctr(), symm::Mode::Decrypt, &init[8..40], Some(&init[40..56])).unwrap();
+
+        let mut decrypted_init = vec![0; 64 + 1];
+        server_decryptor.update(&init, &mut decrypted_init).unwrap();
+        assert_eq!(&decrypted_init[56..60], &INTERMEDIATE_TAG[..]);
+
+        let encrypted = cipher.encrypt(b"payload").unwrap();
+        let mut decrypted = vec![0; encrypted.len() + 1];
+        let len = server_decryptor.update(&encrypted, &mut decrypted).unwrap();
+        assert_eq!(&decrypted[..len], b"payload");
+    }
+}
+
+const DH_PRIME_HEX: &str = "\
+    c71caeb9c6b1c9048e6c522f70f13f73980d40238e3e21c14934d037563d930f\
+    48198a0aa7c14058229493d22530f4dbfa336f6e0ac925139543aed44cce7c37\
+    20fd51f69458705ac68cd4fe6b6b13abdc9746512969328454f18faf8c595f64\
+    2477fe96bb2a941d5bcd1d4ac8cc49880708fa9b378e3c4f3a9060bee67cf9a4\
+    a4a695811051907e162753b56b0f6b410dba74d8a84b2a14b3144e0ef1284754\
+    fd17ed950d5965b4b9dd46582db1178d169c6bc465b0d6ff9ca3928fef5b9ae4\
+    e418fc15e83ebea0f87fa9ff5eed70050ded2849f47bf959d956850ce929851f\
+    0d8115f635b105ee2e4e15d04b2454bf6f4fadf034b10403119cd8e3b92fcc5b";
+
+fn dh_prime() -> Vec<u8> {
+    DH_PRIME_HEX.as_bytes().chunks(2)
+        .map(|hex| u8::from_str_radix(str::from_utf8(hex).unwrap(), 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_check_dh_params() {
+    let dh_prime = dh_prime();
+    let mut g_a = vec![0; 256];
+    g_a[0] = 0x80;
+
+    asymm::check_dh_params(3, &dh_prime, &g_a).unwrap();
+    asymm::check_dh_params(4, &dh_prime, &g_a).unwrap();
+
+    // dh_prime = 3 (mod 8), so 2 doesn't generate the right subgroup
+    assert!(asymm::check_dh_params(2, &dh_prime, &g_a).is_err());
+    assert!(asymm::check_dh_params(3, &dh_prime, &[2]).is_err());
+
+    // Not even prime
+    let mut unsafe_prime = dh_prime.clone();
+    unsafe_prime[255] -= 2;
+    assert!(asymm::check_dh_params(3, &unsafe_prime, &g_a).is_err());
+}
+
+#[test]
+fn test_hash_vectors() {
+    assert_eq!(hash::sha1(b"abc").unwrap(), [
+        0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e,
+        0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+    ]);
+    assert_eq!(hash::sha256(b"abc").unwrap(), [
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+        0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+    ]);
+}
+
+fn msg_key_to_i128(msg_key: [u8; 16]) -> i128 {
+    let mut lo = 0u64;
+    let mut hi = 0u64;
+    for i in 0..8 {
+        lo |= (msg_key[i] as u64) << (8 * i);
+        hi |= (msg_key[8 + i] as u64) << (8 * i);
+    }
+
+    i128::from_parts(hi as i64, lo)
+}
+
+#[test]
+fn test_msg_key() {
+    let raw_key: Vec<u8> = (0..256).map(|i| i as u8).collect();
+    let auth_key = AuthKey::new(&raw_key).unwrap();
+    let plaintext: Vec<u8> = (0..40).map(|i| (i * 7) as u8).collect();
+
+    // MTProto 1.0 hashes the data alone
+    let v1 = msg_key::compute_v1(&plaintext).unwrap();
+    assert_eq!(&v1[..], &hash::sha1(&plaintext).unwrap()[4..20]);
+    let (_, message_key, _) = auth_key.encrypt_message_bytes_as(ProtocolVersion::V1, Sender::Client, &plaintext).unwrap();
+    assert_eq!(message_key, msg_key_to_i128(v1));
+
+    // MTProto 2.0 mixes in a part of the key depending on the sender
+    let v2 = msg_key::compute_v2(&auth_key, &plaintext, Sender::Client).unwrap();
+    let large = hash::sha256(&[&raw_key[88..120], &plaintext[..]].concat()).unwrap();
+    assert_eq!(&v2[..], &large[8..24]);
+    assert!(msg_key::compute_v2(&auth_key, &plaintext, Sender::Server).unwrap() != v2);
+
+    // Round trip through encryption, where the key covers padding too
+    let (auth_key_id, message_key, encrypted) =
+        auth_key.encrypt_message_bytes_as(ProtocolVersion::V2, Sender::Server, &plaintext).unwrap();
+    let padded = auth_key.decrypt_message_bytes_as(ProtocolVersion::V2, Sender::Server, auth_key_id, message_key, &encrypted)
+        .unwrap();
+    let v2 = msg_key::compute_v2(&auth_key, &padded, Sender::Server).unwrap();
+    assert_eq!(msg_key_to_i128(v2), message_key);
+    msg_key::verify_v2(&auth_key, &padded, Sender::Server, &v2).unwrap();
+
+    match *msg_key::verify_v2(&auth_key, &padded, Sender::Client, &v2).unwrap_err().kind() {
+        ErrorKind::MessageKeyMismatch => (),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    // MTProto 1.0 verification needs message_data_length to strip padding
+    let mut message = vec![0; 32];
+    message[28] = 8;
+    message.extend(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    let (auth_key_id, message_key, mut encrypted) =
+        auth_key.encrypt_message_bytes_as(ProtocolVersion::V1, Sender::Server, &message).unwrap();
+    let padded = auth_key.decrypt_message_bytes_as(ProtocolVersion::V1, Sender::Server, auth_key_id, message_key, &encrypted)
+        .unwrap();
+    let v1 = msg_key::compute_v1(&message).unwrap();
+    msg_key::verify_v1(&padded, &v1).unwrap();
+
+    // Corrupt the last block, leaving message_data_length intact
+    let last = encrypted.len() - 1;
+    encrypted[last] ^= 0xff;
+    match *auth_key.decrypt_message_bytes_as(ProtocolVersion::V1, Sender::Server, auth_key_id, message_key, &encrypted)
+        .unwrap_err().kind()
+    {
+        ErrorKind::MessageKeyMismatch => (),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+fn dh_aes_params() -> AesParams {
+    AesParams::from_nonces(i128::new(0x1234_5678), (i128::new(-42), i128::new(1 << 40))).unwrap()
+}
+
+#[test]
+fn test_encrypt_client_dh_inner_data_layout() {
+    let inner = schema::Client_DH_Inner_Data {
+        nonce: i128::new(1),
+        server_nonce: i128::new(2),
+        retry_id: 0,
+        g_b: vec![0xab; 256].into(),
+    };
+    let serialized = serde_mtproto::to_bytes(&Boxed::new(inner.clone())).unwrap();
+
+    let encrypted = dh::encrypt_client_dh_inner_data(inner, dh_aes_params()).unwrap();
+    assert_eq!(encrypted.len() % 16, 0);
+    assert!(encrypted.len() >= 20 + serialized.len() && encrypted.len() < 20 + serialized.len() + 16);
+
+    let decrypted = dh_aes_params().ige_decrypt(&encrypted).unwrap();
+    assert_eq!(&decrypted[0..20], &hash::sha1(&serialized).unwrap()[..]);
+    assert_eq!(&decrypted[20..20 + serialized.len()], serialized.as_slice());
+}
+
+#[test]
+fn test_decrypt_server_dh_inner_data() {
+    let inner = schema::Server_DH_inner_data {
+        nonce: i128::new(1),
+        server_nonce: i128::new(2),
+        g: 3,
+        dh_prime: dh_prime().into(),
+        g_a: vec![0xcd; 256].into(),
+        server_time: 1_500_000_000,
+    };
+    let serialized = serde_mtproto::to_bytes(&Boxed::new(inner.clone())).unwrap();
+
+    let encrypted = dh_aes_params().ige_encrypt_random_padded(&serialized).unwrap();
+    assert_eq!(dh::decrypt_server_dh_inner_data(&encrypted, dh_aes_params()).unwrap(), inner);
+
+    // Corrupt the embedded hash
+    let mut plain = dh_aes_params().ige_decrypt(&encrypted).unwrap();
+    plain[0] ^= 0xff;
+    let corrupted = dh_aes_params().ige_encrypt(&plain, false).unwrap();
+    assert!(dh::decrypt_server_dh_inner_data(&corrupted, dh_aes_params()).is_err());
+}
+
+#[test]
+fn test_check_dh_gen_answer() {
+    let new_nonce = (i128::new(-42), i128::new(1 << 40));
+    let auth_key = AuthKey::new(&[0x5a; 256]).unwrap();
+    let hash = |number| dh::calculate_new_nonce_hash(new_nonce, number, auth_key.aux_hash()).unwrap();
+
+    let dh_gen_ok = |new_nonce_hash1| schema::Set_client_DH_params_answer::dh_gen_ok(schema::dh_gen_ok {
+        nonce: i128::new(1),
+        server_nonce: i128::new(2),
+        new_nonce_hash1: new_nonce_hash1,
+    });
+    let dh_gen_retry = |new_nonce_hash2| schema::Set_client_DH_params_answer::dh_gen_retry(schema::dh_gen_retry {
+        nonce: i128::new(1),
+        server_nonce: i128::new(2),
+        new_nonce_hash2: new_nonce_hash2,
+    });
+    let dh_gen_fail = |new_nonce_hash3| schema::Set_client_DH_params_answer::dh_gen_fail(schema::dh_gen_fail {
+        nonce: i128::new(1),
+        server_nonce: i128::new(2),
+        new_nonce_hash3: new_nonce_hash3,
+    });
+
+    assert_eq!(dh::check_dh_gen_answer(&dh_gen_ok(hash(1)), new_nonce, &auth_key).unwrap(),
+        DhGenOutcome::Ok);
+    assert_eq!(dh::check_dh_gen_answer(&dh_gen_retry(hash(2)), new_nonce, &auth_key).unwrap(),
+        DhGenOutcome::Retry { retry_id: auth_key.aux_hash() });
+    assert!(dh::check_dh_gen_answer(&dh_gen_fail(hash(3)), new_nonce, &auth_key).is_err());
+
+    // Hashes are bound to their constructor
+    assert!(dh::check_dh_gen_answer(&dh_gen_ok(hash(2)), new_nonce, &auth_key).is_err());
+    assert!(dh::check_dh_gen_answer(&dh_gen_retry(hash(1)), new_nonce, &auth_key).is_err());
+}
+
+#[test]
+fn test_check_server_dh_params() {
+    let (nonce, server_nonce) = (i128::new(1), i128::new(2));
+
+    let ok = |nonce, server_nonce| schema::Server_DH_Params::server_DH_params_ok(schema::server_DH_params_ok {
+        nonce: nonce,
+        server_nonce: server_nonce,
+        encrypted_answer: vec![0; 16].into(),
+    });
+    let fail = schema::Server_DH_Params::server_DH_params_fail(schema::server_DH_params_fail {
+        nonce: nonce,
+        server_nonce: server_nonce,
+        new_nonce_hash: i128::new(3),
+    });
+
+    assert_eq!(dh::check_server_dh_params(ok(nonce, server_nonce), nonce, server_nonce).unwrap().nonce, nonce);
+
+    match *dh::check_server_dh_params(ok(i128::new(7), server_nonce), nonce, server_nonce).unwrap_err().kind() {
+        ErrorKind::NonceMismatch(expected, found) => assert_eq!((expected, found), (nonce, i128::new(7))),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    match *dh::check_server_dh_params(ok(nonce, i128::new(7)), nonce, server_nonce).unwrap_err().kind() {
+        ErrorKind::ServerNonceMismatch(expected, found) => assert_eq!((expected, found), (server_nonce, i128::new(7))),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    match *dh::check_server_dh_params(fail, nonce, server_nonce).unwrap_err().kind() {
+        ErrorKind::DhParamsInvalid => (),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_auth_key_id_and_initial_server_salt() {
+    let auth_key = AuthKey::new(&[0x5a; 256]).unwrap();
+    let sha1 = hash::sha1(&[0x5a; 256]).unwrap();
+    // The lower 64 bits of SHA1 of the key
+    let key_id = sha1[12..20].iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64) as i64;
+    assert_eq!(auth_key.key_id(), key_id);
+
+    let new_nonce = (i128::from_parts(0x1111, 0x0123_4567_89ab_cdef), i128::from_parts(0x3333, 0x4444));
+    let server_nonce = i128::from_parts(0x2222, 0xfedc_ba98_7654_3210);
+    assert_eq!(dh::initial_server_salt(new_nonce, server_nonce), -1);
+
+    let server_nonce = i128::from_parts(0x2222, 0x0123_4567_89ab_cdef);
+    assert_eq!(dh::initial_server_salt(new_nonce, server_nonce), 0);
+}
+
+#[test]
+fn test_nonces() {
+    let mut rng = rand::thread_rng();
+    let nonce: Nonce128 = rng.gen();
+    assert_eq!(Nonce128::from(nonce.to_i128()), nonce);
+    assert!(nonce.expect_match(&nonce.clone()).is_ok());
+
+    let other = Nonce128::from(i128::new(7));
+    assert_eq!(other.as_bytes(), &[7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    match *nonce.expect_match(&other).unwrap_err().kind() {
+        ErrorKind::NonceMismatch(expected, found) => assert_eq!((expected, found), (nonce.to_i128(), i128::new(7))),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    let serialized = serde_mtproto::to_bytes(&other).unwrap();
+    assert_eq!(&serialized[..], &other.as_bytes()[..]);
+    assert_eq!(serde_mtproto::from_bytes::<Nonce128>(&serialized, None).unwrap(), other);
+
+    let new_nonce: Nonce256 = rng.gen();
+    assert_eq!(Nonce256::from(new_nonce.to_i128_pair()), new_nonce);
+    assert!(Nonce256::from((i128::new(1), i128::new(2))) != Nonce256::from((i128::new(2), i128::new(1))));
+
+    let serialized = serde_mtproto::to_bytes(&new_nonce).unwrap();
+    assert_eq!(&serialized[..], &new_nonce.as_bytes()[..]);
+    assert_eq!(serde_mtproto::from_bytes::<Nonce256>(&serialized, None).unwrap(), new_nonce);
+}
+
+#[test]
+fn test_pq_from_bytes() {
+    assert_eq!(asymm::pq_from_bytes(&[0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81]).unwrap(), 0x17ed48941a08f981);
+    assert_eq!(asymm::pq_from_bytes(&[0x53, 0x91, 0x1d, 0x43]).unwrap(), 0x53911d43);
+    assert_eq!(asymm::decompose_pq(asymm::pq_from_bytes(&[0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81]).unwrap()).unwrap(),
+        (0x494c553b, 0x53911073));
+    assert!(asymm::check_pq_factors(0x17ed48941a08f981, 0x494c553b, 0x53911073).is_ok());
+
+    match *asymm::check_pq_factors(0x17ed48941a08f981, 0x494c553b, 0x53911075).unwrap_err().kind() {
+        ErrorKind::FactorizationFailureWrongFactors(pq, p, q) => assert_eq!((pq, p, q), (0x17ed48941a08f981, 0x494c553b, 0x53911075)),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    for pq in &[&[][..], &[0x01; 9][..]] {
+        match *asymm::pq_from_bytes(pq).unwrap_err().kind() {
+            ErrorKind::InvalidPqLength(len) => assert_eq!(len, pq.len()),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+}
+
+const TELEGRAM_PUBLIC_KEY_PEM: &str = "\
+-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAwVACPi9w23mF3tBkdZz+
+zwrzKOaaQdr01vAbU4E1pvkfj4sqDsm6lyDONS789sVoD/xCS9Y0hkkC3gtL1tSf
+TlgCMOOul9lcixlEKzwKENj1Yz/s7daSan9tqw3bfUV/nqgbhGX81v/+7RFAEd+R
+wFnK7a+XYl9sluzHRyVVaTTveB2GazTwEfzk2DWgkBluml8OREmvfraX3bkHZJTK
+X4EQSjBbbdJ2ZXIsRrYOXfaA+xayEGB+8hdlLmAjbCVfaigxX0CDqWeR1yFL9kwd
+9P0NsZRPsmoqVwMbMu7mStFai6aIhc3nSlv8kg9qv1m6XHVQY3PnEw+QQtqSIXkl
+HwIDAQAB
+-----END PUBLIC KEY-----";
+
+#[test]
+fn test_public_key_set() {
+    let fingerprint = 0xc3b42b026ce86b21u64 as i64;
+
+    let mut key_set = PublicKeySet::new();
+    assert!(asymm::find_first_key_fail_safe(&key_set, &[fingerprint]).is_err());
+    assert_eq!(key_set.add_pem(TELEGRAM_PUBLIC_KEY_PEM).unwrap(), fingerprint);
+
+    let (_, found) = asymm::find_first_key_fail_safe(&key_set, &[1, fingerprint]).unwrap();
+    assert_eq!(found, fingerprint);
+
+    assert_eq!(PublicKeySet::from_pems(&[TELEGRAM_PUBLIC_KEY_PEM]).unwrap(), key_set);
+    assert_eq!(PublicKeySet::default().fingerprints(), vec![fingerprint]);
+    assert!(key_set.add_pem("-----BEGIN PUBLIC KEY-----\nnot a key\n-----END PUBLIC KEY-----").is_err());
+}
+
+#[test]
+fn test_key_for_fingerprint() {
+    let fingerprint = 0xc3b42b026ce86b21u64 as i64;
+
+    assert_eq!(asymm::all_fingerprints(), vec![fingerprint]);
+
+    let key = asymm::key_for_fingerprint(fingerprint).unwrap();
+    assert_eq!(key.fingerprint().unwrap(), fingerprint);
+    assert!(asymm::key_for_fingerprint(1).is_none());
+}
+
+#[test]
+fn test_rsa_encrypt_block_length() {
+    let key = asymm::key_for_fingerprint(0xc3b42b026ce86b21u64 as i64).unwrap();
+
+    // About 1 in 256 results starts with a zero byte, which `encrypt2()`
+    // must keep
+    for i in 0..1000 {
+        let data = [i as u8; 96];
+        assert_eq!(key.encrypt(&data).unwrap().len(), asymm::RSA_BLOCK_LEN);
+        assert_eq!(key.encrypt2(&data).unwrap().len(), asymm::RSA_BLOCK_LEN);
+    }
+}
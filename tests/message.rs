@@ -1,19 +1,35 @@
+extern crate byteorder;
+extern crate chrono;
+extern crate extprim;
+extern crate flate2;
 #[macro_use]
 extern crate log;
 extern crate mtproto;
 #[macro_use]
 extern crate pretty_assertions;
+extern crate rand;
 extern crate serde_mtproto;
 extern crate test_logger;
 
 
+use std::io::Write;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use mtproto::rpc::{AppInfo, Message, Session};
-use mtproto::rpc::encryption::AuthKey;
-use mtproto::schema::FutureSalt;
-use serde_mtproto::MtProtoSized;
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use mtproto::{ErrorKind, TLObject};
+use mtproto::tl::RawObject;
+use mtproto::tl::dynamic::DEFAULT_MAX_INFLATED_LEN;
+use mtproto::rpc::{message, AppInfo, Clock, FixedClock, Message, RpcResult, Session, SessionState};
+use mtproto::rpc::encryption::{hash, AuthKey, ProtocolVersion, Sender};
+use mtproto::rpc::msg_id::{self, MsgId};
+use mtproto::schema::{self, FutureSalt};
+use rand::{SeedableRng, XorShiftRng};
+use serde_mtproto::{Boxed, MtProtoSized};
 use test_logger::ensure_env_logger_initialized;
 
 
@@ -58,11 +74,389 @@ fn test_encrypted() {
     assert_eq!(bytes.len(), message.size_hint().unwrap());
 
     // Pass number of bytes of encrypted data as second parameter
-    let msg: Message<i32> = session.process_message(&bytes, Some(48)).unwrap();
+    let msg: Message<i32> = session.process_message(&bytes, Some(64)).unwrap();
     debug!("{:?}", msg);
     assert_eq!(message, msg);
 }
 
+#[test]
+fn test_message_id_matches_serialized() {
+    ensure_env_logger_initialized();
+
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    let plain_text = session.create_plain_text_message(23).unwrap();
+    let bytes = serde_mtproto::to_bytes(&plain_text).unwrap();
+    assert_eq!(LittleEndian::read_i64(&bytes[8..16]), plain_text.message_id());
+
+    let auth_key = test_auth_key();
+    session.adopt_key(auth_key.clone());
+    session.add_server_salts(vec![FutureSalt { valid_since: 0x0100_0000, valid_until: 0x0fff_ffff, salt: 1 }]);
+
+    let encrypted = session.create_encrypted_message_no_acks(23).unwrap().unwrap();
+    let bytes = serde_mtproto::to_bytes(&encrypted).unwrap();
+    let msg_key = extprim::i128::i128::from_parts(LittleEndian::read_i64(&bytes[16..24]), LittleEndian::read_u64(&bytes[8..16]));
+    let decrypted = auth_key.decrypt_message_bytes_as(ProtocolVersion::V2, Sender::Client,
+        LittleEndian::read_i64(&bytes[0..8]), msg_key, &bytes[24..]).unwrap();
+    assert_eq!(LittleEndian::read_i64(&decrypted[16..24]), encrypted.message_id());
+}
+
+#[test]
+fn test_invalid_message_data_len() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+
+    // salt, session_id, message_id, seq_no, message_data_length and a 4-byte body
+    let mut decrypted_data = vec![0; 8 + 8 + 8 + 4 + 4 + 4];
+    decrypted_data[28] = 0x40;  // 64 bytes, more than there actually are
+
+    let (auth_key_id, msg_key, encrypted_data) = auth_key.encrypt_message_bytes_as(ProtocolVersion::V2, Sender::Server, &decrypted_data).unwrap();
+    let mut bytes = serde_mtproto::to_bytes(&auth_key_id).unwrap();
+    bytes.extend(serde_mtproto::to_bytes(&msg_key).unwrap());
+    bytes.extend(&encrypted_data);
+
+    let result: Result<Message<i32>, _> = session.process_message(&bytes, Some(encrypted_data.len() as u32));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_process_reply() {
+    ensure_env_logger_initialized();
+
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+
+    let nonce = "123456789012345678901234567890".parse().unwrap();
+    let request = session.create_plain_text_message(schema::rpc::req_pq { nonce: nonce }).unwrap();
+    assert_eq!(request.body().nonce, nonce);
+    assert_eq!(request.body().constructor_name(), Some("req_pq"));
+
+    let res_pq = schema::ResPQ {
+        nonce: nonce,
+        server_nonce: "98765432109876543210".parse().unwrap(),
+        pq: vec![0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81].into(),
+        server_public_key_fingerprints: Boxed::new(vec![-4344800451088585951]),
+    };
+    let reply = session.create_plain_text_message(res_pq.clone()).unwrap();
+    let reply_bytes = serde_mtproto::to_bytes(&reply).unwrap();
+
+    // The reply type is inferred from the request type
+    let msg = session.process_reply(&request, &reply_bytes, None).unwrap();
+    assert_eq!(msg.unwrap_plain_text_body(), res_pq);
+}
+
+#[test]
+fn test_raw_object_message() {
+    ensure_env_logger_initialized();
+
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+
+    // req_pq captured from https://core.telegram.org/mtproto/samples-auth_key
+    let captured = [
+        0x78, 0x97, 0x46, 0x60, 0x3e, 0x05, 0x49, 0x82, 0x8c, 0xca,
+        0x27, 0xe9, 0x66, 0xb3, 0x01, 0xa4, 0x8f, 0xec, 0xe2, 0xfc,
+    ];
+    let raw = RawObject::new(&captured).unwrap();
+    assert_eq!(raw.body(), &captured[4..]);
+
+    let message = session.create_plain_text_message(raw).unwrap();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    assert_eq!(message.size_hint().unwrap(), bytes.len());
+    assert_eq!(LittleEndian::read_u32(&bytes[16..20]), 20);
+    assert_eq!(&bytes[20..], &captured[..]);
+
+    let request: Message<schema::rpc::req_pq> = session.process_message(&bytes, None).unwrap();
+    assert_eq!(request.unwrap_plain_text_body().nonce, extprim::i128::i128::from_parts(0xfce2ec8fa401b366u64 as i64, 0xe927ca8c8249053e));
+
+    // TL objects are aligned to 4 bytes
+    assert!(RawObject::new(&captured[..6]).is_err());
+    assert!(RawObject::new(&[]).is_err());
+}
+
+#[test]
+fn test_time_offset() {
+    ensure_env_logger_initialized();
+
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    assert_eq!(session.time_offset(), 0);
+
+    session.set_time_offset(3600);
+    assert_eq!(session.time_offset(), 3600);
+
+    let message = session.create_plain_text_message(23).unwrap();
+    let (time, _) = msg_id::decode_msg_id(message.message_id());
+    let drift = time.timestamp() - Utc::now().timestamp() - 3600;
+    assert!(drift.abs() <= 1);
+}
+
+#[test]
+fn test_fixed_clock() {
+    ensure_env_logger_initialized();
+
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.set_clock(FixedClock(Utc.timestamp(1_500_000_000, 500_000_000)));
+    session.set_time_offset(3600);
+
+    let first = session.create_plain_text_message(23).unwrap();
+    assert_eq!(first.message_id(), (1_500_003_600 << 32) | 0x8000_0000);
+
+    // The clock doesn't move, so the next ID is only bumped
+    let second = session.create_plain_text_message(23).unwrap();
+    assert_eq!(second.message_id(), first.message_id() + 4);
+
+    session.update_time_offset(msg_id::encode_msg_id(Utc.timestamp(1_500_000_600, 0)) | 1);
+    assert_eq!(session.time_offset(), 600);
+}
+
+#[test]
+fn test_process_reply_updates_time_offset() {
+    ensure_env_logger_initialized();
+
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    let request = session.create_plain_text_message(schema::rpc::req_pq { nonce: extprim::i128::i128::new(1) }).unwrap();
+
+    // Pretend to be a server whose clock is 10 minutes ahead
+    let server = Session::new(0, AppInfo::new(9000, "random text".to_owned()));
+    server.set_time_offset(600);
+    let res_pq = schema::ResPQ {
+        nonce: extprim::i128::i128::new(1),
+        server_nonce: extprim::i128::i128::new(2),
+        pq: vec![0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81].into(),
+        server_public_key_fingerprints: Boxed::new(vec![-4344800451088585951]),
+    };
+    let reply_bytes = serde_mtproto::to_bytes(&server.create_plain_text_message(res_pq).unwrap()).unwrap();
+
+    session.process_reply(&request, &reply_bytes, None).unwrap();
+    assert!((session.time_offset() - 600).abs() <= 1);
+}
+
+#[test]
+fn test_destroy_session() {
+    ensure_env_logger_initialized();
+
+    let mut session = encrypted_session();
+
+    let old_session_id = 4471;
+    let message = session.create_destroy_session_message(old_session_id).unwrap();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    debug!("{:?}", bytes);
+
+    let encrypted_data_len = bytes.len() as u32 - 24;
+    let msg: Message<schema::rpc::destroy_session> =
+        session.process_message(&bytes, Some(encrypted_data_len)).unwrap();
+    assert_eq!(msg.unwrap_decrypted_body().session_id, old_session_id);
+}
+
+#[test]
+fn test_http_wait() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = encrypted_session();
+    set_test_server_time(&session);
+
+    let message = session.create_http_wait_message(0, 0, 25000).unwrap();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    // Not a content message, so neither tracked nor resent
+    assert!(session.sent_msg_ids().is_empty());
+
+    let encrypted_data_len = bytes.len() as u32 - 24;
+    let msg: Message<schema::HttpWait> = session.process_message(&bytes, Some(encrypted_data_len)).unwrap();
+    assert_eq!(msg.unwrap_decrypted_body().max_wait, 25000);
+
+    let update_short = update_short_bytes();
+
+    // The long-polled reply doesn't refer to any request
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &update_short);
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+
+    let updates = session.take_updates();
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].body, update_short);
+}
+
+#[test]
+fn test_flood_wait_retry_delay() {
+    assert_eq!(mtproto::rpc::session::flood_wait_secs("FLOOD_WAIT_2"), Some(2));
+    assert_eq!(mtproto::rpc::session::flood_wait_secs("FLOOD_WAIT_X"), None);
+    assert_eq!(mtproto::rpc::session::flood_wait_secs("PHONE_MIGRATE_2"), None);
+
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    let flood_wait: mtproto::Error = ErrorKind::RpcError(420, "FLOOD_WAIT_2".to_owned()).into();
+    let long_flood_wait: mtproto::Error = ErrorKind::RpcError(420, "FLOOD_WAIT_3600".to_owned()).into();
+    let other: mtproto::Error = ErrorKind::RpcError(400, "PEER_ID_INVALID".to_owned()).into();
+
+    // Disabled by default
+    assert_eq!(session.flood_wait_retry_delay(&flood_wait), None);
+
+    session.set_max_flood_wait(Some(chrono::Duration::seconds(60)));
+    assert_eq!(session.flood_wait_retry_delay(&flood_wait), Some(chrono::Duration::seconds(2)));
+    assert_eq!(session.flood_wait_retry_delay(&long_flood_wait), None);
+    assert_eq!(session.flood_wait_retry_delay(&other), None);
+}
+
+#[test]
+fn test_retry_flood_wait() {
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.set_max_flood_wait(Some(chrono::Duration::seconds(60)));
+
+    // The mocked server floods once and then succeeds
+    let mut attempts = 0;
+    let mut delays = Vec::new();
+    let result = session.retry_flood_wait(|_| {
+        attempts += 1;
+        if attempts == 1 {
+            bail_rpc_error(420, "FLOOD_WAIT_2")
+        } else {
+            Ok(attempts)
+        }
+    }, |delay| delays.push(delay));
+
+    assert_eq!(result.unwrap(), 2);
+    assert_eq!(delays, vec![chrono::Duration::seconds(2)]);
+
+    // Waits over the limit and other errors are reported right away
+    for &(code, message) in &[(420, "FLOOD_WAIT_3600"), (400, "PEER_ID_INVALID")] {
+        let mut attempts = 0;
+        let result: mtproto::Result<()> = session.retry_flood_wait(|_| {
+            attempts += 1;
+            bail_rpc_error(code, message)
+        }, |_| panic!("unexpected retry"));
+
+        assert_eq!(attempts, 1);
+        match *result.unwrap_err().kind() {
+            ErrorKind::RpcError(found_code, ref found_message) => {
+                assert_eq!(found_code, code);
+                assert_eq!(found_message, message);
+            },
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+}
+
+fn bail_rpc_error<T>(code: i32, message: &str) -> mtproto::Result<T> {
+    Err(ErrorKind::RpcError(code, message.to_owned()).into())
+}
+
+#[test]
+fn test_keepalive() {
+    ensure_env_logger_initialized();
+
+    let mut session = encrypted_session();
+
+    let clock = ManualClock::new(Utc.timestamp(1_500_000_000, 0));
+    session.set_clock(clock.clone());
+
+    assert!(!session.keepalive_due());
+    session.set_keepalive(Some(chrono::Duration::milliseconds(200)), false);
+    assert!(session.create_keepalive_message().unwrap().is_none());
+
+    clock.advance(chrono::Duration::milliseconds(250));
+    assert!(session.keepalive_due());
+    let message = session.create_keepalive_message().unwrap().unwrap();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    let encrypted_data_len = bytes.len() as u32 - 24;
+    let _: Message<schema::rpc::ping> = session.process_message(&bytes, Some(encrypted_data_len)).unwrap();
+    assert!(!session.keepalive_due());
+    // Pings aren't tracked for resending
+    assert!(session.sent_msg_ids().is_empty());
+
+    // Other messages postpone the next ping
+    clock.advance(chrono::Duration::milliseconds(120));
+    session.create_encrypted_message_no_acks(23).unwrap().unwrap();
+    clock.advance(chrono::Duration::milliseconds(120));
+    assert!(!session.keepalive_due());
+
+    session.set_keepalive(Some(chrono::Duration::zero()), true);
+    let message = session.create_keepalive_message().unwrap().unwrap();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    let encrypted_data_len = bytes.len() as u32 - 24;
+    let ping: Message<schema::rpc::ping_delay_disconnect> =
+        session.process_message(&bytes, Some(encrypted_data_len)).unwrap();
+    assert_eq!(ping.unwrap_decrypted_body().disconnect_delay, 15);
+
+    session.set_keepalive(None, false);
+    assert!(!session.keepalive_due());
+}
+
+#[test]
+fn test_unpack_gzip_corrupt() {
+    let gzip_packed = Boxed::new(schema::manual::GzipPacked {
+        packed_data: vec![0x27, 0x9a, 0x4e, 0x01, 0xd8, 0x33, 0x70, 0xc5, 0x1b, 0x62].into(),
+    });
+    let bytes = serde_mtproto::to_bytes(&gzip_packed).unwrap();
+
+    match *message::unpack_gzip(&bytes).unwrap_err().kind() {
+        ErrorKind::GzipInflateFailed(packed_len) => assert_eq!(packed_len, 10),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    // Other constructors aren't touched
+    let pong = serde_mtproto::to_bytes(&Boxed::new(schema::Pong { msg_id: 1, ping_id: 2 })).unwrap();
+    assert_eq!(&*message::unpack_gzip(&pong).unwrap(), &pong[..]);
+}
+
+#[test]
+fn test_unpack_gzip_limit() {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Best);
+    encoder.write_all(&vec![0; DEFAULT_MAX_INFLATED_LEN + 1]).unwrap();
+    let gzip_packed = Boxed::new(schema::manual::GzipPacked {
+        packed_data: encoder.finish().unwrap().into(),
+    });
+    let bytes = serde_mtproto::to_bytes(&gzip_packed).unwrap();
+
+    match *message::unpack_gzip(&bytes).unwrap_err().kind() {
+        ErrorKind::DeserializationLimitExceeded(limit_name, limit) => {
+            assert_eq!(limit_name, "inflated length");
+            assert_eq!(limit, DEFAULT_MAX_INFLATED_LEN);
+        },
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_process_rpc_result() {
+    ensure_env_logger_initialized();
+
+    let mut session = encrypted_session();
+
+    let request = session.create_encrypted_message_no_acks(schema::rpc::ping { ping_id: 0x0102_0304 })
+        .unwrap().unwrap();
+    let pong = schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 };
+
+    let serialize_reply = |session: &mut Session, req_msg_id| {
+        let rpc_result = RpcResult { req_msg_id: req_msg_id, result: Boxed::new(pong.clone()) };
+        let reply = session.create_encrypted_message_no_acks(rpc_result).unwrap().unwrap();
+        serde_mtproto::to_bytes(&reply).unwrap()
+    };
+
+    let reply_bytes = serialize_reply(&mut session, request.message_id());
+    let encrypted_data_len = reply_bytes.len() as u32 - 24;
+    let result = session.process_rpc_result(&request, &reply_bytes, Some(encrypted_data_len)).unwrap();
+    assert_eq!(result, pong);
+
+    let reply_bytes = serialize_reply(&mut session, request.message_id() + 4);
+    let encrypted_data_len = reply_bytes.len() as u32 - 24;
+    assert!(session.process_rpc_result(&request, &reply_bytes, Some(encrypted_data_len)).is_err());
+}
+
+#[test]
+fn test_serialize_into() {
+    let session = Session::new(0, AppInfo::new(100, "foo hash".to_owned()));
+    let mut buf = vec![0xff; 256];
+
+    for ping_id in 0..3 {
+        let message = session.create_plain_text_message(schema::rpc::ping { ping_id: ping_id }).unwrap();
+        message.serialize_into(&mut buf).unwrap();
+
+        assert_eq!(buf, serde_mtproto::to_bytes(&message).unwrap());
+        // The initial allocation is large enough for every message
+        assert!(buf.capacity() >= 256);
+    }
+}
+
 #[test]
 fn test_next_message_id_monotonicity() {
     let session = Session::new(0, AppInfo::new(100, "foo hash".to_owned()));
@@ -84,3 +478,632 @@ fn test_next_message_id_monotonicity() {
         sleep(Duration::new(0, 25_000));
     }
 }
+
+#[test]
+fn test_session_new_with_rng() {
+    let app_info = AppInfo::new(9000, "random text".to_owned());
+    let seed = [0x0123_4567, 0x89ab_cdef, 0x7654_3210, 0xfedc_ba98];
+
+    let session1 = Session::new_with_rng(&mut XorShiftRng::from_seed(seed), app_info.clone());
+    let session2 = Session::new_with_rng(&mut XorShiftRng::from_seed(seed), app_info);
+    assert_eq!(session1.session_id(), session2.session_id());
+}
+
+#[test]
+fn test_session_with_state() {
+    let last_msg_id = 0x7fff_ffff_0000_0000;
+    let state = SessionState {
+        auth_key: Some(test_auth_key()),
+        server_salts: vec![],
+        seq_no: 6,
+        last_msg_id: last_msg_id,
+        time_offset: 0,
+        protocol_version: ProtocolVersion::V1,
+    };
+
+    let session = Session::with_state(892103, AppInfo::new(9000, "random text".to_owned()), state);
+    let message = session.create_plain_text_message(23).unwrap();
+    assert_eq!(message.message_id(), last_msg_id + 4);
+
+    let state = session.state();
+    assert_eq!(state.last_msg_id, last_msg_id + 4);
+    assert_eq!(state.seq_no, 6);
+    assert!(state.auth_key.is_some());
+    assert_eq!(state.protocol_version, ProtocolVersion::V1);
+}
+
+#[test]
+fn test_restore_last_msg_id() {
+    // A fresh session after reconnecting with a clock behind the old one
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    let last_msg_id = session.create_plain_text_message(false).unwrap().message_id() + (1 << 32);
+
+    session.restore_last_msg_id(last_msg_id);
+    let message_id = session.create_plain_text_message(false).unwrap().message_id();
+    assert!(message_id >= last_msg_id + 4);
+
+    // Restoring a lower ID must not break monotonicity
+    session.restore_last_msg_id(0);
+    assert!(session.create_plain_text_message(false).unwrap().message_id() > message_id);
+}
+
+#[test]
+fn test_start_new_session() {
+    let auth_key = test_auth_key();
+    let mut session = encrypted_session();
+    set_test_server_time(&session);
+    let ping = session.create_encrypted_message_no_acks(schema::rpc::ping { ping_id: 1 }).unwrap().unwrap();
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &update_short_bytes());
+    session.process_encrypted_reply(&ping, &reply_bytes).unwrap();
+    assert!(session.is_duplicate(0x5a00_0000_0000_0401));
+
+    session.start_new_session_with_rng(&mut XorShiftRng::from_seed([1, 2, 3, 4]));
+    assert!(session.session_id() != 892103);
+
+    let state = session.state();
+    assert_eq!(state.seq_no, 0);
+    assert_eq!(state.last_msg_id, 0);
+    assert_eq!(state.server_salts.len(), 1);
+    assert_eq!(state.auth_key.unwrap().key_id(), auth_key.key_id());
+
+    // IDs received in the old session are forgotten
+    assert!(!session.is_duplicate(0x5a00_0000_0000_0401));
+
+    // The unanswered ping is kept for resending, and so is the update
+    // the caller hasn't taken yet
+    assert_eq!(session.sent_msg_ids(), &[ping.message_id()]);
+    assert_eq!(session.in_flight_messages().len(), 1);
+    assert_eq!(session.take_pending_messages().len(), 1);
+}
+
+/// A clock which only moves when told to, so that tests of timeouts
+/// don't depend on real sleeps.
+#[derive(Clone, Debug)]
+struct ManualClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl ManualClock {
+    fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock(Arc::new(Mutex::new(now)))
+    }
+
+    fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.0.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Make `session` expect server time `0x5a00_0000`, the time of server
+/// message IDs used in these tests.
+fn set_test_server_time(session: &Session) {
+    session.set_time_offset((0x5a00_0000 - Utc::now().timestamp()) as i32);
+}
+
+/// Authorization key of sessions in these tests, which server messages
+/// are encrypted with.
+fn test_auth_key() -> AuthKey {
+    AuthKey::new(&[0xf0, 0xe1, 0xd2, 0xc3, 0xb4, 0xa5, 0x96, 0x87]).unwrap()
+}
+
+/// A session with `test_auth_key()` and a server salt, ready to create
+/// encrypted messages.
+fn encrypted_session() -> Session {
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(test_auth_key());
+    session.add_server_salts(vec![FutureSalt {
+        valid_since: 0x0100_0000,
+        valid_until: 0x0fff_ffff,
+        salt: 0x1234_5678_90ab_cdef,
+    }]);
+
+    session
+}
+
+/// A serialized boxed `updateShort` with a dummy body, since it isn't
+/// deserialized in these tests.
+fn update_short_bytes() -> Vec<u8> {
+    let mut bytes = vec![0; 4];
+    LittleEndian::write_u32(&mut bytes, 0x78d4dec1);
+    bytes.extend(&[0; 24][..]);
+
+    bytes
+}
+
+fn container_item(msg_id: i64, seqno: i32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0; 16];
+    LittleEndian::write_i64(&mut bytes[0..8], msg_id);
+    LittleEndian::write_i32(&mut bytes[8..12], seqno);
+    LittleEndian::write_u32(&mut bytes[12..16], body.len() as u32);
+    bytes.extend(body);
+
+    bytes
+}
+
+fn encrypt_server_message(auth_key: &AuthKey, msg_id: i64, seq_no: i32, body: &[u8]) -> Vec<u8> {
+    encrypt_server_message_as(auth_key, ProtocolVersion::V2, 892103, msg_id, seq_no, body)
+}
+
+fn encrypt_server_message_as(auth_key: &AuthKey,
+                             version: ProtocolVersion,
+                             session_id: i64,
+                             msg_id: i64,
+                             seq_no: i32,
+                             body: &[u8])
+                            -> Vec<u8>
+{
+    // salt, session_id, msg_id, seq_no and message_data_length
+    let mut decrypted_data = vec![0; 32];
+    LittleEndian::write_i64(&mut decrypted_data[8..16], session_id);
+    LittleEndian::write_i64(&mut decrypted_data[16..24], msg_id);
+    LittleEndian::write_i32(&mut decrypted_data[24..28], seq_no);
+    LittleEndian::write_u32(&mut decrypted_data[28..32], body.len() as u32);
+    decrypted_data.extend(body);
+
+    let (auth_key_id, msg_key, encrypted_data) = auth_key.encrypt_message_bytes_as(version, Sender::Server, &decrypted_data).unwrap();
+    let mut bytes = serde_mtproto::to_bytes(&auth_key_id).unwrap();
+    bytes.extend(serde_mtproto::to_bytes(&msg_key).unwrap());
+    bytes.extend(encrypted_data);
+
+    bytes
+}
+
+#[test]
+fn test_v1_rejects_tampered_reply() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::with_protocol_version(892103, AppInfo::new(9000, "random text".to_owned()), ProtocolVersion::V1);
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let pong = schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 };
+    let rpc_result = Boxed::new(RpcResult { req_msg_id: request.message_id(), result: Boxed::new(pong.clone()) });
+    let reply_bytes = encrypt_server_message_as(&auth_key, ProtocolVersion::V1, 892103, 0x5a00_0000_0000_0401, 1,
+        &serde_mtproto::to_bytes(&rpc_result).unwrap());
+
+    // Corrupting the body must be caught by msg_key, there is no padding to absorb it
+    let mut tampered = reply_bytes.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0xff;
+    match *session.process_encrypted_reply(&request, &tampered).unwrap_err().kind() {
+        ErrorKind::MessageKeyMismatch => (),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), Some(pong));
+}
+
+#[test]
+fn test_reject_reply_from_other_session() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let pong = schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 };
+    let rpc_result = Boxed::new(RpcResult { req_msg_id: request.message_id(), result: Boxed::new(pong) });
+    let reply_bytes = encrypt_server_message_as(&auth_key, ProtocolVersion::V2, 892104, 0x5a00_0000_0000_0401, 1,
+        &serde_mtproto::to_bytes(&rpc_result).unwrap());
+
+    match *session.process_encrypted_reply(&request, &reply_bytes).unwrap_err().kind() {
+        ErrorKind::SessionIdMismatch(expected, found) => {
+            assert_eq!(expected, 892103);
+            assert_eq!(found, 892104);
+        },
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_process_encrypted_reply_container() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let pong = schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 };
+
+    let rpc_result = Boxed::new(RpcResult { req_msg_id: request.message_id(), result: Boxed::new(pong.clone()) });
+    let new_session = Boxed::new(schema::NewSession {
+        first_msg_id: request.message_id(),
+        unique_id: 0x0abc_def0,
+        server_salt: 0x1234_5678_90ab_cdef,
+    });
+
+    let mut container = vec![0; 8];
+    LittleEndian::write_u32(&mut container[0..4], 0x73f1f8dc);
+    LittleEndian::write_u32(&mut container[4..8], 2);
+    container.extend(container_item(0x5a00_0000_0000_0401, 1, &serde_mtproto::to_bytes(&rpc_result).unwrap()));
+    container.extend(container_item(0x5a00_0000_0000_0801, 3, &serde_mtproto::to_bytes(&new_session).unwrap()));
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0c01, 4, &container);
+
+    assert!(session.state().server_salts.is_empty());
+    let reply = session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+    assert_eq!(reply, Some(pong));
+
+    // The salt from new_session_created is adopted and nothing is left over
+    assert_eq!(session.state().server_salts.len(), 1);
+    assert!(session.take_pending_messages().is_empty());
+}
+
+#[test]
+fn test_process_encrypted_reply_gzipped_rpc_result() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let pong = schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 };
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(&serde_mtproto::to_bytes(&Boxed::new(pong.clone())).unwrap()).unwrap();
+    let gzip_packed = Boxed::new(schema::manual::GzipPacked {
+        packed_data: encoder.finish().unwrap().into(),
+    });
+
+    // rpc_result#f35c6d01 req_msg_id:long result:gzip_packed
+    let mut rpc_result = vec![0; 12];
+    LittleEndian::write_u32(&mut rpc_result[0..4], 0xf35c6d01);
+    LittleEndian::write_i64(&mut rpc_result[4..12], request.message_id());
+    rpc_result.extend(serde_mtproto::to_bytes(&gzip_packed).unwrap());
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &rpc_result);
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), Some(pong));
+}
+
+#[test]
+fn test_resend_timed_out() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = encrypted_session();
+    set_test_server_time(&session);
+
+    let request = session.create_encrypted_message_no_acks(schema::rpc::ping { ping_id: 0x0102_0304 })
+        .unwrap().unwrap();
+    assert!(session.resend_timed_out(chrono::Duration::seconds(60)).unwrap().is_empty());
+
+    // Never acknowledged, so it is resent with a fresh id once the deadline passes
+    let resent = session.resend_timed_out(chrono::Duration::zero()).unwrap();
+    assert_eq!(resent.len(), 1);
+    let resent_msg_id = resent[0].message_id();
+    assert!(resent_msg_id > request.message_id());
+    assert_eq!(session.sent_msg_ids(), &[resent_msg_id]);
+    assert_eq!(session.in_flight_messages()[0].msg_id, resent_msg_id);
+
+    // A result for the resent message confirms delivery
+    let pong = schema::Pong { msg_id: resent_msg_id, ping_id: 0x0102_0304 };
+    let rpc_result = Boxed::new(RpcResult { req_msg_id: resent_msg_id, result: Boxed::new(pong) });
+    let reply_bytes = encrypt_server_message(
+        &auth_key, 0x5a00_0000_0000_0401, 1, &serde_mtproto::to_bytes(&rpc_result).unwrap());
+    session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+
+    assert!(session.in_flight_messages().is_empty());
+    assert!(session.resend_timed_out(chrono::Duration::zero()).unwrap().is_empty());
+}
+
+#[test]
+fn test_msgs_ack_stops_resend() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = encrypted_session();
+    set_test_server_time(&session);
+
+    let request = session.create_encrypted_message_no_acks(schema::rpc::ping { ping_id: 0x0102_0304 })
+        .unwrap().unwrap();
+    assert_eq!(session.in_flight_messages().len(), 1);
+
+    // The acknowledgment arrives inside a container
+    let msgs_ack = Boxed::new(schema::MsgsAck { msg_ids: Boxed::new(vec![request.message_id()]) });
+    let mut container = vec![0; 8];
+    LittleEndian::write_u32(&mut container[0..4], 0x73f1f8dc);
+    LittleEndian::write_u32(&mut container[4..8], 1);
+    container.extend(container_item(0x5a00_0000_0000_0401, 2, &serde_mtproto::to_bytes(&msgs_ack).unwrap()));
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0801, 4, &container);
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+
+    assert!(session.in_flight_messages().is_empty());
+    assert!(session.sent_msg_ids().is_empty());
+    assert!(session.resend_timed_out(chrono::Duration::zero()).unwrap().is_empty());
+    assert!(session.take_pending_messages().is_empty());
+}
+
+#[test]
+fn test_reauth_on_auth_key_error() {
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+
+    let mut reply_bytes = [0; 4];
+    LittleEndian::write_i32(&mut reply_bytes, -404);
+    let code = message::transport_error_code(&reply_bytes).unwrap();
+    assert_eq!(code, 404);
+
+    // Without the policy the error is only reported
+    assert!(session.process_transport_error(code).is_err());
+    assert!(!session.needs_auth_key());
+
+    session.set_reauth_on_auth_key_error(true);
+    assert!(session.process_transport_error(code).unwrap());
+    assert!(session.needs_auth_key());
+    assert!(session.create_encrypted_message_no_acks(schema::rpc::ping { ping_id: 1 }).is_err());
+
+    assert!(session.process_transport_error(code).is_err());
+    session.adopt_key(auth_key);
+    assert!(!session.needs_auth_key());
+}
+
+#[test]
+fn test_check_server_msg_id() {
+    let session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    set_test_server_time(&session);
+
+    assert!(session.check_server_msg_id(0x5a00_0000_0000_0401).is_ok());
+    assert!(session.check_server_msg_id(0x59ff_ff00_0000_0403).is_ok());
+    // Even IDs are generated by clients
+    assert!(session.check_server_msg_id(0x5a00_0000_0000_0404).is_err());
+    // Too old and too far in the future
+    assert!(session.check_server_msg_id(0x59ff_fe00_0000_0401).is_err());
+    assert!(session.check_server_msg_id(0x5a00_0040_0000_0401).is_err());
+
+    // Out-of-window replies are rejected before anything is processed
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+    let pong = Boxed::new(schema::Pong { msg_id: request.message_id(), ping_id: 0x0102_0304 });
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &serde_mtproto::to_bytes(&pong).unwrap());
+
+    match *session.process_encrypted_reply(&request, &reply_bytes).unwrap_err().kind() {
+        ErrorKind::InvalidServerMsgId(msg_id) => assert_eq!(msg_id, 0x5a00_0000_0000_0401),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+    assert!(session.take_pending_messages().is_empty());
+}
+
+#[test]
+fn test_take_updates() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+
+    let update_short = update_short_bytes();
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &update_short);
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+
+    let updates = session.take_updates();
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].msg_id, 0x5a00_0000_0000_0401);
+    assert_eq!(updates[0].body, update_short);
+    assert!(session.take_pending_messages().is_empty());
+}
+
+#[test]
+fn test_duplicate_msg_ids() {
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+
+    let update_short = update_short_bytes();
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &update_short);
+    assert!(!session.is_duplicate(0x5a00_0000_0000_0401));
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+    assert!(session.is_duplicate(0x5a00_0000_0000_0401));
+    assert!(session.create_encrypted_message_with_acks(23).unwrap().is_some());
+
+    // The resent message is acknowledged again, but not handled twice
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+    assert_eq!(session.take_pending_messages().len(), 1);
+    assert!(session.create_encrypted_message_no_acks(23).unwrap().is_none());
+
+    // Only the latest IDs are remembered
+    session.set_max_recent_msg_ids(1);
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0801, 3, &update_short);
+    assert_eq!(session.process_encrypted_reply(&request, &reply_bytes).unwrap(), None);
+    assert!(session.is_duplicate(0x5a00_0000_0000_0801));
+    assert!(!session.is_duplicate(0x5a00_0000_0000_0401));
+}
+
+#[test]
+fn test_msg_copy() {
+    let auth_key = test_auth_key();
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(auth_key.clone());
+    set_test_server_time(&session);
+
+    let request = session.create_plain_text_message(schema::rpc::ping { ping_id: 0x0102_0304 }).unwrap();
+
+    let update_short = update_short_bytes();
+
+    let msg_copy = |msg_id| {
+        let mut bytes = vec![0; 4];
+        LittleEndian::write_u32(&mut bytes, 0xe06046b2);
+        bytes.extend(container_item(msg_id, 1, &update_short));
+        bytes
+    };
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0401, 1, &update_short);
+    session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+    assert_eq!(session.take_pending_messages().len(), 1);
+
+    // A copy of a message which was already received is dropped
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0805, 3, &msg_copy(0x5a00_0000_0000_0401));
+    session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+    assert!(session.take_pending_messages().is_empty());
+
+    // A copy of a new message is handled as the original one
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0c09, 5, &msg_copy(0x5a00_0000_0000_0a01));
+    session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+    let pending = session.take_pending_messages();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].msg_id, 0x5a00_0000_0000_0a01);
+    assert_eq!(pending[0].body, update_short);
+
+    // The original may be older than 300 seconds, only the copy is checked
+    // against the time window
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_1001, 7, &msg_copy(0x59ff_fda8_0000_0001));
+    session.process_encrypted_reply(&request, &reply_bytes).unwrap();
+    let pending = session.take_pending_messages();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].msg_id, 0x59ff_fda8_0000_0001);
+
+    assert!(message::parse_msg_copy(&msg_copy(0x5a00_0000_0000_0a01)[..20]).is_err());
+}
+
+#[test]
+fn test_salt_for_server_time() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+    let state = SessionState {
+        auth_key: Some(test_auth_key()),
+        server_salts: vec![
+            FutureSalt { valid_since: now - 600, valid_until: now + 1200, salt: 0x1111 }.into(),
+            FutureSalt { valid_since: now + 1200, valid_until: now + 3000, salt: 0x2222 }.into(),
+        ],
+        seq_no: 0,
+        last_msg_id: 0,
+        // Server clock is 30 minutes ahead, so the second salt is valid there
+        time_offset: 1800,
+        protocol_version: ProtocolVersion::V2,
+    };
+
+    let mut session = Session::with_state(892103, AppInfo::new(9000, "random text".to_owned()), state);
+    let message = session.create_encrypted_message_no_acks(23).unwrap().unwrap();
+    assert_eq!(message.salt(), Some(0x2222));
+}
+
+#[test]
+fn test_msg_id_roundtrip() {
+    let time = Utc.ymd(2017, 11, 30).and_hms_nano(12, 34, 56, 789_012_345);
+    let id = msg_id::encode_msg_id(time);
+    assert_eq!(id >> 32, time.timestamp());
+    assert_eq!(id & 3, 0);
+
+    let (decoded, low) = msg_id::decode_msg_id(id);
+    assert_eq!(low, id as u32);
+    assert_eq!(decoded.timestamp(), time.timestamp());
+    // The lower 2 bits are lost, which is about a nanosecond
+    let diff = time.signed_duration_since(decoded).num_nanoseconds().unwrap();
+    assert!(diff >= 0 && diff < 4, "diff = {}", diff);
+
+    assert!(MsgId(id).to_string().contains("2017-11-30T12:34:56"));
+}
+
+#[test]
+fn test_protocol_version_msg_key() {
+    ensure_env_logger_initialized();
+
+    let raw_key = [0xf0, 0xe1, 0xd2, 0xc3, 0xb4, 0xa5, 0x96, 0x87];
+    let mut padded_raw_key = vec![0; 256 - raw_key.len()];
+    padded_raw_key.extend(&raw_key[..]);
+    let auth_key = AuthKey::new(&raw_key).unwrap();
+
+    for &version in &[ProtocolVersion::V1, ProtocolVersion::V2] {
+        let mut session = Session::with_protocol_version(892103, AppInfo::new(9000, "random text".to_owned()), version);
+        assert_eq!(session.protocol_version(), version);
+        session.adopt_key(auth_key.clone());
+        session.add_server_salts(vec![FutureSalt {
+            valid_since: 0x0100_0000,
+            valid_until: 0x0fff_ffff,
+            salt: 0x1234_5678_90ab_cdef,
+        }]);
+
+        let message = session.create_encrypted_message_no_acks(23).unwrap().unwrap();
+        let bytes = serde_mtproto::to_bytes(&message).unwrap();
+        assert_eq!(bytes.len(), message.size_hint().unwrap());
+
+        let auth_key_id = LittleEndian::read_i64(&bytes[0..8]);
+        let msg_key: extprim::i128::i128 = serde_mtproto::from_bytes(&bytes[8..24], None).unwrap();
+        let decrypted = auth_key.decrypt_message_bytes_as(version, Sender::Client, auth_key_id, msg_key, &bytes[24..])
+            .unwrap();
+
+        let expected_msg_key_bytes = match version {
+            // SHA1 of data without padding: header, message_data_length and a boxed int
+            ProtocolVersion::V1 => hash::sha1(&decrypted[0..40]).unwrap()[4..20].to_vec(),
+            ProtocolVersion::V2 => {
+                let mut input = padded_raw_key[88..120].to_vec();
+                input.extend(&decrypted);
+                hash::sha256(&input).unwrap()[8..24].to_vec()
+            },
+        };
+        assert_eq!(&bytes[8..24], expected_msg_key_bytes.as_slice());
+    }
+}
+
+#[test]
+fn test_batch_reply_per_request() {
+    ensure_env_logger_initialized();
+
+    let auth_key = test_auth_key();
+    let mut session = encrypted_session();
+    set_test_server_time(&session);
+
+    let bodies = (1..4)
+        .map(|ping_id| Box::new(schema::rpc::ping { ping_id: ping_id }) as Box<TLObject>)
+        .collect();
+    let (_batch, msg_ids) = session.create_encrypted_batch_message(bodies).unwrap();
+    assert_eq!(msg_ids.len(), 3);
+    assert_eq!(session.sent_msg_ids(), &msg_ids[..]);
+
+    // The second request fails, the first succeeds and the third is not answered yet
+    let pong = schema::Pong { msg_id: msg_ids[0], ping_id: 1 };
+    let rpc_result = serde_mtproto::to_bytes(&Boxed::new(
+        RpcResult { req_msg_id: msg_ids[0], result: Boxed::new(pong.clone()) })).unwrap();
+    let rpc_error = schema::RpcError { error_code: 420, error_message: "FLOOD_WAIT_3".to_owned() };
+    let rpc_error_result = serde_mtproto::to_bytes(&Boxed::new(
+        RpcResult { req_msg_id: msg_ids[1], result: Boxed::new(rpc_error) })).unwrap();
+
+    let mut container = vec![0; 8];
+    LittleEndian::write_u32(&mut container[0..4], 0x73f1f8dc);
+    LittleEndian::write_u32(&mut container[4..8], 2);
+    container.extend(container_item(0x5a00_0000_0000_0401, 1, &rpc_error_result));
+    container.extend(container_item(0x5a00_0000_0000_0405, 3, &rpc_result));
+
+    let reply_bytes = encrypt_server_message(&auth_key, 0x5a00_0000_0000_0409, 4, &container);
+    let replies = session.process_encrypted_batch_reply(&msg_ids, &reply_bytes).unwrap();
+    assert_eq!(replies.len(), 3);
+
+    match replies[0] {
+        Some(Ok(ref msg)) => assert_eq!(msg.read_rpc_result::<schema::Pong>().unwrap(), pong),
+        ref reply => panic!("unexpected reply: {:?}", reply),
+    }
+    match replies[1] {
+        Some(Err(ref err)) => match *err.kind() {
+            ErrorKind::RpcError(420, ref message) => assert_eq!(message, "FLOOD_WAIT_3"),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        },
+        ref reply => panic!("unexpected reply: {:?}", reply),
+    }
+    assert!(replies[2].is_none());
+
+    assert_eq!(session.sent_msg_ids(), &msg_ids[2..]);
+}
@@ -0,0 +1,64 @@
+extern crate byteorder;
+extern crate extprim;
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+extern crate serde_mtproto;
+
+
+use byteorder::{ByteOrder, LittleEndian};
+use extprim::i128::i128;
+use mtproto::rpc::{pfs, AppInfo, Session};
+use mtproto::rpc::encryption::{AuthKey, ProtocolVersion, Sender};
+use mtproto::schema::FutureSalt;
+use mtproto::schema::manual::BindAuthKeyInner;
+use serde_mtproto::Boxed;
+
+
+fn decrypt(key: &AuthKey, version: ProtocolVersion, bytes: &[u8]) -> Vec<u8> {
+    let auth_key_id = LittleEndian::read_i64(&bytes[0..8]);
+    assert_eq!(auth_key_id, key.key_id());
+    let msg_key: i128 = serde_mtproto::from_bytes(&bytes[8..24], None).unwrap();
+
+    key.decrypt_message_bytes_as(version, Sender::Client, auth_key_id, msg_key, &bytes[24..]).unwrap()
+}
+
+#[test]
+fn test_bind_temp_auth_key() {
+    let perm_key = AuthKey::new(&[0xf0, 0xe1, 0xd2, 0xc3, 0xb4, 0xa5, 0x96, 0x87]).unwrap();
+    let temp_key = AuthKey::new(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]).unwrap();
+
+    let mut session = Session::new(892103, AppInfo::new(9000, "random text".to_owned()));
+    session.adopt_key(temp_key.clone());
+    session.add_server_salts(vec![FutureSalt {
+        valid_since: 0x0100_0000,
+        valid_until: 0x0fff_ffff,
+        salt: 0x1234_5678_90ab_cdef,
+    }]);
+
+    let message = pfs::bind_temp_auth_key(&mut session, &perm_key, 3600).unwrap();
+    let message_id = message.message_id();
+    let bytes = serde_mtproto::to_bytes(&message).unwrap();
+    let bind = message.into_body();
+
+    // The outer message is encrypted with the temporary key
+    let outer = decrypt(&temp_key, session.protocol_version(), &bytes);
+    assert_eq!(LittleEndian::read_i64(&outer[16..24]), message_id);
+    assert_eq!(bind.perm_auth_key_id, perm_key.key_id());
+
+    // The inner message is encrypted with the permanent key using MTProto 1.0
+    let inner = decrypt(&perm_key, ProtocolVersion::V1, &bind.encrypted_message[..]);
+    assert_eq!(LittleEndian::read_i64(&inner[16..24]), message_id);
+    assert_eq!(LittleEndian::read_i32(&inner[24..28]), 0);
+    assert_eq!(LittleEndian::read_u32(&inner[28..32]), 40);
+    assert_eq!(LittleEndian::read_u32(&inner[32..36]), 0x75a3f765);
+
+    let bind_auth_key_inner: Boxed<BindAuthKeyInner> = serde_mtproto::from_bytes(&inner[32..72], None).unwrap();
+    assert_eq!(bind_auth_key_inner.into_inner(), BindAuthKeyInner {
+        nonce: bind.nonce,
+        temp_auth_key_id: temp_key.key_id(),
+        perm_auth_key_id: perm_key.key_id(),
+        temp_session_id: 892103,
+        expires_at: bind.expires_at,
+    });
+}
@@ -0,0 +1,49 @@
+extern crate byteorder;
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+extern crate serde_bytes;
+extern crate serde_mtproto;
+
+
+use byteorder::{ByteOrder, LittleEndian};
+use mtproto::rpc::reliability::{self, MsgState};
+use mtproto::schema::{MsgsStateInfo, MsgsStateReq};
+use serde_bytes::ByteBuf;
+use serde_mtproto::Boxed;
+
+
+#[test]
+fn test_msgs_state_req_encoding() {
+    let msgs_state_req = Boxed::new(MsgsStateReq {
+        msg_ids: Boxed::new(vec![0x5a00_0000_0000_0004, 0x5a00_0000_0000_0008]),
+    });
+
+    let mut expected = vec![0; 4 + 4 + 4 + 2 * 8];
+    LittleEndian::write_u32(&mut expected[0..4], 0xda69fb52);
+    LittleEndian::write_u32(&mut expected[4..8], 0x1cb5c415);
+    LittleEndian::write_u32(&mut expected[8..12], 2);
+    LittleEndian::write_i64(&mut expected[12..20], 0x5a00_0000_0000_0004);
+    LittleEndian::write_i64(&mut expected[20..28], 0x5a00_0000_0000_0008);
+
+    assert_eq!(serde_mtproto::to_bytes(&msgs_state_req).unwrap(), expected);
+}
+
+#[test]
+fn test_msgs_state_info_decoding() {
+    let msg_ids = [4, 8, 12];
+    let msgs_state_info = MsgsStateInfo {
+        req_msg_id: 16,
+        // received and acked; not received; nothing known
+        info: ByteBuf::from(vec![4 | 8, 2, 1]),
+    };
+
+    let states = reliability::parse_msgs_state_info(&msg_ids, &msgs_state_info).unwrap();
+    assert_eq!(states, vec![(4, MsgState::new(12)), (8, MsgState::new(2)), (12, MsgState::new(1))]);
+
+    assert!(states[0].1.is_received() && states[0].1.is_ack_received());
+    assert!(states[1].1.needs_resend());
+    assert!(states[2].1.is_unknown() && !states[2].1.needs_resend());
+
+    assert!(reliability::parse_msgs_state_info(&msg_ids[..2], &msgs_state_info).is_err());
+}
@@ -0,0 +1,163 @@
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+
+
+use std::io::Cursor;
+
+use mtproto::ErrorKind;
+use mtproto::rpc::encryption::obfuscation::INTERMEDIATE_TAG;
+use mtproto::rpc::tcp_common::{self, FrameDecoder, FrameEncoder, FrameMode};
+
+
+const MAX_LEN: usize = 16 * 1024 * 1024;
+
+
+#[test]
+fn test_frame_abridged() {
+    let body = [1, 2, 3, 4, 5, 6, 7, 8];
+    assert_eq!(tcp_common::frame_abridged(&body).unwrap(), vec![0x02, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // 0x7f words and more need the long form
+    let body = vec![0xab; 0x7f * 4];
+    let frame = tcp_common::frame_abridged(&body).unwrap();
+    assert_eq!(&frame[0..4], &[0x7f, 0x7f, 0x00, 0x00]);
+    assert_eq!(&frame[4..], &body[..]);
+
+    let body = vec![0xcd; 0x1_0203 * 4];
+    let frame = tcp_common::frame_abridged(&body).unwrap();
+    assert_eq!(&frame[0..4], &[0x7f, 0x03, 0x02, 0x01]);
+    assert_eq!(frame.len(), 4 + body.len());
+}
+
+#[test]
+fn test_frame_abridged_unaligned() {
+    match *tcp_common::frame_abridged(&[1, 2, 3, 4, 5, 6]).unwrap_err().kind() {
+        ErrorKind::UnalignedFrameBody(len) => assert_eq!(len, 6),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_frame_intermediate() {
+    let body = [1, 2, 3, 4, 5, 6, 7, 8];
+    assert_eq!(tcp_common::frame_intermediate(&body).unwrap(),
+               vec![0x08, 0x00, 0x00, 0x00, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+    let body = vec![0xab; 0x0102];
+    let frame = tcp_common::frame_intermediate(&body).unwrap();
+    assert_eq!(&frame[0..4], &[0x02, 0x01, 0x00, 0x00]);
+    assert_eq!(&frame[4..], &body[..]);
+}
+
+#[test]
+fn test_frame_full() {
+    let body = [1, 2, 3, 4];
+    assert_eq!(tcp_common::frame_full(0, &body).unwrap(), vec![
+        0x10, 0x00, 0x00, 0x00, // length
+        0x00, 0x00, 0x00, 0x00, // seq_no
+        1, 2, 3, 4,
+        0x42, 0x0f, 0xb5, 0xe0, // crc32
+    ]);
+    assert_eq!(tcp_common::frame_full(5, &body).unwrap(), vec![
+        0x10, 0x00, 0x00, 0x00,
+        0x05, 0x00, 0x00, 0x00,
+        1, 2, 3, 4,
+        0x26, 0x01, 0x55, 0xa8,
+    ]);
+}
+
+#[test]
+fn test_read_frame_giant_len() {
+    // Must fail on the length alone instead of allocating 4 GiB for the body
+    let mut reader = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+    let mut decoder = FrameDecoder::new(FrameMode::Intermediate, MAX_LEN);
+
+    match *tcp_common::read_frame(&mut reader, &mut decoder).unwrap_err().kind() {
+        ErrorKind::ResponseTooLong(len) => assert_eq!(len, 0xffff_ffff),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_checked_frame_len_u32_max() {
+    let max = u32::max_value() as u64;
+
+    assert_eq!(tcp_common::checked_frame_len(max, max as usize).unwrap(), max as usize);
+
+    match *tcp_common::checked_frame_len(max + 1, max as usize).unwrap_err().kind() {
+        ErrorKind::ResponseTooLong(len) => assert_eq!(len, max + 1),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    match *tcp_common::checked_frame_len(max, MAX_LEN).unwrap_err().kind() {
+        ErrorKind::ResponseTooLong(len) => assert_eq!(len, max),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_read_frame_closed_mid_message() {
+    // Counts cover the whole frame, including the 4-byte length
+    let mut reader = Cursor::new(vec![0x10, 0x00, 0x00, 0x00]);
+    let mut decoder = FrameDecoder::new(FrameMode::Intermediate, MAX_LEN);
+
+    match *tcp_common::read_frame(&mut reader, &mut decoder).unwrap_err().kind() {
+        ErrorKind::ConnectionClosedMidMessage(expected, received) => {
+            assert_eq!(expected, 20);
+            assert_eq!(received, 4);
+        },
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    let mut reader = Cursor::new(vec![0x10, 0x00, 0x00, 0x00, 1, 2, 3, 4, 5, 6]);
+    let mut decoder = FrameDecoder::new(FrameMode::Intermediate, MAX_LEN);
+
+    match *tcp_common::read_frame(&mut reader, &mut decoder).unwrap_err().kind() {
+        ErrorKind::ConnectionClosedMidMessage(expected, received) => {
+            assert_eq!(expected, 20);
+            assert_eq!(received, 10);
+        },
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_frame_encoder_intermediate_tag() {
+    let body = [1, 2, 3, 4];
+    let mut encoder = FrameEncoder::new(FrameMode::Intermediate);
+
+    let mut expected = INTERMEDIATE_TAG.to_vec();
+    expected.extend_from_slice(&[0x04, 0x00, 0x00, 0x00, 1, 2, 3, 4]);
+    assert_eq!(encoder.frame(&body).unwrap(), expected);
+
+    // Only the first frame carries the tag
+    assert_eq!(encoder.frame(&body).unwrap(), vec![0x04, 0x00, 0x00, 0x00, 1, 2, 3, 4]);
+    assert_eq!(encoder.frame(&body).unwrap(), vec![0x04, 0x00, 0x00, 0x00, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_frame_encoder_full_seq_no() {
+    let body = [1, 2, 3, 4];
+    let mut encoder = FrameEncoder::new(FrameMode::Full);
+
+    let first = encoder.frame(&body).unwrap();
+    assert_eq!(first, tcp_common::frame_full(0, &body).unwrap());
+    assert_eq!(&first[4..8], &[0x00, 0x00, 0x00, 0x00]);
+
+    let second = encoder.frame(&body).unwrap();
+    assert_eq!(second, tcp_common::frame_full(1, &body).unwrap());
+    assert_eq!(&second[4..8], &[0x01, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_read_frame_back_to_back() {
+    // E.g. an update pushed right before the reply, received in one read
+    let mut bytes = tcp_common::frame_full(0, &[1, 2, 3, 4]).unwrap();
+    bytes.extend(tcp_common::frame_full(1, &[5, 6, 7, 8, 9, 10, 11, 12]).unwrap());
+    let mut reader = Cursor::new(bytes);
+    let mut decoder = FrameDecoder::new(FrameMode::Full, MAX_LEN);
+
+    assert_eq!(tcp_common::read_frame(&mut reader, &mut decoder).unwrap(), vec![1, 2, 3, 4]);
+    assert_eq!(tcp_common::read_frame(&mut reader, &mut decoder).unwrap(), vec![5, 6, 7, 8, 9, 10, 11, 12]);
+}
@@ -0,0 +1,475 @@
+extern crate byteorder;
+extern crate extprim;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+extern crate serde;
+extern crate serde_bytes;
+extern crate serde_mtproto;
+extern crate test_logger;
+
+
+use std::io::Write;
+
+use byteorder::{ByteOrder, LittleEndian};
+use extprim::i128::i128;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use mtproto::{tl, TLObject};
+use mtproto::manual_types::Object;
+use mtproto::tl::FromTlBytes;
+use mtproto::rpc::invoke;
+use mtproto::schema::{self, FutureSalt, FutureSalts};
+use serde::de::DeserializeSeed;
+use serde_bytes::ByteBuf;
+use serde_mtproto::{Boxed, MtProtoSized, WithSize};
+use test_logger::ensure_env_logger_initialized;
+
+
+fn future_salts_bytes() -> Vec<u8> {
+    let mut bytes = vec![0; 8 + 4 + 4 + 2 * 16];
+
+    LittleEndian::write_i64(&mut bytes[0..8], 0x0123_4567_89ab_cdef);  // req_msg_id
+    LittleEndian::write_i32(&mut bytes[8..12], 0x5a00_0000);            // now
+    LittleEndian::write_u32(&mut bytes[12..16], 2);                     // bare vector length
+
+    LittleEndian::write_i32(&mut bytes[16..20], 0x5a00_0000);           // salts[0].valid_since
+    LittleEndian::write_i32(&mut bytes[20..24], 0x5a00_1000);           // salts[0].valid_until
+    LittleEndian::write_i64(&mut bytes[24..32], 0x1111_2222_3333_4444); // salts[0].salt
+
+    LittleEndian::write_i32(&mut bytes[32..36], 0x5a00_1000);           // salts[1].valid_since
+    LittleEndian::write_i32(&mut bytes[36..40], 0x5a00_2000);           // salts[1].valid_until
+    LittleEndian::write_i64(&mut bytes[40..48], 0x5555_6666_7777_0000); // salts[1].salt
+
+    bytes
+}
+
+#[test]
+fn test_bare_vector_of_bare_constructors() {
+    ensure_env_logger_initialized();
+
+    let bytes = future_salts_bytes();
+    let future_salts: FutureSalts = serde_mtproto::from_bytes(&bytes, None).unwrap();
+    debug!("{:#?}", future_salts);
+
+    assert_eq!(future_salts, FutureSalts {
+        req_msg_id: 0x0123_4567_89ab_cdef,
+        now: 0x5a00_0000,
+        salts: vec![
+            FutureSalt { valid_since: 0x5a00_0000, valid_until: 0x5a00_1000, salt: 0x1111_2222_3333_4444 },
+            FutureSalt { valid_since: 0x5a00_1000, valid_until: 0x5a00_2000, salt: 0x5555_6666_7777_0000 },
+        ],
+    });
+
+    let serialized = serde_mtproto::to_bytes(&future_salts).unwrap();
+    assert_eq!(serialized, bytes);
+    assert_eq!(serialized.len(), future_salts.size_hint().unwrap());
+}
+
+#[test]
+fn test_boxed_future_salts() {
+    ensure_env_logger_initialized();
+
+    let mut bytes = vec![0; 4];
+    LittleEndian::write_u32(&mut bytes, 0xae500895);
+    bytes.extend(future_salts_bytes());
+
+    let boxed: Boxed<FutureSalts> = serde_mtproto::from_bytes(&bytes, None).unwrap();
+    debug!("{:#?}", boxed);
+    assert_eq!(boxed.inner().salts.len(), 2);
+
+    // Elements of a bare vector must not be prefixed by their constructor id
+    assert_eq!(serde_mtproto::to_bytes(&boxed).unwrap(), bytes);
+}
+
+#[test]
+fn test_constructor_names() {
+    assert_eq!(schema::constructor_name(0x1cb5c415), Some("vector"));
+    assert_eq!(schema::constructor_name(0x05162463), Some("resPQ"));
+    assert_eq!(schema::constructor_name(0xb304a621), Some("upload.saveFilePart"));
+
+    let ping = schema::rpc::ping { ping_id: 0x0102_0304 };
+    assert_eq!(ping.constructor_name(), Some("ping"));
+}
+
+#[test]
+fn test_primitives_little_endian() {
+    assert_eq!(serde_mtproto::to_bytes(&-1i32).unwrap(), [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(serde_mtproto::to_bytes(&0x0102_0304i32).unwrap(), [0x04, 0x03, 0x02, 0x01]);
+    assert_eq!(serde_mtproto::to_bytes(&0x0102_0304_0506_0708i64).unwrap(),
+               [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+
+    let mut bytes = vec![0; 8];
+    LittleEndian::write_f64(&mut bytes, -1234.5678);
+    assert_eq!(serde_mtproto::to_bytes(&-1234.5678f64).unwrap(), bytes);
+
+    let double: f64 = serde_mtproto::from_bytes(&bytes, None).unwrap();
+    assert_eq!(double, -1234.5678);
+    let long: i64 = serde_mtproto::from_bytes(&[0xff; 8], None).unwrap();
+    assert_eq!(long, -1);
+}
+
+#[test]
+fn test_read_boxed_dispatches_on_constructor_id() {
+    ensure_env_logger_initialized();
+
+    let fail = schema::Server_DH_Params::server_DH_params_fail(schema::server_DH_params_fail {
+        nonce: "100".parse().unwrap(),
+        server_nonce: "20000".parse().unwrap(),
+        new_nonce_hash: "821349182".parse().unwrap(),
+    });
+    let ok = schema::Server_DH_Params::server_DH_params_ok(schema::server_DH_params_ok {
+        nonce: "100".parse().unwrap(),
+        server_nonce: "20000".parse().unwrap(),
+        encrypted_answer: vec![1, 2, 3].into(),
+    });
+
+    for value in vec![fail, ok] {
+        let bytes = serde_mtproto::to_bytes(&Boxed::new(value.clone())).unwrap();
+        assert_eq!(tl::read_boxed::<schema::Server_DH_Params>(&bytes).unwrap(), value);
+    }
+
+    let mut future_salts = vec![0; 4];
+    LittleEndian::write_u32(&mut future_salts, 0xae500895);
+    future_salts.extend(future_salts_bytes());
+    match *tl::read_boxed::<schema::Server_DH_Params>(&future_salts).unwrap_err().kind() {
+        mtproto::ErrorKind::UnknownConstructorId(_, id) => assert_eq!(id, 0xae500895),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_bytes_length_prefix_and_padding() {
+    ensure_env_logger_initialized();
+
+    // (payload length, length prefix, serialized length)
+    let cases: &[(usize, &[u8], usize)] = &[
+        (0, &[0], 4),
+        (1, &[1], 4),
+        (253, &[253], 256),
+        (254, &[254, 254, 0, 0], 260),
+        (255, &[254, 255, 0, 0], 260),
+    ];
+
+    for &(len, prefix, serialized_len) in cases {
+        let payload = ByteBuf::from(vec![0xa5; len]);
+        let bytes = serde_mtproto::to_bytes(&payload).unwrap();
+
+        assert_eq!(bytes.len(), serialized_len);
+        assert_eq!(payload.size_hint().unwrap(), serialized_len);
+        assert_eq!(&bytes[..prefix.len()], prefix);
+        assert!(bytes[prefix.len()..prefix.len() + len].iter().all(|&b| b == 0xa5));
+        assert!(bytes[prefix.len() + len..].iter().all(|&b| b == 0));
+
+        let deserialized: ByteBuf = serde_mtproto::from_bytes(&bytes, None).unwrap();
+        assert_eq!(deserialized, payload);
+    }
+}
+
+#[test]
+fn test_invoke_after_serialization() {
+    ensure_env_logger_initialized();
+
+    let ping = schema::rpc::ping { ping_id: 0x0102_0304_0506_0708 };
+    let ping_bytes = serde_mtproto::to_bytes(&Boxed::new(ping.clone())).unwrap();
+
+    let invoke_after = Boxed::new(invoke::invoke_after(0x5a00_0000_0000_0004, ping.clone()));
+    let bytes = serde_mtproto::to_bytes(&invoke_after).unwrap();
+    assert_eq!(LittleEndian::read_u32(&bytes[0..4]), 0xcb9f372d);
+    assert_eq!(LittleEndian::read_i64(&bytes[4..12]), 0x5a00_0000_0000_0004);
+    assert_eq!(&bytes[12..], &ping_bytes[..]);
+    assert_eq!(invoke_after.size_hint().unwrap(), bytes.len());
+
+    let invoke_after_msgs = Boxed::new(invoke::invoke_after_msgs(vec![4, 8], ping));
+    let bytes = serde_mtproto::to_bytes(&invoke_after_msgs).unwrap();
+    assert_eq!(LittleEndian::read_u32(&bytes[0..4]), 0x3dc4b4f0);
+    assert_eq!(LittleEndian::read_u32(&bytes[4..8]), 0x1cb5c415); // vector
+    assert_eq!(LittleEndian::read_u32(&bytes[8..12]), 2);
+    assert_eq!(LittleEndian::read_i64(&bytes[12..20]), 4);
+    assert_eq!(LittleEndian::read_i64(&bytes[20..28]), 8);
+    assert_eq!(&bytes[28..], &ping_bytes[..]);
+}
+
+#[test]
+fn test_schema_layer() {
+    let scheme = include_str!("../tl/000-scheme.tl");
+    let layer: i32 = scheme.lines()
+        .find(|line| line.starts_with("// LAYER "))
+        .map(|line| line["// LAYER ".len()..].trim().parse().unwrap())
+        .unwrap();
+    assert_eq!(schema::LAYER, layer);
+
+    let ping = schema::rpc::ping { ping_id: 0x0102_0304_0506_0708 };
+    let ping_bytes = serde_mtproto::to_bytes(&Boxed::new(ping.clone())).unwrap();
+
+    let bytes = serde_mtproto::to_bytes(&Boxed::new(invoke::invoke_with_layer(ping))).unwrap();
+    assert_eq!(LittleEndian::read_u32(&bytes[0..4]), 0xda9b0d0d);
+    assert_eq!(LittleEndian::read_i32(&bytes[4..8]), layer);
+    assert_eq!(&bytes[8..], &ping_bytes[..]);
+}
+
+#[test]
+fn test_bool_constructor_ids() {
+    ensure_env_logger_initialized();
+
+    let true_bytes = serde_mtproto::to_bytes(&true).unwrap();
+    let false_bytes = serde_mtproto::to_bytes(&false).unwrap();
+    assert_eq!(true_bytes, vec![0xb5, 0x75, 0x72, 0x99]);   // boolTrue#997275b5
+    assert_eq!(false_bytes, vec![0x37, 0x97, 0x79, 0xbc]);  // boolFalse#bc799737
+
+    assert_eq!(serde_mtproto::from_bytes::<bool>(&true_bytes, None).unwrap(), true);
+    assert_eq!(serde_mtproto::from_bytes::<bool>(&false_bytes, None).unwrap(), false);
+
+    // Neither boolTrue nor boolFalse
+    assert!(serde_mtproto::from_bytes::<bool>(&[0x01, 0x00, 0x00, 0x00], None).is_err());
+    assert!(serde_mtproto::from_bytes::<bool>(&[0x15, 0xc4, 0xb5, 0x1c], None).is_err());
+}
+
+#[test]
+fn test_res_pq_from_tl_bytes() {
+    ensure_env_logger_initialized();
+
+    // resPQ from https://core.telegram.org/mtproto/samples-auth_key
+    let bytes = [
+        0x63, 0x24, 0x16, 0x05,
+        0x3e, 0x05, 0x49, 0x82, 0x8c, 0xca, 0x27, 0xe9, 0x66, 0xb3, 0x01, 0xa4, 0x8f, 0xec, 0xe2, 0xfc,
+        0xa5, 0xcf, 0x4d, 0x33, 0xf4, 0xa1, 0x1e, 0xa8, 0x77, 0xba, 0x4a, 0xa5, 0x73, 0x90, 0x73, 0x30,
+        0x08, 0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81, 0x00, 0x00, 0x00,
+        0x15, 0xc4, 0xb5, 0x1c, 0x01, 0x00, 0x00, 0x00, 0x21, 0x6b, 0xe8, 0x6c, 0x02, 0x2b, 0xb4, 0xc3,
+    ];
+
+    let res_pq = schema::ResPQ::from_tl_bytes(&bytes).unwrap();
+    assert_eq!(res_pq.nonce, i128::from_parts(0xfce2ec8fa401b366u64 as i64, 0xe927ca8c8249053e));
+    assert_eq!(res_pq.server_nonce, i128::from_parts(0x30739073a54aba77, 0xa81ea1f4334dcfa5));
+    assert_eq!(&res_pq.pq[..], &[0x17, 0xed, 0x48, 0x94, 0x1a, 0x08, 0xf9, 0x81]);
+    assert_eq!(res_pq.server_public_key_fingerprints.inner(), &vec![0xc3b42b026ce86b21u64 as i64]);
+
+    // A different constructor is rejected
+    let pong = serde_mtproto::to_bytes(&Boxed::new(schema::Pong { msg_id: 1, ping_id: 2 })).unwrap();
+    assert!(schema::ResPQ::from_tl_bytes(&pong).is_err());
+}
+
+#[test]
+fn test_to_tl_bytes() {
+    let req_pq = schema::rpc::req_pq { nonce: i128::from_parts(0x0123_4567, 0x89ab_cdef) };
+    let expected = serde_mtproto::to_bytes(&Boxed::new(req_pq.clone())).unwrap();
+
+    assert_eq!(req_pq.to_tl_bytes().unwrap(), expected);
+    assert_eq!(req_pq.tl_size_hint().unwrap(), expected.len());
+
+    let object: Box<TLObject> = Box::new(req_pq);
+    assert_eq!(object.to_tl_bytes().unwrap(), expected);
+}
+
+#[test]
+fn test_manual_rpc_result_roundtrip() {
+    ensure_env_logger_initialized();
+
+    let pong = schema::Pong { msg_id: 0x5a00_0000_0000_0004, ping_id: 0x0102_0304 };
+    let rpc_result = Boxed::new(schema::manual::RpcResult {
+        req_msg_id: 0x5a00_0000_0000_0004,
+        result: Boxed::new(Box::new(pong.clone()) as Object),
+    });
+
+    let bytes = serde_mtproto::to_bytes(&rpc_result).unwrap();
+    assert_eq!(rpc_result.size_hint().unwrap(), bytes.len());
+    assert_eq!(LittleEndian::read_u32(&bytes[0..4]), 0xf35c6d01);
+    assert_eq!(LittleEndian::read_i64(&bytes[4..12]), 0x5a00_0000_0000_0004);
+    assert_eq!(&bytes[12..], &pong.to_tl_bytes().unwrap()[..]);
+
+    let mut cmap = tl::TLConstructorsMap::new();
+    schema::register_ctors(&mut cmap);
+    let result = cmap.read_dynamic(&bytes[12..]).unwrap();
+    assert_eq!(result.as_any().downcast_ref::<schema::Pong>(), Some(&pong));
+}
+
+#[test]
+fn test_manual_message_container_layout() {
+    let pong = schema::Pong { msg_id: 0x5a00_0000_0000_0004, ping_id: 0x0102_0304 };
+    let pong_bytes = pong.to_tl_bytes().unwrap();
+    let container = Boxed::new(schema::manual::MessageContainer {
+        messages: vec![
+            schema::manual::Message {
+                msg_id: 0x5a00_0000_0000_0401,
+                seqno: 1,
+                body: WithSize::new(Boxed::new(Box::new(pong) as Object)).unwrap(),
+            },
+        ],
+    });
+
+    let bytes = serde_mtproto::to_bytes(&container).unwrap();
+    assert_eq!(container.size_hint().unwrap(), bytes.len());
+    assert_eq!(LittleEndian::read_u32(&bytes[0..4]), 0x73f1f8dc);
+    assert_eq!(LittleEndian::read_u32(&bytes[4..8]), 1);                      // bare vector length
+    assert_eq!(LittleEndian::read_i64(&bytes[8..16]), 0x5a00_0000_0000_0401); // msg_id
+    assert_eq!(LittleEndian::read_i32(&bytes[16..20]), 1);                    // seqno
+    assert_eq!(LittleEndian::read_u32(&bytes[20..24]) as usize, pong_bytes.len());
+    assert_eq!(&bytes[24..], &pong_bytes[..]);
+}
+
+#[test]
+fn test_heterogeneous_message_container() {
+    let bodies: Vec<Object> = vec![
+        Box::new(schema::rpc::ping { ping_id: 0x0102_0304 }),
+        Box::new(schema::rpc::get_future_salts { num: 3 }),
+    ];
+    let body_bytes: Vec<Vec<u8>> = bodies.iter().map(|body| body.to_tl_bytes().unwrap()).collect();
+    let container = Boxed::new(schema::manual::MessageContainer {
+        messages: bodies.into_iter().enumerate().map(|(i, body)| {
+            schema::manual::Message {
+                msg_id: 0x5a00_0000_0000_0004 + 4 * i as i64,
+                seqno: 2 * i as i32 + 1,
+                body: WithSize::new(Boxed::new(body)).unwrap(),
+            }
+        }).collect(),
+    });
+
+    let bytes = serde_mtproto::to_bytes(&container).unwrap();
+    assert_eq!(container.size_hint().unwrap(), bytes.len());
+    assert_eq!(LittleEndian::read_u32(&bytes[4..8]), 2);
+
+    let mut pos = 8;
+    for (i, expected) in body_bytes.iter().enumerate() {
+        assert_eq!(LittleEndian::read_i64(&bytes[pos..pos + 8]), 0x5a00_0000_0000_0004 + 4 * i as i64);
+        assert_eq!(LittleEndian::read_i32(&bytes[pos + 8..pos + 12]), 2 * i as i32 + 1);
+        assert_eq!(LittleEndian::read_u32(&bytes[pos + 12..pos + 16]) as usize, expected.len());
+        assert_eq!(&bytes[pos + 16..pos + 16 + expected.len()], &expected[..]);
+        pos += 16 + expected.len();
+    }
+    assert_eq!(pos, bytes.len());
+    assert_eq!(LittleEndian::read_u32(&body_bytes[0][0..4]), 0x7abe77ec);
+    assert_eq!(LittleEndian::read_u32(&body_bytes[1][0..4]), 0xb921bd04);
+}
+
+fn gzip_packed_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(bytes).unwrap();
+    let gzip_packed = Boxed::new(schema::manual::GzipPacked {
+        packed_data: encoder.finish().unwrap().into(),
+    });
+
+    serde_mtproto::to_bytes(&gzip_packed).unwrap()
+}
+
+#[test]
+fn test_constructors_map_read_dynamic() {
+    ensure_env_logger_initialized();
+
+    let mut cmap = tl::TLConstructorsMap::new();
+    cmap.add::<schema::Pong>(0x347773c5);
+    cmap.add::<schema::MsgsAck>(0x62d6b459);
+
+    let pong = schema::Pong { msg_id: 0x5a00_0000_0000_0004, ping_id: 0x0102_0304 };
+    let object = cmap.read_dynamic(&pong.to_tl_bytes().unwrap()).unwrap();
+    assert_eq!(object.as_any().downcast_ref::<schema::Pong>(), Some(&pong));
+
+    // The same map is reused for the next object
+    let msgs_ack = schema::MsgsAck { msg_ids: Boxed::new(vec![0x5a00_0000_0000_0401, 0x5a00_0000_0000_0805]) };
+    let object = cmap.read_dynamic(&msgs_ack.to_tl_bytes().unwrap()).unwrap();
+    assert_eq!(object.as_any().downcast_ref::<schema::MsgsAck>(), Some(&msgs_ack));
+
+    let future_salt = FutureSalt { valid_since: 0x5a00_0000, valid_until: 0x5a00_1000, salt: 0x1111 };
+    // Unregistered constructors are rejected
+    match *cmap.read_dynamic(&future_salt.to_tl_bytes().unwrap()).unwrap_err().kind() {
+        mtproto::ErrorKind::UnknownConstructorId(_, id) => assert_eq!(id, 0x0949d9dc),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_constructors_map_inflates_nested_gzip() {
+    ensure_env_logger_initialized();
+
+    let pong = schema::Pong { msg_id: 0x5a00_0000_0000_0004, ping_id: 0x0102_0304 };
+    let bytes = gzip_packed_bytes(&gzip_packed_bytes(&serde_mtproto::to_bytes(&Boxed::new(pong.clone())).unwrap()));
+
+    let mut cmap = tl::TLConstructorsMap::new();
+    schema::register_ctors(&mut cmap);
+    cmap.set_inflate_gzip(true);
+
+    let object = cmap.deserialize(&mut serde_mtproto::Deserializer::new(&bytes[..], None)).unwrap();
+    assert_eq!(object.as_any().downcast_ref::<schema::Pong>(), Some(&pong));
+}
+
+#[test]
+fn test_endian_wrappers() {
+    let n = 0x0102_0304_0506_0708;
+
+    let be_bytes = serde_mtproto::to_bytes(&tl::BeU64(n)).unwrap();
+    assert_eq!(be_bytes, vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+    let be: tl::BeU64 = serde_mtproto::from_bytes(&be_bytes, None).unwrap();
+    assert_eq!(be, tl::BeU64(n));
+    assert_eq!(tl::BeU64(n).to_bytes(), [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+
+    let le_bytes = serde_mtproto::to_bytes(&tl::LeU64(n)).unwrap();
+    assert_eq!(le_bytes, vec![0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+    let le: tl::LeU64 = serde_mtproto::from_bytes(&le_bytes, None).unwrap();
+    assert_eq!(le, tl::LeU64(n));
+    assert_eq!(tl::LeU64::from_bytes([0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]), tl::LeU64(n));
+
+    assert_eq!(tl::BeU64(n).size_hint().unwrap(), 8);
+    assert_eq!(tl::LeU64(n).size_hint().unwrap(), 8);
+}
+
+#[test]
+fn test_constructors_map_gzip_limits() {
+    ensure_env_logger_initialized();
+
+    let pong = schema::Pong { msg_id: 0x5a00_0000_0000_0004, ping_id: 0x0102_0304 };
+    let pong_bytes = serde_mtproto::to_bytes(&Boxed::new(pong)).unwrap();
+    let nested_bytes = gzip_packed_bytes(&gzip_packed_bytes(&pong_bytes));
+
+    let mut cmap = tl::TLConstructorsMap::new();
+    schema::register_ctors(&mut cmap);
+    cmap.set_inflate_gzip(true);
+    cmap.set_max_gzip_depth(1);
+    assert!(cmap.deserialize(&mut serde_mtproto::Deserializer::new(&nested_bytes[..], None)).is_err());
+
+    // The boxed pong is 20 bytes long
+    let mut cmap = tl::TLConstructorsMap::new();
+    schema::register_ctors(&mut cmap);
+    cmap.set_inflate_gzip(true);
+    cmap.set_max_inflated_len(pong_bytes.len() - 1);
+    let bytes = gzip_packed_bytes(&pong_bytes);
+    assert!(cmap.deserialize(&mut serde_mtproto::Deserializer::new(&bytes[..], None)).is_err());
+}
+
+#[test]
+fn test_deserialization_limits() {
+    ensure_env_logger_initialized();
+
+    // msgs_ack whose vector claims 0xffffffff elements, but carries two
+    let mut bytes = vec![0; 12];
+    LittleEndian::write_u32(&mut bytes[0..4], 0x62d6b459);
+    LittleEndian::write_u32(&mut bytes[4..8], 0x1cb5c415);
+    LittleEndian::write_u32(&mut bytes[8..12], 0xffff_ffff);
+    bytes.extend(&[0; 16]);
+
+    let mut cmap = tl::TLConstructorsMap::new();
+    cmap.add::<schema::MsgsAck>(0x62d6b459);
+    match *cmap.read_dynamic(&bytes).unwrap_err().kind() {
+        mtproto::ErrorKind::DeserializationLimitExceeded(name, limit) =>
+            assert_eq!((name, limit), ("vector length", tl::limits::DEFAULT_MAX_VECTOR_LEN)),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    let nested = vec![vec![vec![1i32, 2]], vec![]];
+    let bytes = serde_mtproto::to_bytes(&nested).unwrap();
+    let limits = tl::DeserializationLimits { max_vector_len: 16, max_depth: 2 };
+    match *tl::from_bytes_limited::<Vec<Vec<Vec<i32>>>>(&bytes, None, limits).unwrap_err().kind() {
+        mtproto::ErrorKind::DeserializationLimitExceeded(name, limit) => assert_eq!((name, limit), ("nesting depth", 2)),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    let limits = tl::DeserializationLimits { max_depth: 3, ..limits };
+    assert_eq!(tl::from_bytes_limited::<Vec<Vec<Vec<i32>>>>(&bytes, None, limits).unwrap(), nested);
+
+    let limits = tl::DeserializationLimits { max_vector_len: 1, ..limits };
+    match *tl::from_bytes_limited::<Vec<Vec<Vec<i32>>>>(&bytes, None, limits).unwrap_err().kind() {
+        mtproto::ErrorKind::DeserializationLimitExceeded(name, limit) => assert_eq!((name, limit), ("vector length", 1)),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+}
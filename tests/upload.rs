@@ -0,0 +1,34 @@
+extern crate mtproto;
+#[macro_use]
+extern crate pretty_assertions;
+
+
+use mtproto::rpc::upload::{FileParts, MAX_PART_SIZE};
+
+
+#[test]
+fn test_file_parts() {
+    let file: Vec<u8> = (0..3 * MAX_PART_SIZE / 2).map(|i| i as u8).collect();
+
+    let mut parts = FileParts::new(file.as_slice(), 0x7357, MAX_PART_SIZE).unwrap();
+    let mut uploaded = Vec::new();
+
+    for (i, part) in (&mut parts).enumerate() {
+        let part = part.unwrap();
+        assert_eq!(part.file_id, 0x7357);
+        assert_eq!(part.file_part, i as i32);
+        uploaded.extend_from_slice(&part.bytes);
+    }
+
+    assert_eq!(parts.parts_count(), 3);
+    assert_eq!(uploaded, file);
+}
+
+#[test]
+fn test_file_parts_invalid_part_size() {
+    assert!(FileParts::new(&b""[..], 0, 0).is_err());
+    assert!(FileParts::new(&b""[..], 0, 1000).is_err());
+    assert!(FileParts::new(&b""[..], 0, 3 * 1024).is_err());
+    assert!(FileParts::new(&b""[..], 0, 2 * MAX_PART_SIZE).is_err());
+    assert!(FileParts::new(&b""[..], 0, 64 * 1024).is_ok());
+}
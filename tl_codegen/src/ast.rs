@@ -53,6 +53,13 @@ impl Type {
         }
     }
 
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Type::Named(ref v) => v.len() == 1 && v[0] == "true",
+            _ => false,
+        }
+    }
+
     pub fn is_type_parameter(&self) -> bool {
         match *self {
             Type::TypeParameter(..) => true,
@@ -289,7 +296,10 @@ impl Constructor {
             Some("server_DH_inner_data") |
             Some("client_DH_inner_data") |
             Some("req_DH_params") |
-            Some("set_client_DH_params") => (),
+            Some("set_client_DH_params") |
+            // Status bytes are not UTF-8
+            Some("msgs_state_info") |
+            Some("msgs_all_info") => (),
             _ => return,
         }
 
@@ -483,6 +493,45 @@ impl Constructor {
         self.to_syn_struct(&name, ctors_typeck_info)
     }
 
+    /// Generates `is_*` and `set_*` methods for `flags.N?true` fields,
+    /// which are serialized as nothing since their flag bit alone tells
+    /// whether they are set.
+    pub fn to_syn_true_flag_accessors(&self, name: &syn::Ident) -> Option<syn::Item> {
+        if !self.type_parameters.is_empty() {
+            return None; // FIXME
+        }
+
+        let mut methods = vec![];
+
+        for field in &self.fields {
+            let (flag, bit, field_name) = match (&field.ty, &field.name) {
+                (&Type::Flagged(ref flag, bit, ref ty), &Some(ref field_name)) if ty.is_true() => (flag, bit, field_name),
+                _ => continue,
+            };
+
+            methods.push(format!("
+                pub fn is_{name}(&self) -> bool {{
+                    self.{flag} & (1 << {bit}) != 0
+                }}
+
+                pub fn set_{name}(&mut self, value: bool) {{
+                    if value {{
+                        self.{flag} |= 1 << {bit};
+                    }} else {{
+                        self.{flag} &= !(1 << {bit});
+                    }}
+                }}",
+                name = field_name, flag = no_conflict_ident(flag).as_ref(), bit = bit));
+        }
+
+        if methods.is_empty() {
+            return None;
+        }
+
+        let impl_item = format!("impl {} {{ {} }}", name.as_ref(), methods.join("\n"));
+        Some(syn::parse_item(&impl_item).unwrap())
+    }
+
     pub fn to_syn_single_type_struct<'a>(&self, ctors_typeck_info: &BTreeMap<&'a Constructor, TypeckKind>) -> error::Result<syn::Item> {
         let name = self.output.name().map(no_conflict_ident).unwrap(); // FIXME
         self.to_syn_type_struct_base(name, ctors_typeck_info)
@@ -515,7 +564,10 @@ impl Constructor {
             }
         }.as_str()).unwrap();
 
-        Ok(vec![struct_block, impl_item])
+        let mut items = vec![struct_block, impl_item];
+        items.extend(self.to_syn_true_flag_accessors(&name));
+
+        Ok(items)
     }
 }
 
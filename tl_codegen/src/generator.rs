@@ -18,8 +18,11 @@ pub fn generate_code_for(input: &str) -> quote::Tokens {
 }
 
 pub fn generate_ast_for(input: &str) -> syn::Crate {
+    let items = parser::parse_string(input).unwrap();
+    // Collect names before filtering so that core constructors like `vector` are included
+    let constructor_name_fn = generate_constructor_name_fn(&items);
     let mut constructors = {
-        let mut items = parser::parse_string(input).unwrap();
+        let mut items = items;
         filter_items(&mut items);
         partition_by_delimiter_and_namespace(items)
     };
@@ -102,6 +105,7 @@ pub fn generate_ast_for(input: &str) -> syn::Crate {
         }
     }
 
+    let enum_variant_id_fn = generate_enum_variant_id_fn(&constructors);
     let ctors_typeck_info = constructors.ctors_typeck_info();
 
     let mut dynamic_ctors: Vec<(Vec<String>, u32, syn::Stmt)> = vec![];
@@ -127,6 +131,8 @@ pub fn generate_ast_for(input: &str) -> syn::Crate {
         }
     }.as_str()).unwrap();
     krate.items.push(register_ctors);
+    krate.items.push(constructor_name_fn);
+    krate.items.push(enum_variant_id_fn);
 
     let mut rpc_items = vec![];
     for (namespaces, substructs) in &constructors.functions {
@@ -147,6 +153,61 @@ pub fn generate_ast_for(input: &str) -> syn::Crate {
     krate
 }
 
+fn generate_constructor_name_fn(items: &[Item]) -> syn::Item {
+    let names: BTreeMap<u32, String> = items.iter()
+        .filter_map(|item| {
+            let c = match *item {
+                Item::Constructor(ref c) => c,
+                _ => return None,
+            };
+
+            match (c.tl_id, c.variant.names_vec()) {
+                (Some(tl_id), Some(names)) => Some((tl_id, names.join("."))),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let arms = names.into_iter().map(|(tl_id, name)| {
+        quote! { #tl_id => Some(#name), }
+    });
+
+    syn::parse_item(quote! {
+        /// Returns the TL name of a constructor with the provided id, if it is known
+        pub fn constructor_name(id: u32) -> Option<&'static str> {
+            match id {
+                #(#arms)*
+                _ => None,
+            }
+        }
+    }.as_str()).unwrap()
+}
+
+fn generate_enum_variant_id_fn(constructors: &AllConstructors) -> syn::Item {
+    let variant_ids: BTreeMap<u32, String> = constructors.types.values()
+        .flat_map(|constructor_map| constructor_map.values())
+        // Single-constructor types are generated as structs
+        .filter(|ctors| ctors.0.len() >= 2)
+        .flat_map(|ctors| ctors.0.iter())
+        .filter_map(|c| c.tl_id.map(|tl_id| (tl_id, c.variant_name().as_ref().to_owned())))
+        .collect();
+
+    let arms = variant_ids.into_iter().map(|(tl_id, variant_id)| {
+        quote! { #tl_id => Some(#variant_id), }
+    });
+
+    syn::parse_item(quote! {
+        /// Returns the enum variant name of a constructor with the provided id, if it is a
+        /// constructor of a generated enum
+        pub fn enum_variant_id(id: u32) -> Option<&'static str> {
+            match id {
+                #(#arms)*
+                _ => None,
+            }
+        }
+    }.as_str()).unwrap()
+}
+
 fn filter_items(items: &mut Vec<Item>) {
     items.retain(|item| {
         let c = match *item {
@@ -210,7 +271,12 @@ impl Constructors {
 
     fn to_syn_data_type_items<'a>(&self, ctors_typeck_info: &BTreeMap<&'a Constructor, TypeckKind>) -> error::Result<Vec<syn::Item>> {
         if self.0.len() == 1 {
-            return self.0[0].to_syn_single_type_struct(ctors_typeck_info).map(|s| vec![s]);
+            let ctor = &self.0[0];
+            let name = ctor.output.name().map(no_conflict_ident).unwrap(); // FIXME
+            let mut items = vec![ctor.to_syn_single_type_struct(ctors_typeck_info)?];
+            items.extend(ctor.to_syn_true_flag_accessors(&name));
+
+            return Ok(items);
         }
 
         assert!(self.0.len() >= 2); // FIXME: return errors instead of assert
@@ -223,6 +289,8 @@ impl Constructors {
             .collect::<error::Result<Vec<_>>>()?
             .into_iter()
             .filter_map(|maybe_struct| maybe_struct);
+        let accessors = self.0.iter()
+            .filter_map(|ctor| ctor.to_syn_true_flag_accessors(&ctor.variant_name()));
 
         let is_static_typeck_kind = self.0.iter()
             .map(|ctor| ctors_typeck_info[ctor])
@@ -247,6 +315,7 @@ impl Constructors {
             v.push(syn_enum);
             v.extend(methods);
             v.extend(structs);
+            v.extend(accessors);
 
             v
         };